@@ -0,0 +1,323 @@
+use crate::math::Vec3f;
+use crate::surface::Aabb;
+use crate::util::join;
+
+/// Nodes with this many items or fewer aren't split any further
+const MAX_LEAF_ITEMS: usize = 4;
+/// Only subtrees with at least this many items are built on a separate thread,
+/// smaller ones are quicker to build than to hand off
+const PARALLEL_BUILD_MIN_ITEMS: usize = 4096;
+/// Relative amount that each item's bounding box is grown by, so that rounding
+/// errors can't cause a ray to skip over an item which it really does hit
+const BOUNDS_PADDING: f32 = 1e-4;
+
+/// Bounding volume hierarchy: a tree of nested bounding boxes over a list of
+/// items (eg the objects in a scene), which lets a ray skip over every item in a
+/// box that it misses. Items are referred to by their index in the list, and the
+/// caller does the actual intersection tests, so the same tree works for any kind
+/// of item. Items without a bounding box are tested against every ray.
+pub struct Bvh {
+    root: Option<BvhNode>,
+    unbounded: Vec<usize>,
+    item_count: usize,
+}
+
+enum BvhNode {
+    Leaf {
+        bounds: Aabb,
+        items: Vec<usize>,
+    },
+    Split {
+        bounds: Aabb,
+        children: Box<[BvhNode; 2]>,
+    },
+}
+
+/// An item being sorted into the tree, as (index, bounds, center)
+type BuildItem = (usize, Aabb, Vec3f);
+
+impl Bvh {
+    /// Builds the tree over items with the given bounding boxes, splitting each
+    /// node at the median of its items' centers along its longest axis
+    pub fn build(item_bounds: &[Option<Aabb>]) -> Bvh {
+        let mut items = Vec::new();
+        let mut unbounded = Vec::new();
+
+        for (index, bounds) in item_bounds.iter().enumerate() {
+            match bounds {
+                Some(bounds) => {
+                    let size = bounds.max().sub(&bounds.min());
+                    let largest = size.x.abs().max(size.y.abs()).max(size.z.abs());
+                    let padded = bounds.padded(BOUNDS_PADDING * largest.max(1.0));
+                    items.push((index, padded, padded.center()));
+                }
+                None => unbounded.push(index),
+            }
+        }
+
+        Bvh {
+            root: if items.is_empty() {
+                None
+            } else {
+                Some(build_node(&mut items))
+            },
+            unbounded,
+            item_count: item_bounds.len(),
+        }
+    }
+
+    /// Number of items the tree was built over
+    pub fn len(&self) -> usize {
+        self.item_count
+    }
+
+    /// Finds the item with the nearest intersection along the ray closer than
+    /// "max_distance", given a function which intersects the ray with an item,
+    /// returning the item's index and the distance. On ties, the item with the
+    /// lowest index wins, just as if every item had been tested in order.
+    pub fn nearest(
+        &self,
+        ray_origin: &Vec3f,
+        ray_direction: &Vec3f,
+        max_distance: f32,
+        intersect: impl Fn(usize) -> Option<f32>,
+    ) -> Option<(usize, f32)> {
+        let mut nearest: Option<(usize, f32)> = None;
+        let test = |nearest: &mut Option<(usize, f32)>, index: usize| {
+            if let Some(dist) = intersect(index).filter(|&dist| dist < max_distance) {
+                let is_nearer = nearest.is_none_or(|(nearest_index, nearest_dist)| {
+                    dist.total_cmp(&nearest_dist)
+                        .then(index.cmp(&nearest_index))
+                        .is_lt()
+                });
+                if is_nearer {
+                    *nearest = Some((index, dist));
+                }
+            }
+        };
+
+        for &index in &self.unbounded {
+            test(&mut nearest, index);
+        }
+
+        let mut stack: Vec<&BvhNode> = self.root.iter().collect();
+        while let Some(node) = stack.pop() {
+            // Skip boxes which are missed entirely, are behind the ray, or start
+            // further away than the nearest hit so far (or the maximum distance)
+            let (t_near, t_far) = match node.bounds().ray_interval(ray_origin, ray_direction) {
+                Some(interval) => interval,
+                None => continue,
+            };
+            let search_distance = nearest.map_or(max_distance, |(_, dist)| dist);
+            if t_far < 0.0 || t_near > search_distance {
+                continue;
+            }
+
+            match node {
+                BvhNode::Leaf { items, .. } => {
+                    for &index in items {
+                        test(&mut nearest, index);
+                    }
+                }
+                BvhNode::Split { children, .. } => {
+                    // Visit the nearer child first (it's pushed last), since a hit
+                    // in it may allow the other to be skipped
+                    let entry = |child: &BvhNode| {
+                        child
+                            .bounds()
+                            .ray_interval(ray_origin, ray_direction)
+                            .map_or(f32::INFINITY, |(t_near, _)| t_near)
+                    };
+                    let [first, second] = &**children;
+                    if entry(first) <= entry(second) {
+                        stack.push(second);
+                        stack.push(first);
+                    } else {
+                        stack.push(first);
+                        stack.push(second);
+                    }
+                }
+            }
+        }
+
+        nearest
+    }
+
+    /// Returns the items whose bounding boxes contain the point, in no particular
+    /// order, plus all of the unbounded items
+    pub fn items_containing(&self, point: &Vec3f) -> Vec<usize> {
+        let mut found = self.unbounded.clone();
+
+        let mut stack: Vec<&BvhNode> = self.root.iter().collect();
+        while let Some(node) = stack.pop() {
+            if !node.bounds().contains(point) {
+                continue;
+            }
+            match node {
+                BvhNode::Leaf { items, .. } => found.extend(items),
+                BvhNode::Split { children, .. } => stack.extend(children.iter()),
+            }
+        }
+
+        found
+    }
+}
+
+impl BvhNode {
+    fn bounds(&self) -> &Aabb {
+        match self {
+            BvhNode::Leaf { bounds, .. } => bounds,
+            BvhNode::Split { bounds, .. } => bounds,
+        }
+    }
+}
+
+/// Builds a node over the (non-empty) items, reordering them as it goes
+fn build_node(items: &mut [BuildItem]) -> BvhNode {
+    let bounds = items[1..]
+        .iter()
+        .fold(items[0].1, |bounds, (_, item_bounds, _)| {
+            bounds.union(item_bounds)
+        });
+    let leaf = |items: &[BuildItem]| BvhNode::Leaf {
+        bounds,
+        items: items.iter().map(|(index, ..)| *index).collect(),
+    };
+
+    if items.len() <= MAX_LEAF_ITEMS {
+        return leaf(items);
+    }
+
+    // Split along the axis over which the centers are most spread out. If they
+    // are all in the same place, there's no sensible way to split them.
+    let centers: Vec<Vec3f> = items.iter().map(|(_, _, center)| *center).collect();
+    let center_bounds = Aabb::from_points(&centers);
+    let spread = center_bounds.max().sub(&center_bounds.min());
+    let axis: fn(&Vec3f) -> f32 = if spread.x >= spread.y && spread.x >= spread.z {
+        |v| v.x
+    } else if spread.y >= spread.z {
+        |v| v.y
+    } else {
+        |v| v.z
+    };
+    if axis(&spread) <= 0.0 {
+        return leaf(items);
+    }
+
+    let middle = items.len() / 2;
+    items.select_nth_unstable_by(middle, |(_, _, c1), (_, _, c2)| {
+        axis(c1).total_cmp(&axis(c2))
+    });
+    let (first, second) = items.split_at_mut(middle);
+
+    let children = if first.len() >= PARALLEL_BUILD_MIN_ITEMS {
+        join(|| build_node(first), || build_node(second))
+    } else {
+        (build_node(first), build_node(second))
+    };
+
+    BvhNode::Split {
+        bounds,
+        children: Box::new([children.0, children.1]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::surface::{Plane, Sphere, Surface};
+    use crate::util::rand_f32;
+
+    fn random_point(extent: f32) -> Vec3f {
+        Vec3f {
+            x: (rand_f32() - 0.5) * extent,
+            y: (rand_f32() - 0.5) * extent,
+            z: (rand_f32() - 0.5) * extent,
+        }
+    }
+
+    /// Random spheres, some of which are exact copies of earlier ones so that
+    /// rays hitting them tie, and two identical unbounded planes below them all
+    fn random_surfaces(spheres: usize) -> Vec<Box<dyn Surface>> {
+        let mut surfaces: Vec<Box<dyn Surface>> = Vec::new();
+        let mut placed = Vec::new();
+        for i in 0..spheres {
+            let (center, radius) = if i % 10 == 9 {
+                placed[i / 2]
+            } else {
+                (random_point(100.0), 0.2 + rand_f32() * 2.0)
+            };
+            placed.push((center, radius));
+            surfaces.push(Box::new(Sphere::new(&center, radius)));
+        }
+        for _ in 0..2 {
+            let floor = Plane::new(
+                &Vec3f {
+                    x: 0.0,
+                    y: 0.0,
+                    z: -60.0,
+                },
+                &Vec3f {
+                    x: 1.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                &Vec3f {
+                    x: 0.0,
+                    y: 1.0,
+                    z: 0.0,
+                },
+            );
+            surfaces.push(Box::new(floor));
+        }
+        surfaces
+    }
+
+    /// Checks that nearest() finds the same hits as testing every item in order
+    fn assert_nearest_matches_brute_force(surfaces: &[Box<dyn Surface>]) {
+        let bounds: Vec<_> = surfaces.iter().map(|s| s.bounding_box()).collect();
+        let bvh = Bvh::build(&bounds);
+        assert_eq!(bvh.len(), surfaces.len());
+
+        for i in 0..500 {
+            let origin = random_point(120.0);
+            // Aim half the rays at an item, so that plenty of them hit something
+            let target = bounds[i * 7 % bounds.len()];
+            let direction = match target {
+                Some(target) if i % 2 == 0 => target.center().sub(&origin).normalize(),
+                _ => random_point(1.0).normalize(),
+            };
+            let intersect =
+                |index: usize| surfaces[index].intersection_with_ray(&origin, &direction);
+
+            let mut expected: Option<(usize, f32)> = None;
+            for index in 0..surfaces.len() {
+                if let Some(dist) = intersect(index).filter(|&dist| dist < 80.0) {
+                    if expected.is_none_or(|(_, nearest)| dist < nearest) {
+                        expected = Some((index, dist));
+                    }
+                }
+            }
+
+            assert_eq!(
+                bvh.nearest(&origin, &direction, 80.0, intersect),
+                expected,
+                "ray from {:?} along {:?}",
+                origin,
+                direction
+            );
+        }
+    }
+
+    #[test]
+    fn nearest_matches_brute_force() {
+        assert_nearest_matches_brute_force(&random_surfaces(300));
+    }
+
+    #[test]
+    fn parallel_build_matches_brute_force() {
+        // Enough items for both halves of the root to be built in parallel
+        let surfaces = random_surfaces(3 * PARALLEL_BUILD_MIN_ITEMS);
+        assert_nearest_matches_brute_force(&surfaces);
+    }
+}
@@ -0,0 +1,73 @@
+use crate::math::Vec3f;
+
+/// How a ray's origin and direction change when moving one pixel across (dx) or
+/// down (dy) the image, which gives the size of the pixel's footprint wherever the
+/// ray lands. See "Tracing Ray Differentials" (Igehy, 1999).
+#[derive(Debug, Copy, Clone)]
+pub struct RayDifferential {
+    pub origin_dx: Vec3f,
+    pub origin_dy: Vec3f,
+    pub direction_dx: Vec3f,
+    pub direction_dy: Vec3f,
+}
+
+const ZERO_VECTOR: Vec3f = Vec3f {
+    x: 0.0,
+    y: 0.0,
+    z: 0.0,
+};
+
+impl RayDifferential {
+    /// Differential for a ray which doesn't come from a pixel, which gives a zero
+    /// sized footprint (ie, textures are point sampled)
+    pub const ZERO: RayDifferential = RayDifferential {
+        origin_dx: ZERO_VECTOR,
+        origin_dy: ZERO_VECTOR,
+        direction_dx: ZERO_VECTOR,
+        direction_dy: ZERO_VECTOR,
+    };
+
+    /// Moves the differential along the ray to where it hits a surface "dist" away,
+    /// so that the origin differentials describe how the hit position changes.
+    /// Neighboring rays are intersected with the plane tangent to the surface.
+    pub fn transfer(&self, ray_direction: &Vec3f, dist: f32, normal: &Vec3f) -> RayDifferential {
+        let cos_angle = ray_direction.dot(normal);
+
+        let transfer_one = |origin_d: &Vec3f, direction_d: &Vec3f| {
+            let moved = origin_d.add(&direction_d.scale(dist));
+            // Rays skimming the surface would give an infinite footprint, so just
+            // ignore the change in distance for those.
+            let dist_d = if cos_angle.abs() > 1e-6 {
+                -moved.dot(normal) / cos_angle
+            } else {
+                0.0
+            };
+            moved.add(&ray_direction.scale(dist_d))
+        };
+
+        RayDifferential {
+            origin_dx: transfer_one(&self.origin_dx, &self.direction_dx),
+            origin_dy: transfer_one(&self.origin_dy, &self.direction_dy),
+            ..*self
+        }
+    }
+
+    /// Returns the differential of a ray reflected about the normal, for a
+    /// differential which has been transferred to the surface. The curvature of the
+    /// surface is ignored, so this underestimates the spread of rays reflected off
+    /// curved mirrors.
+    pub fn reflect(&self, normal: &Vec3f) -> RayDifferential {
+        RayDifferential {
+            direction_dx: self.direction_dx.reflect(normal),
+            direction_dy: self.direction_dy.reflect(normal),
+            ..*self
+        }
+    }
+
+    /// Approximate width of the pixel's footprint at the ray origin, in world units
+    pub fn footprint(&self) -> f32 {
+        let length = |v: &Vec3f| v.dot(v).sqrt();
+
+        length(&self.origin_dx).max(length(&self.origin_dy))
+    }
+}
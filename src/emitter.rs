@@ -0,0 +1,122 @@
+use crate::scene::{Scene, MAX_DEPTH};
+use crate::texture::TexturePoint;
+use crate::util::rand_f32;
+
+/// Number of points sampled on each emissive object to estimate how bright its
+/// emission texture is on average
+const EMISSION_ESTIMATE_SAMPLES: usize = 16;
+
+/// Picks emissive objects at random in proportion to the total light they give
+/// off (ie, their area times their average emission), which is how lights should
+/// be chosen when estimating the direct light from many emissive objects.
+/// Objects which are infinite or give off no light are never picked.
+pub struct EmitterDistribution {
+    /// Index in the scene's objects of each emitter which can be picked
+    object_indices: Vec<usize>,
+    /// Running total of the emitters' power, divided by the total of all of them
+    cumulative: Vec<f32>,
+}
+
+impl EmitterDistribution {
+    pub fn build(scene: &Scene) -> EmitterDistribution {
+        let mut object_indices = Vec::new();
+        let mut powers = Vec::new();
+
+        for (index, vobj) in scene.objects.iter().enumerate() {
+            let (emission_texture, area) = match (&vobj.emission_texture, vobj.surface.area()) {
+                (Some(emission_texture), Some(area)) => (emission_texture, area),
+                _ => continue,
+            };
+
+            let total_luminance: f32 = (0..EMISSION_ESTIMATE_SAMPLES)
+                .filter_map(|_| vobj.surface.sample_point())
+                .map(|sample| {
+                    let surf_prop = vobj.surface.at_point(&sample.position);
+                    let point = TexturePoint {
+                        u: surf_prop.u,
+                        v: surf_prop.v,
+                        position: sample.position,
+                        footprint: 0.0,
+                    };
+                    emission_texture
+                        .color(scene, MAX_DEPTH, &point)
+                        .luminance()
+                        .max(0.0)
+                })
+                .sum();
+            let power = area * total_luminance / (EMISSION_ESTIMATE_SAMPLES as f32);
+
+            if power > 0.0 && power.is_finite() {
+                object_indices.push(index);
+                powers.push(power);
+            }
+        }
+
+        let total_power: f32 = powers.iter().sum();
+        let cumulative = powers
+            .iter()
+            .scan(0.0, |running_total, power| {
+                *running_total += power / total_power;
+                Some(*running_total)
+            })
+            .collect();
+
+        EmitterDistribution {
+            object_indices,
+            cumulative,
+        }
+    }
+
+    /// Picks an emitter at random, returning its index in the scene's objects and
+    /// the probability of having picked it, or None if there are no emitters
+    pub fn sample(&self) -> Option<(usize, f32)> {
+        // Check for emitters before drawing a random number, so that a scene without
+        // any uses the same random numbers (eg for lens samples) as if the emitters
+        // had never been looked for
+        let last = self.cumulative.len().checked_sub(1)?;
+        let r = rand_f32();
+        // Rounding can leave the last running total just short of 1.0, so clamp
+        let chosen = self
+            .cumulative
+            .partition_point(|&total| total <= r)
+            .min(last);
+
+        Some((self.object_indices[chosen], self.probability_of(chosen)))
+    }
+
+    fn probability_of(&self, chosen: usize) -> f32 {
+        let previous = if chosen > 0 {
+            self.cumulative[chosen - 1]
+        } else {
+            0.0
+        };
+        self.cumulative[chosen] - previous
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::rand_u32;
+
+    #[test]
+    fn no_emitters_use_no_random_numbers() {
+        let empty = EmitterDistribution {
+            object_indices: Vec::new(),
+            cumulative: Vec::new(),
+        };
+
+        // Each thread starts from the same random state
+        let unsampled = std::thread::spawn(rand_u32).join().unwrap();
+        let sampled = std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    assert_eq!(empty.sample(), None);
+                    rand_u32()
+                })
+                .join()
+                .unwrap()
+        });
+        assert_eq!(sampled, unsampled);
+    }
+}
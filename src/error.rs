@@ -0,0 +1,82 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Everything that can stop raymond from producing an image
+pub enum RaymondError {
+    /// The command line arguments couldn't be understood, or asked for something
+    /// this build can't do
+    Arguments(String),
+    /// Reading or writing a file (or socket) failed
+    Io {
+        /// What was being done, eg "write output to out.ppm", if known
+        context: Option<String>,
+        source: io::Error,
+    },
+    /// A scene file couldn't be read, or doesn't describe a scene
+    SceneFile { filename: String, message: String },
+    /// The scene can't be rendered as asked, eg because it failed validation or a
+    /// tag given on the command line doesn't match any objects
+    Scene(String),
+    /// The interactive preview window couldn't be shown
+    #[cfg_attr(not(feature = "preview"), allow(dead_code))]
+    Preview(String),
+}
+
+impl RaymondError {
+    /// An IO error, with a description of what was being done when it happened
+    pub fn io(source: io::Error, context: String) -> RaymondError {
+        RaymondError::Io {
+            context: Some(context),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for RaymondError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RaymondError::Arguments(msg) => {
+                write!(f, "Could not process command line arguments: {}", msg)
+            }
+            RaymondError::Io {
+                context: Some(context),
+                source,
+            } => write!(f, "Could not {}: {}", context, source),
+            RaymondError::Io {
+                context: None,
+                source,
+            } => write!(f, "{}", source),
+            RaymondError::SceneFile { filename, message } => {
+                write!(f, "Could not load scene {}: {}", filename, message)
+            }
+            RaymondError::Scene(msg) | RaymondError::Preview(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+// Returning an error from main() prints it with Debug, so this gives the same
+// readable message as Display rather than the structure of the enum
+impl fmt::Debug for RaymondError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl Error for RaymondError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RaymondError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RaymondError {
+    fn from(source: io::Error) -> RaymondError {
+        RaymondError::Io {
+            context: None,
+            source,
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use crate::math::Vec3f;
+use crate::surface::slab_interval;
+
+/// Homogeneous participating medium (ie, fog) filling an axis aligned box. Light
+/// passing through it is attenuated, and light from the scene's light sources is
+/// scattered into rays passing through it.
+#[derive(Debug, Copy, Clone)]
+pub struct FogVolume {
+    pub min: Vec3f,
+    pub max: Vec3f,
+    /// Fraction of light scattered per unit distance travelled through the fog
+    pub scattering: f32,
+    /// Henyey-Greenstein asymmetry parameter, in (-1, 1). Positive values scatter
+    /// light forwards, negative backwards, and 0.0 scatters evenly in all directions.
+    pub anisotropy: f32,
+    /// Number of points along each ray at which in-scattered light is sampled
+    pub samples: usize,
+}
+
+impl FogVolume {
+    /// Returns the range of distances along the ray, between the ray origin and
+    /// max_distance, which is inside the fog.
+    pub fn overlap(
+        &self,
+        ray_origin: &Vec3f,
+        ray_direction: &Vec3f,
+        max_distance: f32,
+    ) -> Option<(f32, f32)> {
+        let as_array = |v: &Vec3f| [v.x, v.y, v.z];
+
+        slab_interval(
+            &as_array(ray_origin),
+            &as_array(ray_direction),
+            &as_array(&self.min),
+            &as_array(&self.max),
+        )
+        .map(|(t_near, t_far)| (t_near.max(0.0), t_far.min(max_distance)))
+        .filter(|(t_near, t_far)| t_near < t_far)
+    }
+
+    /// Fraction of light which makes it through "distance" units of fog
+    pub fn transmittance(&self, distance: f32) -> f32 {
+        (-self.scattering * distance).exp()
+    }
+
+    /// Henyey-Greenstein phase function, giving the fraction of light scattered
+    /// per steradian between two directions with the given cosine between them.
+    pub fn phase(&self, cos_angle: f32) -> f32 {
+        let g = self.anisotropy;
+        let denom = 1.0 + g * g - 2.0 * g * cos_angle;
+
+        (1.0 - g * g) / (4.0 * std::f32::consts::PI * denom * denom.sqrt())
+    }
+}
@@ -1,39 +1,224 @@
+mod bvh;
+mod differential;
+mod emitter;
+mod error;
+mod fog;
+mod material;
 mod math;
+mod mesh;
+mod palette;
+mod photon;
+#[cfg(feature = "server")]
+mod png;
 mod ppm;
+#[cfg(feature = "preview")]
+mod preview;
 mod scene;
+mod scene_file;
+#[cfg(feature = "server")]
+mod server;
+mod sky;
+mod stats;
 mod surface;
 mod texture;
 mod util;
 
 use std::env;
-use std::process::ExitCode;
+use std::io;
+use std::io::IsTerminal;
 use std::time::Instant;
 
+use emitter::EmitterDistribution;
+use error::RaymondError;
+use material::Material;
 use math::*;
+use photon::CausticMap;
 use scene::*;
+use scene_file::SceneFile;
+use sky::*;
 use surface::*;
 use texture::*;
+use util::Array2D;
 
+#[derive(Clone)]
 struct CommandLineArguments {
     output_file: String,
     width: usize,
     height: usize,
-    oversampling_factor: usize,
+    oversampling_x: usize,
+    oversampling_y: usize,
+    aa_sigma: f32,
+    edge_samples: usize,
+    pixel_aspect_ratio: f32,
+    lens_shift: (f32, f32),
+    colorspace: ColorSpace,
+    camera_index: usize,
+    only_tags: Vec<String>,
+    hidden_tags: Vec<String>,
+    time: f32,
+    sky: bool,
+    photons: usize,
+    bit_depth: u32,
+    serve_port: Option<u16>,
+    palette_file: Option<String>,
+    preview_scale: Option<usize>,
+    noise_threshold: Option<f32>,
+    max_samples: usize,
+    /// Object properties to change, as (tag, property, value)
+    property_overrides: Vec<(String, String, f32)>,
+    depth_fade_bounces: i32,
+    coverage_file: Option<String>,
+    seed: u32,
+    /// Camera shown by the portal, if it differs from the one rendered from
+    portal_camera_index: Option<usize>,
+    /// Materials to give objects, as (tag, material name)
+    material_overrides: Vec<(String, String)>,
+    /// Shadow rays per light with soft shadows, in place of each light's own count
+    shadow_samples: Option<usize>,
+    /// Lens radius for depth of field, 0.0 for a pinhole camera
+    aperture: f32,
+    /// Number of sides and rotation in degrees of a polygonal aperture, or None
+    /// for a round one
+    aperture_blades: Option<(u32, f32)>,
+    /// Distance to the plane in focus, or None to focus on the middle of the image
+    focus_distance: Option<f32>,
+    /// Width of the view in world units for an orthographic projection, or None
+    /// for perspective
+    orthographic_width: Option<f32>,
+    /// JSON file describing the scene, in place of the built in one
+    scene_file: Option<String>,
+    /// JSON describing the scene, as sent to the server, in place of the scene file
+    /// or the built in scene
+    scene_json: Option<String>,
+    /// Write each row of the image out as soon as it is traced
+    stream: bool,
 }
 
+/// Size of the grid cells that caustics are collected in, in world units. Smaller
+/// cells give sharper caustics, but need more photons to avoid noise.
+const CAUSTIC_CELL_SIZE: f32 = 0.1;
+
+/// Most rays that a request to the server can ask for: pixels, times the most
+/// samples any pixel can take, times the shadow rays for each sample. This keeps
+/// one request from exhausting the server's memory or holding it up for long.
+#[cfg(feature = "server")]
+const MAX_SERVED_SAMPLES: usize = 1 << 26;
+
+/// Most photons per object that a request to the server can ask for
+#[cfg(feature = "server")]
+const MAX_SERVED_PHOTONS: usize = 1 << 20;
+
 type FlagNames = (&'static str, &'static str);
 impl CommandLineArguments {
     const FLAG_OUTPUT: FlagNames = ("-o", "--output");
     const FLAG_WIDTH: FlagNames = ("-w", "--width");
     const FLAG_HEIGHT: FlagNames = ("-h", "--height");
     const FLAG_SAMPLES: FlagNames = ("-s", "--samples");
+    const FLAG_AA_SIGMA: FlagNames = ("-a", "--aa-sigma");
+    const FLAG_EDGE_SAMPLES: FlagNames = ("-e", "--edge-samples");
+    const FLAG_PIXEL_ASPECT: FlagNames = ("-p", "--pixel-aspect");
+    const FLAG_LENS_SHIFT: FlagNames = ("-l", "--lens-shift");
+    const FLAG_COLORSPACE: FlagNames = ("-c", "--colorspace");
+    const FLAG_CAMERA: FlagNames = ("-v", "--camera");
+    const FLAG_ONLY: FlagNames = ("-k", "--only");
+    const FLAG_HIDE: FlagNames = ("-x", "--hide");
+    const FLAG_TIME: FlagNames = ("-t", "--time");
+    const FLAG_BACKGROUND: FlagNames = ("-b", "--background");
+    const FLAG_PHOTONS: FlagNames = ("-f", "--photons");
+    const FLAG_BIT_DEPTH: FlagNames = ("-d", "--bit-depth");
+    const FLAG_SERVE: FlagNames = ("-S", "--serve");
+    const FLAG_PALETTE: FlagNames = ("-m", "--palette");
+    const FLAG_PREVIEW: FlagNames = ("-P", "--preview");
+    const FLAG_ADAPTIVE: FlagNames = ("-A", "--adaptive");
+    const FLAG_MAX_SAMPLES: FlagNames = ("-M", "--max-samples");
+    const FLAG_SET: FlagNames = ("-D", "--set");
+    const FLAG_DEPTH_FADE: FlagNames = ("-r", "--depth-fade");
+    const FLAG_COVERAGE: FlagNames = ("-C", "--coverage");
+    const FLAG_SEED: FlagNames = ("-z", "--seed");
+    const FLAG_PORTAL_CAMERA: FlagNames = ("-V", "--portal-camera");
+    const FLAG_LINEAR: FlagNames = ("-L", "--linear");
+    const FLAG_MATERIAL: FlagNames = ("-T", "--material");
+    const FLAG_SHADOW_SAMPLES: FlagNames = ("-H", "--shadow-samples");
+    const FLAG_APERTURE: FlagNames = ("-R", "--aperture");
+    const FLAG_APERTURE_BLADES: FlagNames = ("-B", "--aperture-blades");
+    const FLAG_FOCUS_DISTANCE: FlagNames = ("-F", "--focus-distance");
+    const FLAG_ORTHOGRAPHIC: FlagNames = ("-O", "--orthographic");
+    const FLAG_SCENE: FlagNames = ("-i", "--scene");
+    const FLAG_STREAM: FlagNames = ("-W", "--stream");
+
+    /// Flags which requests to the server may give, as query parameters named
+    /// after the long flag. Those which read or write files, or which would stop
+    /// the server rendering, are left out.
+    #[cfg(feature = "server")]
+    const SERVED_FLAGS: [FlagNames; 28] = [
+        Self::FLAG_WIDTH,
+        Self::FLAG_HEIGHT,
+        Self::FLAG_SAMPLES,
+        Self::FLAG_AA_SIGMA,
+        Self::FLAG_EDGE_SAMPLES,
+        Self::FLAG_PIXEL_ASPECT,
+        Self::FLAG_LENS_SHIFT,
+        Self::FLAG_COLORSPACE,
+        Self::FLAG_CAMERA,
+        Self::FLAG_ONLY,
+        Self::FLAG_HIDE,
+        Self::FLAG_TIME,
+        Self::FLAG_BACKGROUND,
+        Self::FLAG_PHOTONS,
+        Self::FLAG_BIT_DEPTH,
+        Self::FLAG_ADAPTIVE,
+        Self::FLAG_MAX_SAMPLES,
+        Self::FLAG_SET,
+        Self::FLAG_DEPTH_FADE,
+        Self::FLAG_SEED,
+        Self::FLAG_PORTAL_CAMERA,
+        Self::FLAG_LINEAR,
+        Self::FLAG_MATERIAL,
+        Self::FLAG_SHADOW_SAMPLES,
+        Self::FLAG_APERTURE,
+        Self::FLAG_APERTURE_BLADES,
+        Self::FLAG_FOCUS_DISTANCE,
+        Self::FLAG_ORTHOGRAPHIC,
+    ];
 
     fn default() -> CommandLineArguments {
         CommandLineArguments {
             output_file: String::from("raymond_out.ppm"),
             width: 1024,
             height: 768,
-            oversampling_factor: 2,
+            oversampling_x: 2,
+            oversampling_y: 2,
+            aa_sigma: 0.4,
+            edge_samples: 0,
+            pixel_aspect_ratio: 1.0,
+            lens_shift: (0.0, 0.0),
+            colorspace: ColorSpace::Srgb,
+            camera_index: 0,
+            only_tags: Vec::new(),
+            hidden_tags: Vec::new(),
+            time: 0.0,
+            sky: false,
+            photons: 0,
+            bit_depth: 8,
+            serve_port: None,
+            palette_file: None,
+            preview_scale: None,
+            noise_threshold: None,
+            max_samples: 64,
+            property_overrides: Vec::new(),
+            depth_fade_bounces: 0,
+            coverage_file: None,
+            seed: 0,
+            portal_camera_index: None,
+            material_overrides: Vec::new(),
+            shadow_samples: None,
+            aperture: 0.0,
+            aperture_blades: None,
+            focus_distance: None,
+            orthographic_width: None,
+            scene_file: None,
+            scene_json: None,
+            stream: false,
         }
     }
 
@@ -50,21 +235,222 @@ impl CommandLineArguments {
         );
         flag_usage(Self::FLAG_WIDTH, "Width of output image (in pixels)");
         flag_usage(Self::FLAG_HEIGHT, "Height of output image (in pixels)");
-        flag_usage(Self::FLAG_SAMPLES, "Oversampling factor (ie, antialiasing)");
+        flag_usage(
+            Self::FLAG_SAMPLES,
+            "Oversampling factor (ie, antialiasing), N or per-axis NxM",
+        );
+        flag_usage(
+            Self::FLAG_AA_SIGMA,
+            "Antialiasing filter width in pixels (smaller is sharper)",
+        );
+        flag_usage(
+            Self::FLAG_EDGE_SAMPLES,
+            "Antialias only object edges, with NxN samples, N at most 16 (overrides -s)",
+        );
+        flag_usage(
+            Self::FLAG_PIXEL_ASPECT,
+            "Pixel aspect ratio (width / height of a single pixel)",
+        );
+        flag_usage(
+            Self::FLAG_LENS_SHIFT,
+            "Shift the view as X,Y (in half image widths) without turning",
+        );
+        flag_usage(
+            Self::FLAG_COLORSPACE,
+            "Output color space: srgb (default), p3, rec709 or linear",
+        );
+        flag_usage(Self::FLAG_CAMERA, "Index of the camera to render from");
+        flag_usage(
+            Self::FLAG_ONLY,
+            "Only render objects with this tag (may be repeated)",
+        );
+        flag_usage(
+            Self::FLAG_HIDE,
+            "Don't render objects with this tag (may be repeated)",
+        );
+        flag_usage(
+            Self::FLAG_TIME,
+            "Time to render animated textures at (in seconds)",
+        );
+        flag_usage(
+            Self::FLAG_BACKGROUND,
+            "Background: solid (default) or sky (gradient with a sun)",
+        );
+        flag_usage(
+            Self::FLAG_PHOTONS,
+            "Photons per light per object for caustics (0 disables)",
+        );
+        flag_usage(
+            Self::FLAG_BIT_DEPTH,
+            "Bits per channel in the output file: 8 (default) or 16",
+        );
+        flag_usage(
+            Self::FLAG_SERVE,
+            "Serve renders over HTTP on this port (needs the server feature)",
+        );
+        flag_usage(
+            Self::FLAG_PALETTE,
+            "Mandelbrot colors from a GIMP palette (.gpl) or CSV file",
+        );
+        flag_usage(
+            Self::FLAG_PREVIEW,
+            "Show an interactive preview at 1/N resolution (needs the preview feature)",
+        );
+        flag_usage(
+            Self::FLAG_ADAPTIVE,
+            "Sample each pixel until its noise is below this (overrides -s and -e)",
+        );
+        flag_usage(
+            Self::FLAG_MAX_SAMPLES,
+            "Maximum samples per pixel with adaptive sampling (default 64)",
+        );
+        flag_usage(
+            Self::FLAG_SET,
+            "Change a property of tagged objects, eg sphere.reflectivity=0.5",
+        );
+        flag_usage(
+            Self::FLAG_DEPTH_FADE,
+            "Fade the last N bounces of deep reflections to black (0 disables)",
+        );
+        flag_usage(
+            Self::FLAG_COVERAGE,
+            "Also write an alpha mask of where objects are, in PGM format",
+        );
+        flag_usage(
+            Self::FLAG_SEED,
+            "Seed for adaptive sample positions (vary per animation frame)",
+        );
+        flag_usage(
+            Self::FLAG_PORTAL_CAMERA,
+            "Index of the camera the portal shows (default: same as -v)",
+        );
+        flag_usage(
+            Self::FLAG_LINEAR,
+            "Write linear values without gamma correction (takes no value)",
+        );
+        flag_usage(
+            Self::FLAG_MATERIAL,
+            "Give tagged objects a preset material, eg sphere=glass",
+        );
+        flag_usage(
+            Self::FLAG_SHADOW_SAMPLES,
+            "Shadow rays per soft-shadowed light (fewer is faster but noisier)",
+        );
+        flag_usage(
+            Self::FLAG_APERTURE,
+            "Lens radius for depth of field, blurs what is out of focus (default 0)",
+        );
+        flag_usage(
+            Self::FLAG_APERTURE_BLADES,
+            "Make the lens a polygon with N sides, eg 6 or 6,15 to rotate it 15 degrees",
+        );
+        flag_usage(
+            Self::FLAG_FOCUS_DISTANCE,
+            "Distance to the plane in focus (default: what's in the middle of the image)",
+        );
+        flag_usage(
+            Self::FLAG_ORTHOGRAPHIC,
+            "Use a parallel projection, with a view this many units wide",
+        );
+        flag_usage(
+            Self::FLAG_SCENE,
+            "Load the scene from a JSON file, eg scenes/default.json",
+        );
+        flag_usage(
+            Self::FLAG_STREAM,
+            "Write rows as they are traced, using less memory (takes no value)",
+        );
     }
 
-    fn from_args() -> Result<CommandLineArguments, String> {
+    fn from_args() -> Result<CommandLineArguments, RaymondError> {
+        let raw_args: Vec<String> = env::args().skip(1).collect(); // skip program name
+
+        Self::default()
+            .with_flags(raw_args)
+            .map_err(RaymondError::Arguments)
+    }
+
+    /// Applies the query parameters of a request to the server on top of these
+    /// arguments. Only the SERVED_FLAGS can be given, and the render must not take
+    /// more than MAX_SERVED_SAMPLES samples or MAX_SERVED_PHOTONS photons.
+    #[cfg(feature = "server")]
+    fn with_query_params(
+        self,
+        params: Vec<(String, String)>,
+    ) -> Result<CommandLineArguments, String> {
+        let mut raw_args = Vec::new();
+        for (name, value) in params {
+            let flag = Self::SERVED_FLAGS
+                .iter()
+                .find(|flag| flag.1.strip_prefix("--") == Some(name.as_str()))
+                .ok_or_else(|| format!("Unknown parameter: {}", name))?;
+            // Insist on values for exactly the flags which take them, so that a
+            // value can't be mistaken for another flag
+            let is_switch = *flag == Self::FLAG_LINEAR;
+            if is_switch && !value.is_empty() {
+                return Err(format!("Parameter {} does not take a value", name));
+            }
+            if !is_switch && value.is_empty() {
+                return Err(format!("Parameter {} needs a value", name));
+            }
+
+            raw_args.push(String::from(flag.1));
+            if !is_switch {
+                raw_args.push(value);
+            }
+        }
+
+        let args = self.with_flags(raw_args)?;
+        let samples_per_pixel = if args.noise_threshold.is_some() {
+            Some(args.max_samples)
+        } else if args.edge_samples > 0 {
+            args.edge_samples.checked_mul(args.edge_samples)
+        } else {
+            args.oversampling_x.checked_mul(args.oversampling_y)
+        };
+        let samples = [
+            samples_per_pixel,
+            Some(args.height),
+            Some(args.shadow_samples.unwrap_or(1)),
+        ]
+        .iter()
+        .try_fold(args.width, |total, factor| total.checked_mul((*factor)?));
+        if samples.is_none_or(|samples| samples > MAX_SERVED_SAMPLES) {
+            return Err(format!(
+                "Render is too large, at most {} samples can be traced per request",
+                MAX_SERVED_SAMPLES
+            ));
+        }
+        if args.photons > MAX_SERVED_PHOTONS {
+            return Err(format!(
+                "At most {} photons can be traced per request",
+                MAX_SERVED_PHOTONS
+            ));
+        }
+
+        Ok(args)
+    }
+
+    /// Applies a list of flags and their values on top of these arguments
+    fn with_flags(self, mut raw_args: Vec<String>) -> Result<CommandLineArguments, String> {
         fn is_flag(s: &str, flag: FlagNames) -> bool {
             s == flag.0 || s == flag.1
         }
 
-        let mut raw_args: Vec<String> = env::args().collect();
-        let mut args = Self::default();
-
+        let mut args = self;
         raw_args.reverse();
-        raw_args.pop(); // skip program name
 
         while let Some(flag) = raw_args.pop() {
+            // Switches which don't take a value
+            if is_flag(&flag, Self::FLAG_LINEAR) {
+                args.colorspace = ColorSpace::Linear;
+                continue;
+            }
+            if is_flag(&flag, Self::FLAG_STREAM) {
+                args.stream = true;
+                continue;
+            }
+
             let arg = match raw_args.pop() {
                 Some(arg) => arg,
                 None => return Err(String::from("Value expected after command line argument")),
@@ -74,17 +460,189 @@ impl CommandLineArguments {
                 args.output_file = arg;
             } else if is_flag(&flag, Self::FLAG_WIDTH) {
                 args.width = arg.parse().map_err(|_| "Could not parse width")?;
+                if args.width == 0 {
+                    return Err(String::from("Width must be at least 1"));
+                }
             } else if is_flag(&flag, Self::FLAG_HEIGHT) {
                 args.height = arg.parse().map_err(|_| "Could not parse height")?;
+                if args.height == 0 {
+                    return Err(String::from("Height must be at least 1"));
+                }
             } else if is_flag(&flag, Self::FLAG_SAMPLES) {
-                args.oversampling_factor = arg
+                // Either a single factor for both axes, or NxM
+                let (x, y) = arg.split_once('x').unwrap_or((&arg, &arg));
+                args.oversampling_x = x
                     .parse()
                     .map_err(|_| "Could not parse oversampling factor")?;
+                args.oversampling_y = y
+                    .parse()
+                    .map_err(|_| "Could not parse oversampling factor")?;
+                if args.oversampling_x == 0 || args.oversampling_y == 0 {
+                    return Err(String::from("Oversampling factor must be at least 1"));
+                }
+            } else if is_flag(&flag, Self::FLAG_AA_SIGMA) {
+                args.aa_sigma = arg
+                    .parse()
+                    .map_err(|_| "Could not parse antialiasing sigma")?;
+                // Beyond a few pixels, the filter only blurs and its kernel gets huge
+                if !(args.aa_sigma > 0.0 && args.aa_sigma <= 10.0) {
+                    return Err(String::from(
+                        "Antialiasing sigma must be more than 0 and at most 10",
+                    ));
+                }
+            } else if is_flag(&flag, Self::FLAG_EDGE_SAMPLES) {
+                args.edge_samples = arg.parse().map_err(|_| "Could not parse edge samples")?;
+                // Each edge pixel takes the square of this many rays
+                if args.edge_samples > 16 {
+                    return Err(String::from("Edge samples must be at most 16"));
+                }
+            } else if is_flag(&flag, Self::FLAG_PIXEL_ASPECT) {
+                let ratio: f32 = arg
+                    .parse()
+                    .map_err(|_| "Could not parse pixel aspect ratio")?;
+                if !(ratio > 0.0 && ratio.is_finite()) {
+                    return Err(String::from("Pixel aspect ratio must be positive"));
+                }
+                args.pixel_aspect_ratio = ratio;
+            } else if is_flag(&flag, Self::FLAG_LENS_SHIFT) {
+                args.lens_shift = arg
+                    .split_once(',')
+                    .and_then(|(x, y)| Some((x.parse().ok()?, y.parse().ok()?)))
+                    .ok_or("Could not parse lens shift")?;
+            } else if is_flag(&flag, Self::FLAG_CAMERA) {
+                args.camera_index = arg.parse().map_err(|_| "Could not parse camera index")?;
+            } else if is_flag(&flag, Self::FLAG_ONLY) {
+                args.only_tags.push(arg);
+            } else if is_flag(&flag, Self::FLAG_HIDE) {
+                args.hidden_tags.push(arg);
+            } else if is_flag(&flag, Self::FLAG_TIME) {
+                args.time = arg.parse().map_err(|_| "Could not parse time")?;
+            } else if is_flag(&flag, Self::FLAG_BACKGROUND) {
+                args.sky = match arg.as_str() {
+                    "solid" => false,
+                    "sky" => true,
+                    _ => return Err(String::from("Unknown background")),
+                };
+            } else if is_flag(&flag, Self::FLAG_PHOTONS) {
+                args.photons = arg.parse().map_err(|_| "Could not parse photon count")?;
+            } else if is_flag(&flag, Self::FLAG_BIT_DEPTH) {
+                args.bit_depth = match arg.as_str() {
+                    "8" => 8,
+                    "16" => 16,
+                    _ => return Err(String::from("Bit depth must be 8 or 16")),
+                };
+            } else if is_flag(&flag, Self::FLAG_SERVE) {
+                args.serve_port = Some(arg.parse().map_err(|_| "Could not parse port")?);
+            } else if is_flag(&flag, Self::FLAG_PALETTE) {
+                args.palette_file = Some(arg);
+            } else if is_flag(&flag, Self::FLAG_PREVIEW) {
+                let scale: usize = arg.parse().map_err(|_| "Could not parse preview scale")?;
+                if scale == 0 {
+                    return Err(String::from("Preview scale must be at least 1"));
+                }
+                args.preview_scale = Some(scale);
+            } else if is_flag(&flag, Self::FLAG_ADAPTIVE) {
+                let threshold: f32 = arg.parse().map_err(|_| "Could not parse noise threshold")?;
+                if !(threshold > 0.0 && threshold.is_finite()) {
+                    return Err(String::from("Noise threshold must be positive"));
+                }
+                args.noise_threshold = Some(threshold);
+            } else if is_flag(&flag, Self::FLAG_MAX_SAMPLES) {
+                args.max_samples = arg.parse().map_err(|_| "Could not parse max samples")?;
+                if args.max_samples == 0 {
+                    return Err(String::from("Max samples must be at least 1"));
+                }
+            } else if is_flag(&flag, Self::FLAG_SET) {
+                // eg "sphere.reflectivity=0.5"
+                let (tag, property, value) = arg
+                    .split_once('=')
+                    .and_then(|(name, value)| {
+                        let (tag, property) = name.rsplit_once('.')?;
+                        Some((tag, property, value.parse().ok()?))
+                    })
+                    .ok_or("Could not parse property override, expected tag.property=value")?;
+                args.property_overrides
+                    .push((String::from(tag), String::from(property), value));
+            } else if is_flag(&flag, Self::FLAG_DEPTH_FADE) {
+                args.depth_fade_bounces = arg
+                    .parse()
+                    .map_err(|_| "Could not parse depth fade bounces")?;
+            } else if is_flag(&flag, Self::FLAG_COVERAGE) {
+                args.coverage_file = Some(arg);
+            } else if is_flag(&flag, Self::FLAG_SEED) {
+                args.seed = arg.parse().map_err(|_| "Could not parse seed")?;
+            } else if is_flag(&flag, Self::FLAG_PORTAL_CAMERA) {
+                args.portal_camera_index = Some(
+                    arg.parse()
+                        .map_err(|_| "Could not parse portal camera index")?,
+                );
+            } else if is_flag(&flag, Self::FLAG_MATERIAL) {
+                // eg "sphere=glass"
+                let (tag, material) = arg
+                    .split_once('=')
+                    .ok_or("Could not parse material, expected tag=material")?;
+                args.material_overrides
+                    .push((String::from(tag), String::from(material)));
+            } else if is_flag(&flag, Self::FLAG_SHADOW_SAMPLES) {
+                let samples: usize = arg.parse().map_err(|_| "Could not parse shadow samples")?;
+                if samples == 0 {
+                    return Err(String::from("Shadow samples must be at least 1"));
+                }
+                args.shadow_samples = Some(samples);
+            } else if is_flag(&flag, Self::FLAG_APERTURE) {
+                args.aperture = arg.parse().map_err(|_| "Could not parse aperture")?;
+                if !(args.aperture >= 0.0 && args.aperture.is_finite()) {
+                    return Err(String::from("Aperture must not be negative"));
+                }
+            } else if is_flag(&flag, Self::FLAG_APERTURE_BLADES) {
+                let (sides, degrees) = match arg.split_once(',') {
+                    Some((sides, degrees)) => (sides, degrees),
+                    None => (arg.as_str(), "0"),
+                };
+                let sides: u32 = sides
+                    .parse()
+                    .map_err(|_| "Could not parse aperture blades")?;
+                let degrees: f32 = degrees
+                    .parse()
+                    .ok()
+                    .filter(|degrees: &f32| degrees.is_finite())
+                    .ok_or("Could not parse aperture rotation")?;
+                if sides < 3 {
+                    return Err(String::from("Aperture must have at least 3 blades"));
+                }
+                args.aperture_blades = Some((sides, degrees));
+            } else if is_flag(&flag, Self::FLAG_FOCUS_DISTANCE) {
+                let distance: f32 = arg.parse().map_err(|_| "Could not parse focus distance")?;
+                if !(distance > 0.0 && distance.is_finite()) {
+                    return Err(String::from("Focus distance must be positive"));
+                }
+                args.focus_distance = Some(distance);
+            } else if is_flag(&flag, Self::FLAG_ORTHOGRAPHIC) {
+                let width: f32 = arg.parse().map_err(|_| "Could not parse view width")?;
+                if !(width > 0.0 && width.is_finite()) {
+                    return Err(String::from("View width must be positive"));
+                }
+                args.orthographic_width = Some(width);
+            } else if is_flag(&flag, Self::FLAG_SCENE) {
+                args.scene_file = Some(arg);
+            } else if is_flag(&flag, Self::FLAG_COLORSPACE) {
+                args.colorspace = ColorSpace::from_name(&arg).ok_or("Unknown color space")?;
             } else {
                 return Err(String::from("Unexpected command line argument"));
             }
         }
 
+        // Streaming only works for filters which need a few rows at a time
+        if args.stream
+            && (args.noise_threshold.is_some()
+                || args.edge_samples > 0
+                || args.coverage_file.is_some())
+        {
+            return Err(String::from(
+                "Streaming can't be combined with adaptive or edge sampling, or coverage",
+            ));
+        }
+
         Ok(args)
     }
 }
@@ -106,29 +664,144 @@ fn random_sphere() -> VisObj {
             blue: util::rand_f32(),
         }),
         reflectivity: 0.9,
+        roughness: 0.0,
+        specular_intensity: 0.0,
+        shininess: 32.0,
+        transparency: 0.0,
+        index_of_refraction: 1.0,
+        ambient_multiplier: 1.0,
+        emission_texture: None,
+        fade: None,
+        tag: None,
+    }
+}
+
+fn build_cameras() -> Vec<Camera> {
+    vec![
+        // Looking across the checkerboard at the sphere
+        Camera::new(
+            Vec3f {
+                x: -11.0,
+                y: 0.0,
+                z: 2.0,
+            },
+            Vec3f {
+                x: 10.0,
+                y: 0.0,
+                z: -1.0,
+            },
+            45.0,
+        ),
+        // Looking down on the scene from a high corner
+        Camera::new(
+            Vec3f {
+                x: 9.0,
+                y: 9.0,
+                z: 7.0,
+            },
+            Vec3f {
+                x: -9.0,
+                y: -9.0,
+                z: -6.0,
+            },
+            45.0,
+        ),
+    ]
+}
+
+/// Sky with the sun placed at the given light, so it shows up in reflections
+fn build_sky(sun: &LightSource) -> Sky {
+    Sky {
+        horizon_color: Rgb {
+            red: 0.7,
+            green: 0.8,
+            blue: 0.95,
+        },
+        zenith_color: Rgb {
+            red: 0.15,
+            green: 0.3,
+            blue: 0.8,
+        },
+        sun_direction: Vec3f::UP,
+        sun_angular_radius: 0.05,
+        sun_color: Rgb {
+            red: 8.0,
+            green: 7.5,
+            blue: 6.0,
+        },
     }
+    .with_sun_at_light(sun)
+}
+
+/// Colors used for the Mandelbrot set unless a palette file is given (sRGB encoded)
+fn default_colormap() -> Vec<Rgb> {
+    vec![
+        Rgb {
+            red: 0.0,
+            green: 0.0,
+            blue: 0.5,
+        },
+        Rgb {
+            red: 0.0,
+            green: 0.0,
+            blue: 1.0,
+        },
+        Rgb {
+            red: 0.0,
+            green: 1.0,
+            blue: 1.0,
+        },
+        Rgb {
+            red: 1.0,
+            green: 1.0,
+            blue: 0.0,
+        },
+        Rgb {
+            red: 1.0,
+            green: 0.0,
+            blue: 0.0,
+        },
+        Rgb {
+            red: 0.5,
+            green: 0.0,
+            blue: 0.0,
+        },
+    ]
 }
 
-fn build_scene(camera: &Camera) -> Scene {
+/// Builds the demo scene. The portal shows the view from "portal_camera", which
+/// gives a hall of mirrors effect when it is the camera being rendered from.
+fn build_scene(portal_camera: &Camera, colormap: Vec<Rgb>) -> Scene {
     let mut scene = Scene {
         background: Rgb {
             red: 0.3,
             green: 0.5,
             blue: 0.9,
         },
-        ambient_light_intensity: 0.25,
+        ambient_light: Rgb::gray(0.25),
+        terminator_softening: 0.0,
+        depth_fade_bounces: 0,
         light_sources: Vec::new(),
         objects: Vec::new(),
+        bvh: None,
+        environment: None,
+        fog_volumes: Vec::new(),
+        caustics: None,
+        emitters: None,
+        time: 0.0,
+        progress: None,
     };
 
-    scene.light_sources.push(LightSource {
-        dir_to_light: Vec3f {
-            x: 0.0,
-            y: -10.0,
-            z: 10.0,
+    scene.light_sources.push(LightSource::new(
+        LightKind::Directional {
+            dir_to_light: Vec3f {
+                x: 0.0,
+                y: -10.0,
+                z: 10.0,
+            },
         },
-        intensity: 0.75,
-    });
+        0.75,
+    ));
 
     // Classic red and white infinite checkerboard
     scene.objects.push(VisObj {
@@ -162,41 +835,20 @@ fn build_scene(camera: &Camera) -> Scene {
             }),
         )),
         reflectivity: 0.0,
+        roughness: 0.0,
+        specular_intensity: 0.0,
+        shininess: 32.0,
+        transparency: 0.0,
+        index_of_refraction: 1.0,
+        ambient_multiplier: 1.0,
+        emission_texture: None,
+        fade: Some(DistanceFade {
+            start: 40.0,
+            end: 100.0,
+        }),
+        tag: Some(String::from("floor")),
     });
 
-    let colormap = vec![
-        Rgb {
-            red: 0.0,
-            green: 0.0,
-            blue: 0.5,
-        },
-        Rgb {
-            red: 0.0,
-            green: 0.0,
-            blue: 1.0,
-        },
-        Rgb {
-            red: 0.0,
-            green: 1.0,
-            blue: 1.0,
-        },
-        Rgb {
-            red: 1.0,
-            green: 1.0,
-            blue: 0.0,
-        },
-        Rgb {
-            red: 1.0,
-            green: 0.0,
-            blue: 0.0,
-        },
-        Rgb {
-            red: 0.5,
-            green: 0.0,
-            blue: 0.0,
-        },
-    ];
-
     // Rectangle showing the Mandelbrot set
     scene.objects.push(VisObj {
         surface: Box::new(Quad::new(
@@ -222,13 +874,28 @@ fn build_scene(camera: &Camera) -> Scene {
             2.5,
         )),
         texture: Box::new(CoordinateTransform {
-            texture: Box::new(MandelbrotSet { colormap }),
+            texture: Box::new(MandelbrotSet {
+                colormap,
+                subsamples: 1,
+                subsample_spacing: 0.0,
+                contour_lines: false,
+                contour_thickness: 0.0,
+            }),
             u_offset: -2.0,
             v_offset: -1.25,
             u_scale: 1.0,
             v_scale: 1.0,
         }),
         reflectivity: 0.0,
+        roughness: 0.0,
+        specular_intensity: 0.0,
+        shininess: 32.0,
+        transparency: 0.0,
+        index_of_refraction: 1.0,
+        ambient_multiplier: 1.0,
+        emission_texture: None,
+        fade: None,
+        tag: Some(String::from("mandelbrot")),
     });
 
     // Rectangle recursively showing the same scene
@@ -257,7 +924,9 @@ fn build_scene(camera: &Camera) -> Scene {
         )),
         texture: Box::new(CoordinateTransform {
             texture: Box::new(Portal {
-                camera: camera.clone(),
+                camera: portal_camera.clone(),
+                subsamples: 1,
+                subsample_spacing: 0.0,
             }),
             u_offset: -1.5,
             v_offset: -1.25,
@@ -265,6 +934,15 @@ fn build_scene(camera: &Camera) -> Scene {
             v_scale: -1.0,
         }),
         reflectivity: 0.0,
+        roughness: 0.0,
+        specular_intensity: 0.0,
+        shininess: 32.0,
+        transparency: 0.0,
+        index_of_refraction: 1.0,
+        ambient_multiplier: 1.0,
+        emission_texture: None,
+        fade: None,
+        tag: Some(String::from("portal")),
     });
 
     // Nice reflective sphere
@@ -279,53 +957,671 @@ fn build_scene(camera: &Camera) -> Scene {
         )),
         texture: Box::new(Rgb::BLACK),
         reflectivity: 0.9,
+        roughness: 0.0,
+        specular_intensity: 0.0,
+        shininess: 32.0,
+        transparency: 0.0,
+        index_of_refraction: 1.0,
+        ambient_multiplier: 1.0,
+        emission_texture: None,
+        fade: None,
+        tag: Some(String::from("sphere")),
     });
 
     scene
 }
 
-fn main() -> ExitCode {
-    let args = match CommandLineArguments::from_args() {
-        Ok(args) => args,
-        Err(msg) => {
-            eprintln!("Error processing command line arguments: {}", msg);
-            eprintln!();
-            CommandLineArguments::show_usage();
-            return ExitCode::FAILURE;
+/// Converts the image to pixels in the given color space, in parallel. Each
+/// sample takes one byte if "bit_depth" is 8, or two bytes, most significant
+/// first, if it is 16, as a PPM file stores them.
+fn encode_image(image: &Array2D<Rgb>, colorspace: ColorSpace, bit_depth: u32) -> Vec<u8> {
+    let bytes_per_pixel = if bit_depth == 16 { 6 } else { 3 };
+    let mut pixel_data = vec![0u8; image.rows * image.columns * bytes_per_pixel];
+
+    let jobs: Vec<_> = pixel_data
+        .chunks_exact_mut(image.columns * bytes_per_pixel)
+        .zip(image.iter_rows())
+        .map(|(out_row, in_row)| {
+            move || {
+                for (out_pixel, in_pixel) in out_row.chunks_exact_mut(bytes_per_pixel).zip(in_row) {
+                    if bit_depth == 16 {
+                        let (red, green, blue) = colorspace.encode(in_pixel).rgb48();
+                        out_pixel[0..2].copy_from_slice(&red.to_be_bytes());
+                        out_pixel[2..4].copy_from_slice(&green.to_be_bytes());
+                        out_pixel[4..6].copy_from_slice(&blue.to_be_bytes());
+                    } else {
+                        let (red, green, blue) = colorspace.encode_rgb24(in_pixel);
+                        out_pixel.copy_from_slice(&[red, green, blue]);
+                    }
+                }
+            }
+        })
+        .collect();
+
+    util::run_parallel_jobs(jobs);
+
+    pixel_data
+}
+
+fn write_image(
+    output_file: &str,
+    image: &Array2D<Rgb>,
+    colorspace: ColorSpace,
+    bit_depth: u32,
+) -> io::Result<()> {
+    let pixel_data = encode_image(image, colorspace, bit_depth);
+    let max_value = if bit_depth == 16 { 65535 } else { 255 };
+
+    let mut ppm_out = ppm::PPMWriter::new(
+        output_file,
+        image.columns as i32,
+        image.rows as i32,
+        max_value,
+    )?;
+    ppm_out.write_rows(&pixel_data)?;
+    ppm_out.finish()
+}
+
+/// Writes an alpha mask, as traced by Scene::trace_coverage(), to a PGM file
+fn write_coverage(output_file: &str, coverage: &Array2D<f32>) -> io::Result<()> {
+    let samples: Vec<u8> = coverage
+        .iter_rows()
+        .flatten()
+        .map(|alpha| (alpha.clamp(0.0, 1.0) * 255.0).round() as u8)
+        .collect();
+
+    ppm::write_pgm(
+        output_file,
+        coverage.columns as i32,
+        coverage.rows as i32,
+        &samples,
+    )
+}
+
+/// Returns the objects in the scene with the given tag, or an error if there are
+/// none, which is probably a typo
+fn tagged_objects<'a>(
+    scene: &'a mut Scene,
+    tag: &str,
+) -> Result<Vec<&'a mut VisObj>, RaymondError> {
+    let objects: Vec<_> = scene
+        .objects
+        .iter_mut()
+        .filter(|vobj| vobj.has_tag(tag))
+        .collect();
+
+    if objects.is_empty() {
+        Err(RaymondError::Scene(format!(
+            "No objects are tagged \"{}\"",
+            tag
+        )))
+    } else {
+        Ok(objects)
+    }
+}
+
+/// Builds the scene and selects the camera described by the arguments
+fn build_scene_and_camera(args: &CommandLineArguments) -> Result<(Scene, Camera), RaymondError> {
+    let scene_file_error = |filename: &String, message: String| RaymondError::SceneFile {
+        filename: filename.clone(),
+        message,
+    };
+    let scene_file = match (&args.scene_json, &args.scene_file) {
+        (Some(json), _) => {
+            let filename = String::from("from request");
+            let scene_file = SceneFile::parse(json)
+                .map_err(|err| scene_file_error(&filename, err.to_string()))?;
+            Some((filename, scene_file))
         }
+        (None, Some(filename)) => Some((
+            filename.clone(),
+            SceneFile::load(filename).map_err(|err| scene_file_error(filename, err.to_string()))?,
+        )),
+        (None, None) => None,
     };
+    let cameras = match &scene_file {
+        Some((_, scene_file)) => scene_file.cameras(),
+        None => build_cameras(),
+    };
+    let select_camera = |index: usize| {
+        cameras
+            .get(index)
+            .cloned()
+            .ok_or(RaymondError::Scene(format!(
+                "Camera {} does not exist, the scene has {} cameras",
+                index,
+                cameras.len()
+            )))
+    };
+    let mut camera = select_camera(args.camera_index)?;
+    if let Some(view_width) = args.orthographic_width {
+        camera = camera.with_orthographic_projection(view_width);
+    }
+    camera = camera
+        .with_pixel_aspect_ratio(args.pixel_aspect_ratio)
+        .with_lens_shift(args.lens_shift.0, args.lens_shift.1);
+    let portal_camera = match args.portal_camera_index {
+        Some(index) => select_camera(index)?,
+        None => camera.clone(),
+    };
+    let colormap = match &args.palette_file {
+        Some(filename) => palette::load_palette(filename)
+            .map_err(|err| RaymondError::io(err, format!("load palette {}", filename)))?,
+        None => default_colormap(),
+    };
+    let mut scene = match &scene_file {
+        Some((filename, scene_file)) => scene_file
+            .build_scene(&portal_camera, &colormap)
+            .map_err(|msg| scene_file_error(filename, msg))?,
+        None => build_scene(&portal_camera, colormap),
+    };
+    scene.time = args.time;
+    scene.depth_fade_bounces = args.depth_fade_bounces;
+    if let Some(shadow_samples) = args.shadow_samples {
+        for light_source in &mut scene.light_sources {
+            light_source.shadow_samples = shadow_samples;
+        }
+    }
+    if args.sky {
+        let sun = scene
+            .light_sources
+            .first()
+            .ok_or(RaymondError::Scene(String::from(
+                "The sky needs a light source to place the sun at",
+            )))?;
+        scene.environment = Some(Box::new(build_sky(sun)));
+    }
+    // As with the other options taking tags, an unknown tag is probably a typo
+    for tag in args.only_tags.iter().chain(&args.hidden_tags) {
+        tagged_objects(&mut scene, tag)?;
+    }
+    if !args.only_tags.is_empty() {
+        scene
+            .objects
+            .retain(|vobj| args.only_tags.iter().any(|tag| vobj.has_tag(tag)));
+    }
+    scene
+        .objects
+        .retain(|vobj| !args.hidden_tags.iter().any(|tag| vobj.has_tag(tag)));
+    // Materials go first, so that --set can adjust them
+    for (tag, name) in &args.material_overrides {
+        let material = Material::preset(name).ok_or(RaymondError::Scene(format!(
+            "Unknown material \"{}\", expected one of: {}",
+            name,
+            Material::PRESET_NAMES.join(", ")
+        )))?;
+        for vobj in tagged_objects(&mut scene, tag)? {
+            vobj.set_material(&material);
+        }
+    }
+    for (tag, property, value) in &args.property_overrides {
+        for vobj in tagged_objects(&mut scene, tag)? {
+            vobj.set_property(property, *value)
+                .map_err(RaymondError::Scene)?;
+        }
+    }
 
-    let camera = Camera::new(
-        Vec3f {
-            x: -11.0,
-            y: 0.0,
-            z: 2.0,
-        },
-        Vec3f {
-            x: 10.0,
-            y: 0.0,
-            z: -1.0,
-        },
-        45.0,
-    );
-    let scene = build_scene(&camera);
+    if let Err(problems) = scene.validate(&camera) {
+        return Err(RaymondError::Scene(format!(
+            "Problems found in the scene:\n  {}",
+            problems.join("\n  ")
+        )));
+    }
+    scene.build_bvh();
+    scene.emitters = Some(EmitterDistribution::build(&scene));
+
+    if args.aperture > 0.0 {
+        let focus_distance = match args.focus_distance {
+            Some(distance) => distance,
+            None => {
+                // Focus on whatever is in the middle of the image
+                let center_ray = camera.ray_direction(0.0, 0.0);
+                let (_, dist) = scene
+                    .trace_to_nearest_object(&camera.ray_origin(0.0, 0.0), &center_ray)
+                    .ok_or(RaymondError::Scene(String::from(
+                        "Nothing in the middle of the image to focus on, set a focus distance",
+                    )))?;
+                dist * center_ray.dot(camera.direction())
+            }
+        };
+        camera = camera.with_depth_of_field(args.aperture, focus_distance);
+        if let Some((sides, degrees)) = args.aperture_blades {
+            camera = camera.with_aperture_shape(ApertureShape::Polygon {
+                sides,
+                rotation: degrees.to_radians(),
+            });
+        }
+    }
+
+    if args.photons > 0 {
+        let photon_start = Instant::now();
+        scene.caustics = Some(CausticMap::build(&scene, args.photons, CAUSTIC_CELL_SIZE));
+        println!(
+            "Traced photons in {} ms.",
+            photon_start.elapsed().as_millis()
+        );
+    }
+
+    Ok((scene, camera))
+}
+
+/// Builds the scene and traces it as described by the arguments
+fn render(args: &CommandLineArguments) -> Result<Array2D<Rgb>, RaymondError> {
+    let (mut scene, camera) = build_scene_and_camera(args)?;
+    // Large images take a while, so show how far along they are, unless the output
+    // is going somewhere other than the terminal
+    if io::stderr().is_terminal() {
+        scene.progress = Some(Box::new(|done, total| {
+            eprint!("\rTracing image: {}%", done * 100 / total);
+            if done == total {
+                eprintln!();
+            }
+        }));
+    }
 
     let trace_start = Instant::now();
-    let image =
-        scene.trace_image_oversampled(&camera, args.width, args.height, args.oversampling_factor);
+    let image = if let Some(noise_threshold) = args.noise_threshold {
+        let (image, sample_counts) = scene.trace_image_adaptive(
+            &camera,
+            args.width,
+            args.height,
+            args.max_samples,
+            noise_threshold,
+            args.seed,
+        );
+        let counts: Vec<usize> = sample_counts.iter_rows().flatten().copied().collect();
+        println!(
+            "Adaptive sampling took {:.1} samples per pixel on average, {} at most.",
+            (counts.iter().sum::<usize>() as f32) / (counts.len().max(1) as f32),
+            counts.iter().max().unwrap_or(&0)
+        );
+        image
+    } else if args.edge_samples > 0 {
+        scene.trace_image_edge_antialiased(&camera, args.width, args.height, args.edge_samples)
+    } else {
+        scene.trace_image_oversampled(
+            &camera,
+            args.width,
+            args.height,
+            args.oversampling_x,
+            args.oversampling_y,
+            args.aa_sigma,
+        )
+    };
     println!("Traced image in {} ms.", trace_start.elapsed().as_millis());
+    println!("Image hash is {:016x}.", util::image_hash(&image));
 
-    let write_start = Instant::now();
-    let mut ppm_out =
-        ppm::PPMWriter::new(&args.output_file, image.columns as i32, image.rows as i32).unwrap();
+    if let Some(coverage_file) = &args.coverage_file {
+        let coverage = scene.trace_coverage(
+            &camera,
+            args.width,
+            args.height,
+            args.oversampling_x,
+            args.oversampling_y,
+        );
+        write_coverage(coverage_file, &coverage)
+            .map_err(|err| RaymondError::io(err, format!("write coverage to {}", coverage_file)))?;
+    }
+
+    let ray_stats = stats::take_totals();
+    println!(
+        "Cast {} rays ({} primary, {} secondary, {} shadow), deepest bounce was {}.",
+        ray_stats.total_rays(),
+        ray_stats.primary_rays,
+        ray_stats.secondary_rays,
+        ray_stats.shadow_rays,
+        ray_stats.deepest_bounce
+    );
+
+    Ok(image)
+}
+
+/// Renders the image like render(), but encodes and writes out each row as soon as
+/// it is traced, so that the whole image never has to be held in memory
+fn render_streamed(args: &CommandLineArguments) -> Result<(), RaymondError> {
+    let (scene, camera) = build_scene_and_camera(args)?;
+    let context = || format!("write output to {}", args.output_file);
+    let max_value = if args.bit_depth == 16 { 65535 } else { 255 };
+
+    let trace_start = Instant::now();
+    let mut ppm_out = ppm::PPMWriter::new(
+        &args.output_file,
+        args.width as i32,
+        args.height as i32,
+        max_value,
+    )
+    .map_err(|err| RaymondError::io(err, context()))?;
+    let rows = scene.trace_rows(
+        &camera,
+        args.width,
+        args.height,
+        args.oversampling_x,
+        args.oversampling_y,
+        args.aa_sigma,
+    );
+    for row in rows {
+        let row = Array2D::from_vec(1, args.width, row);
+        ppm_out
+            .write_rows(&encode_image(&row, args.colorspace, args.bit_depth))
+            .map_err(|err| RaymondError::io(err, context()))?;
+    }
+    ppm_out
+        .finish()
+        .map_err(|err| RaymondError::io(err, context()))?;
+    println!(
+        "Traced and wrote image in {} ms.",
+        trace_start.elapsed().as_millis()
+    );
+
+    Ok(())
+}
 
-    for scanline in image.iter_rows() {
-        for pixel in scanline {
-            let (red, green, blue) = pixel.linear_to_srgb().rgb24();
-            ppm_out.write(red, green, blue).unwrap();
+/// Renders images on request over HTTP, with each request's query parameters
+/// overriding the command line arguments, eg "/?width=320&camera=1"
+#[cfg(feature = "server")]
+fn serve(port: u16, args: &CommandLineArguments) -> Result<(), RaymondError> {
+    let context = || format!("run server on port {}", port);
+    let listener = std::net::TcpListener::bind(("127.0.0.1", port))
+        .map_err(|err| RaymondError::io(err, context()))?;
+    serve_on(listener, args).map_err(|err| RaymondError::io(err, context()))
+}
+
+/// Answers requests arriving at the listener with PNG images, rendered with the
+/// arguments the server was started with, adjusted by each request
+#[cfg(feature = "server")]
+fn serve_on(listener: std::net::TcpListener, args: &CommandLineArguments) -> io::Result<()> {
+    server::serve(listener, "image/png", |params, body| {
+        let mut request_args = args.clone().with_query_params(params)?;
+        // A scene can be sent in the body of the request, eg with a POST
+        if !body.is_empty() {
+            let json = String::from_utf8(body.to_vec())
+                .map_err(|_| "Scene in request body is not UTF-8")?;
+            request_args.scene_json = Some(json);
         }
+        let image = render(&request_args).map_err(|err| err.to_string())?;
+
+        let pixel_data = encode_image(&image, request_args.colorspace, request_args.bit_depth);
+        Ok(png::encode(
+            image.columns,
+            image.rows,
+            request_args.bit_depth,
+            &pixel_data,
+        ))
+    })
+}
+
+/// Opens a window showing the scene, which can be moved around interactively
+#[cfg(feature = "preview")]
+fn preview(scale: usize, args: &CommandLineArguments) -> Result<(), RaymondError> {
+    let (scene, camera) = build_scene_and_camera(args)?;
+
+    preview::run(
+        &scene,
+        camera,
+        (args.width / scale).max(1),
+        (args.height / scale).max(1),
+        args.colorspace,
+    )
+    .map_err(RaymondError::Preview)
+}
+
+#[cfg(not(feature = "preview"))]
+fn preview(_scale: usize, _args: &CommandLineArguments) -> Result<(), RaymondError> {
+    Err(RaymondError::Arguments(String::from(
+        "This build of raymond does not include the preview feature",
+    )))
+}
+
+#[cfg(not(feature = "server"))]
+fn serve(_port: u16, _args: &CommandLineArguments) -> Result<(), RaymondError> {
+    Err(RaymondError::Arguments(String::from(
+        "This build of raymond does not include the server feature",
+    )))
+}
+
+fn main() -> Result<(), RaymondError> {
+    let args = CommandLineArguments::from_args().inspect_err(|_| {
+        CommandLineArguments::show_usage();
+        eprintln!();
+    })?;
+
+    if let Some(port) = args.serve_port {
+        return serve(port, &args);
+    }
+    if let Some(scale) = args.preview_scale {
+        return preview(scale, &args);
     }
+
+    if args.stream {
+        return render_streamed(&args);
+    }
+
+    let image = render(&args)?;
+
+    let write_start = Instant::now();
+    write_image(&args.output_file, &image, args.colorspace, args.bit_depth)
+        .map_err(|err| RaymondError::io(err, format!("write output to {}", args.output_file)))?;
     println!("Wrote output in {} ms.", write_start.elapsed().as_millis());
 
-    ExitCode::SUCCESS
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses command line style arguments on top of the defaults
+    fn args_from(flags: &[&str]) -> Result<CommandLineArguments, String> {
+        CommandLineArguments::default().with_flags(flags.iter().map(|&flag| flag.into()).collect())
+    }
+
+    /// Total difference in luminance between horizontally adjacent pixels
+    fn local_contrast(image: &Array2D<Rgb>) -> f32 {
+        image
+            .iter_rows()
+            .flat_map(|row| row.windows(2))
+            .map(|pair| (pair[0].luminance() - pair[1].luminance()).abs())
+            .sum()
+    }
+
+    #[test]
+    fn smaller_aa_sigma_gives_a_sharper_image() {
+        let render_with_sigma =
+            |sigma| render(&args_from(&["-w", "64", "-h", "48", "-a", sigma]).unwrap()).unwrap();
+        let sharp = render_with_sigma("0.2");
+        let soft = render_with_sigma("1.0");
+
+        assert!(local_contrast(&sharp) > local_contrast(&soft));
+    }
+
+    #[test]
+    fn streamed_rows_match_the_whole_image() {
+        // Tall enough for several bands, with different oversampling on each axis
+        for flags in [
+            ["-w", "24", "-h", "70", "-s", "2x3"],
+            ["-w", "24", "-h", "70", "-s", "1"],
+        ] {
+            let args = args_from(&flags).unwrap();
+            let (scene, camera) = build_scene_and_camera(&args).unwrap();
+            let whole = scene.trace_image_oversampled(
+                &camera,
+                args.width,
+                args.height,
+                args.oversampling_x,
+                args.oversampling_y,
+                args.aa_sigma,
+            );
+            let streamed: Vec<Rgb> = scene
+                .trace_rows(
+                    &camera,
+                    args.width,
+                    args.height,
+                    args.oversampling_x,
+                    args.oversampling_y,
+                    args.aa_sigma,
+                )
+                .flatten()
+                .collect();
+
+            assert!(
+                whole.into_vec() == streamed,
+                "streamed rows differ for {:?}",
+                flags
+            );
+        }
+    }
+
+    #[test]
+    fn parallel_encoding_matches_one_pixel_at_a_time() {
+        let pixels = (0..200 * 150)
+            .map(|i| Rgb {
+                red: (i % 200) as f32 / 199.0,
+                green: (i / 200) as f32 / 149.0,
+                blue: ((i * 7919) % 1000) as f32 / 999.0,
+            })
+            .collect();
+        let image = Array2D::from_vec(150, 200, pixels);
+
+        for colorspace in [ColorSpace::Srgb, ColorSpace::DisplayP3, ColorSpace::Rec709] {
+            let serial_8: Vec<u8> = image
+                .iter_rows()
+                .flatten()
+                .flat_map(|pixel| {
+                    let (red, green, blue) = colorspace.encode_rgb24(pixel);
+                    [red, green, blue]
+                })
+                .collect();
+            assert!(encode_image(&image, colorspace, 8) == serial_8);
+
+            let serial_16: Vec<u8> = image
+                .iter_rows()
+                .flatten()
+                .flat_map(|pixel| {
+                    let (red, green, blue) = colorspace.encode(pixel).rgb48();
+                    [red, green, blue].into_iter().flat_map(u16::to_be_bytes)
+                })
+                .collect();
+            assert!(encode_image(&image, colorspace, 16) == serial_16);
+        }
+    }
+
+    #[test]
+    fn sixteen_bit_images_read_back_precisely() {
+        // A shallow gradient, which 8 bits can't distinguish all the steps of
+        let pixels = (0..64)
+            .map(|i| Rgb::gray(0.5 + (i as f32) / 8192.0))
+            .collect();
+        let image = Array2D::from_vec(4, 16, pixels);
+        let filename = env::temp_dir().join(format!("raymond_test_{}.ppm", std::process::id()));
+        let filename = filename.to_str().unwrap();
+
+        write_image(filename, &image, ColorSpace::Srgb, 16).unwrap();
+        let read_back = ppm::read_ppm(filename);
+        std::fs::remove_file(filename).unwrap();
+
+        let read_back = read_back.unwrap();
+        assert_eq!((read_back.rows, read_back.columns), (4, 16));
+        for (original, read) in image
+            .iter_rows()
+            .flatten()
+            .zip(read_back.iter_rows().flatten())
+        {
+            assert!(
+                // Closer to this step of the gradient than the next
+                (original.red - read.red).abs() < 0.5 / 8192.0,
+                "{:?} read back as {:?}",
+                original,
+                read
+            );
+        }
+    }
+
+    #[test]
+    fn only_and_hide_filter_objects_by_tag() {
+        let object_count = |flags: &[&str]| {
+            build_scene_and_camera(&args_from(flags).unwrap()).map(|(scene, _)| scene.objects.len())
+        };
+        let all = object_count(&[]).unwrap();
+
+        assert_eq!(object_count(&["-k", "sphere"]).unwrap(), 1);
+        assert_eq!(object_count(&["-k", "sphere", "-k", "floor"]).unwrap(), 2);
+        assert_eq!(object_count(&["-x", "sphere"]).unwrap(), all - 1);
+        assert!(object_count(&["-k", "spehre"]).is_err());
+        assert!(object_count(&["-x", "spehre"]).is_err());
+    }
+
+    #[test]
+    fn edge_samples_are_bounded() {
+        assert!(args_from(&["-e", "16"]).is_ok());
+        assert!(args_from(&["-e", "17"]).is_err());
+        assert!(args_from(&["-e", &usize::MAX.to_string()]).is_err());
+    }
+
+    #[test]
+    fn noise_threshold_must_be_positive_and_finite() {
+        for threshold in ["0", "-0.01", "NaN", "inf"] {
+            assert!(
+                args_from(&["-A", threshold]).is_err(),
+                "{} was accepted",
+                threshold
+            );
+        }
+        assert!(args_from(&["-A", "0.01"]).is_ok());
+    }
+
+    #[test]
+    fn aa_sigma_must_be_positive_and_finite() {
+        for sigma in ["0", "-1", "NaN", "inf", "1000"] {
+            assert!(args_from(&["-a", sigma]).is_err(), "{} was accepted", sigma);
+        }
+        assert!(args_from(&["-a", "0.4"]).is_ok());
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn server_renders_a_posted_scene_as_png() {
+        use std::io::{Read, Write};
+        use std::net::{TcpListener, TcpStream};
+
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || serve_on(listener, &CommandLineArguments::default()));
+
+        let scene = r#"{
+            "background": [0.0, 0.0, 0.0],
+            "cameras": [
+                {"type": "Direction", "position": [0, -5, 0], "direction": [0, 1, 0], "fov_degrees": 60}
+            ],
+            "objects": [
+                {"surface": {"type": "Sphere", "center": [0, 0, 0], "radius": 1},
+                 "texture": {"type": "Color", "color": [1, 0, 0]}}
+            ]
+        }"#;
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "POST /?width=8&height=6&samples=1 HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            scene.len(),
+            scene
+        )
+        .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+
+        let header_end = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap();
+        let headers = String::from_utf8_lossy(&response[..header_end]);
+        assert!(headers.starts_with("HTTP/1.1 200 "), "{}", headers);
+        assert!(headers.contains("Content-Type: image/png"));
+
+        let png = &response[header_end + 4..];
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        // The IHDR chunk comes first, giving the width and height
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(png[16..20], 8u32.to_be_bytes());
+        assert_eq!(png[20..24], 6u32.to_be_bytes());
+        assert!(png.ends_with(b"IEND\xae\x42\x60\x82"));
+    }
 }
@@ -0,0 +1,48 @@
+/// Set of surface properties which can be applied to an object in one go, see
+/// VisObj::set_material(). The object's texture and emission are left alone, so
+/// eg "gold" only makes an object gold colored if its texture is.
+#[derive(Debug, Copy, Clone)]
+pub struct Material {
+    pub reflectivity: f32,
+    pub roughness: f32,
+    pub specular_intensity: f32,
+    pub shininess: f32,
+    pub transparency: f32,
+    pub index_of_refraction: f32,
+    pub ambient_multiplier: f32,
+}
+
+impl Material {
+    /// Names accepted by preset()
+    pub const PRESET_NAMES: [&'static str; 5] = ["glass", "water", "gold", "mirror", "plastic"];
+
+    /// Returns one of a small library of common materials by name, or None if the
+    /// name isn't one of PRESET_NAMES
+    pub fn preset(name: &str) -> Option<Material> {
+        let material = |reflectivity,
+                        roughness,
+                        (specular_intensity, shininess),
+                        transparency,
+                        index_of_refraction| Material {
+            reflectivity,
+            roughness,
+            specular_intensity,
+            shininess,
+            transparency,
+            index_of_refraction,
+            ambient_multiplier: 1.0,
+        };
+
+        match name {
+            // Mostly see-through, with a faint reflection and sharp highlights
+            "glass" => Some(material(0.1, 0.0, (0.8, 200.0), 0.85, 1.5)),
+            "water" => Some(material(0.05, 0.0, (0.6, 120.0), 0.9, 1.33)),
+            // Metals reflect most of the light, slightly blurred
+            "gold" => Some(material(0.8, 0.03, (0.8, 60.0), 0.0, 1.0)),
+            "mirror" => Some(material(0.9, 0.0, (1.0, 500.0), 0.0, 1.0)),
+            // Mostly diffuse, with a dull sheen and broad highlights
+            "plastic" => Some(material(0.05, 0.15, (0.4, 20.0), 0.0, 1.0)),
+            _ => None,
+        }
+    }
+}
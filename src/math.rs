@@ -1,3 +1,6 @@
+use std::ops;
+use std::sync::OnceLock;
+
 use num_complex::Complex;
 
 use crate::util::{run_parallel_jobs, Array2D};
@@ -12,7 +15,7 @@ pub struct Vec3f {
 
 /// "Linear" Rgb value. (ie, SRGB without gamma correction)
 /// Component values fall in [0.0, 1.0]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Rgb {
     pub red: f32,
     pub green: f32,
@@ -26,44 +29,155 @@ impl Vec3f {
         z: 1.0,
     };
 
+    // The named arithmetic methods predate the operators, which they are now
+    // equivalent to
     pub fn add(&self, other: &Vec3f) -> Vec3f {
+        *self + *other
+    }
+
+    pub fn sub(&self, other: &Vec3f) -> Vec3f {
+        *self - *other
+    }
+
+    pub fn scale(&self, factor: f32) -> Vec3f {
+        *self * factor
+    }
+
+    /// Returns the unit vector in the same direction. The zero vector has no
+    /// direction, and gives a vector of NaNs.
+    pub fn normalize(&self) -> Vec3f {
+        self.scale(1.0 / self.length())
+    }
+
+    pub fn length(&self) -> f32 {
+        self.length_squared().sqrt()
+    }
+
+    /// Returns the square of the length, which is cheaper to find, and enough for
+    /// comparing lengths
+    pub fn length_squared(&self) -> f32 {
+        self.dot(self)
+    }
+
+    /// Returns the distance between two points
+    pub fn distance(&self, other: &Vec3f) -> f32 {
+        (*self - *other).length()
+    }
+
+    pub fn dot(&self, other: &Vec3f) -> f32 {
+        (self.x * other.x) + (self.y * other.y) + (self.z * other.z)
+    }
+
+    /// Returns true if none of the components are NaN or infinite
+    pub fn is_finite(&self) -> bool {
+        self.x.is_finite() && self.y.is_finite() && self.z.is_finite()
+    }
+
+    pub fn cross(&self, other: &Vec3f) -> Vec3f {
+        Vec3f {
+            x: self.y * other.z - self.z * other.y,
+            y: self.z * other.x - self.x * other.z,
+            z: self.x * other.y - self.y * other.x,
+        }
+    }
+
+    /// Reflects the vector (eg, the direction of an incident ray) off a surface
+    /// with the given unit normal
+    pub fn reflect(&self, normal: &Vec3f) -> Vec3f {
+        self.sub(&normal.scale(2.0 * self.dot(normal)))
+    }
+
+    /// Refracts a unit direction vector through a surface with the given unit
+    /// normal, which should face against the direction, following Snell's law.
+    /// "eta" is the ratio of the refractive indices, ie the one being left over the
+    /// one being entered. Returns None if there is total internal reflection.
+    pub fn refract(&self, normal: &Vec3f, eta: f32) -> Option<Vec3f> {
+        let cos_incident = -self.dot(normal);
+        let sin2_transmitted = eta * eta * (1.0 - cos_incident * cos_incident);
+        if sin2_transmitted > 1.0 {
+            return None;
+        }
+
+        let cos_transmitted = (1.0 - sin2_transmitted).sqrt();
+        Some(
+            self.scale(eta)
+                .add(&normal.scale(eta * cos_incident - cos_transmitted)),
+        )
+    }
+}
+
+// The operator traits aren't imported, as in this module they would take
+// precedence over the named methods, which take their arguments by reference
+impl ops::Add for Vec3f {
+    type Output = Vec3f;
+
+    fn add(self, other: Vec3f) -> Vec3f {
         Vec3f {
             x: self.x + other.x,
             y: self.y + other.y,
             z: self.z + other.z,
         }
     }
+}
 
-    pub fn sub(&self, other: &Vec3f) -> Vec3f {
+impl ops::Sub for Vec3f {
+    type Output = Vec3f;
+
+    fn sub(self, other: Vec3f) -> Vec3f {
         Vec3f {
             x: self.x - other.x,
             y: self.y - other.y,
             z: self.z - other.z,
         }
     }
+}
 
-    pub fn scale(&self, factor: f32) -> Vec3f {
+impl ops::Mul<f32> for Vec3f {
+    type Output = Vec3f;
+
+    fn mul(self, factor: f32) -> Vec3f {
         Vec3f {
             x: self.x * factor,
             y: self.y * factor,
             z: self.z * factor,
         }
     }
+}
 
-    pub fn normalize(&self) -> Vec3f {
-        self.scale(1.0 / self.dot(self).sqrt())
+impl ops::Neg for Vec3f {
+    type Output = Vec3f;
+
+    fn neg(self) -> Vec3f {
+        Vec3f {
+            x: -self.x,
+            y: -self.y,
+            z: -self.z,
+        }
     }
+}
 
-    pub fn dot(&self, other: &Vec3f) -> f32 {
-        (self.x * other.x) + (self.y * other.y) + (self.z * other.z)
+// Operators on references, so that borrowed vectors don't need dereferencing
+impl ops::Add<&Vec3f> for &Vec3f {
+    type Output = Vec3f;
+
+    fn add(self, other: &Vec3f) -> Vec3f {
+        *self + *other
     }
+}
 
-    pub fn cross(&self, other: &Vec3f) -> Vec3f {
-        Vec3f {
-            x: self.y * other.z - self.z * other.y,
-            y: self.z * other.x - self.x * other.z,
-            z: self.x * other.y - self.y * other.x,
-        }
+impl ops::Sub<&Vec3f> for &Vec3f {
+    type Output = Vec3f;
+
+    fn sub(self, other: &Vec3f) -> Vec3f {
+        *self - *other
+    }
+}
+
+impl ops::Mul<f32> for &Vec3f {
+    type Output = Vec3f;
+
+    fn mul(self, factor: f32) -> Vec3f {
+        *self * factor
     }
 }
 
@@ -73,6 +187,20 @@ impl Rgb {
         green: 0.0,
         blue: 0.0,
     };
+    pub const WHITE: Rgb = Rgb {
+        red: 1.0,
+        green: 1.0,
+        blue: 1.0,
+    };
+
+    /// Shade of gray with all three channels equal to "level"
+    pub fn gray(level: f32) -> Rgb {
+        Rgb {
+            red: level,
+            green: level,
+            blue: level,
+        }
+    }
 
     /// Produce a 24-bit Rgb value (It is assumed that the caller has already converted
     /// to SRGB with linear_to_srgb())
@@ -84,6 +212,16 @@ impl Rgb {
         )
     }
 
+    /// Produce a 48-bit Rgb value, for output formats with 16 bits per channel. (As
+    /// with rgb24(), the caller should already have applied the transfer function)
+    pub fn rgb48(&self) -> (u16, u16, u16) {
+        (
+            (self.red * 65535.0) as u16,
+            (self.green * 65535.0) as u16,
+            (self.blue * 65535.0) as u16,
+        )
+    }
+
     fn map(&self, f: impl Fn(f32) -> f32) -> Rgb {
         Rgb {
             red: f(self.red),
@@ -93,11 +231,31 @@ impl Rgb {
     }
 
     pub fn linear_to_srgb(&self) -> Rgb {
-        self.map(|x| x.max(0.0).min(1.0).powf(1.0 / 2.2))
+        self.map(|x| x.clamp(0.0, 1.0).powf(1.0 / 2.2))
+    }
+
+    /// Relative luminance of the (linear) color, using the Rec. 709 weights
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.red + 0.7152 * self.green + 0.0722 * self.blue
+    }
+
+    /// A neutral gray with the same luminance as this color
+    pub fn to_gray(self) -> Rgb {
+        Rgb::gray(self.luminance())
+    }
+
+    /// Multiply the color, treated as a column vector, by a 3x3 matrix
+    pub fn transform(&self, matrix: &[[f32; 3]; 3]) -> Rgb {
+        let row = |r: &[f32; 3]| r[0] * self.red + r[1] * self.green + r[2] * self.blue;
+        Rgb {
+            red: row(&matrix[0]),
+            green: row(&matrix[1]),
+            blue: row(&matrix[2]),
+        }
     }
 
     pub fn srgb_to_linear(&self) -> Rgb {
-        self.map(|x| x.max(0.0).min(1.0).powf(2.2))
+        self.map(|x| x.clamp(0.0, 1.0).powf(2.2))
     }
 
     // TODO: Very similar to Vec3f functionality, and one could imagine use for other
@@ -116,28 +274,455 @@ impl Rgb {
             blue: self.blue + other.blue,
         }
     }
+    /// Blends linearly from this color (at t = 0.0) to "other" (at t = 1.0)
+    pub fn lerp(&self, other: &Rgb, t: f32) -> Rgb {
+        self.scale(1.0 - t).add(&other.scale(t))
+    }
+    /// Multiplies each channel by the corresponding channel of "other", eg to tint
+    /// a surface color by the color of the light falling on it
+    pub fn mul(&self, other: &Rgb) -> Rgb {
+        Rgb {
+            red: self.red * other.red,
+            green: self.green * other.green,
+            blue: self.blue * other.blue,
+        }
+    }
+}
+
+/// 4x4 matrix for affine transforms of 3-D points and vectors, stored in row
+/// major order. Points and vectors are treated as columns, so they are multiplied
+/// on the right, and a point (x, y, z) has an implicit w of 1, whereas a vector
+/// has a w of 0 and so is unaffected by translation.
+#[derive(Debug, Copy, Clone)]
+pub struct Mat4 {
+    pub m: [f32; 16],
+}
+
+/// Color spaces that images can be output in. Rendering always happens with
+/// linear values and sRGB primaries, the ColorSpace determines how those values
+/// are encoded for display.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ColorSpace {
+    Srgb,
+    DisplayP3,
+    Rec709,
+    /// sRGB primaries without any transfer function, ie the linear values are
+    /// written out directly (clamped to [0.0, 1.0]), eg for debugging shading
+    Linear,
+}
+
+impl ColorSpace {
+    /// Converts linear sRGB to linear Display P3 (both with a D65 white point)
+    const SRGB_TO_DISPLAY_P3: [[f32; 3]; 3] = [
+        [0.8225, 0.1774, 0.0000],
+        [0.0332, 0.9669, 0.0000],
+        [0.0171, 0.0724, 0.9108],
+    ];
+
+    pub fn from_name(name: &str) -> Option<ColorSpace> {
+        match name {
+            "srgb" => Some(ColorSpace::Srgb),
+            "p3" => Some(ColorSpace::DisplayP3),
+            "rec709" => Some(ColorSpace::Rec709),
+            "linear" => Some(ColorSpace::Linear),
+            _ => None,
+        }
+    }
+
+    /// Convert a linear color with sRGB primaries into this color space, including
+    /// the transfer function (ie, gamma correction)
+    pub fn encode(&self, color: &Rgb) -> Rgb {
+        match self {
+            ColorSpace::Srgb => color.linear_to_srgb(),
+            // Display P3 uses the same transfer function as sRGB
+            ColorSpace::DisplayP3 => color.transform(&Self::SRGB_TO_DISPLAY_P3).linear_to_srgb(),
+            // Rec. 709 shares sRGB's primaries, only the transfer function differs
+            ColorSpace::Rec709 => color.map(|x| {
+                let x = x.clamp(0.0, 1.0);
+                if x < 0.018 {
+                    4.5 * x
+                } else {
+                    1.099 * x.powf(0.45) - 0.099
+                }
+            }),
+            ColorSpace::Linear => color.map(|x| x.clamp(0.0, 1.0)),
+        }
+    }
+
+    /// Same as encode(...).rgb24(), but faster for color spaces using the sRGB
+    /// transfer function, which is done with a lookup table
+    pub fn encode_rgb24(&self, color: &Rgb) -> (u8, u8, u8) {
+        let srgb8 = |c: &Rgb| {
+            (
+                linear_to_srgb8(c.red),
+                linear_to_srgb8(c.green),
+                linear_to_srgb8(c.blue),
+            )
+        };
+
+        match self {
+            ColorSpace::Srgb => srgb8(color),
+            ColorSpace::DisplayP3 => srgb8(&color.transform(&Self::SRGB_TO_DISPLAY_P3)),
+            ColorSpace::Rec709 | ColorSpace::Linear => self.encode(color).rgb24(),
+        }
+    }
+}
+
+impl Mat4 {
+    pub const IDENTITY: Mat4 = Mat4 {
+        m: [
+            1.0, 0.0, 0.0, 0.0, //
+            0.0, 1.0, 0.0, 0.0, //
+            0.0, 0.0, 1.0, 0.0, //
+            0.0, 0.0, 0.0, 1.0, //
+        ],
+    };
+
+    /// Construct an affine transform from its upper left 3x3 part, given as rows,
+    /// followed by a translation
+    pub fn affine(rows: [[f32; 3]; 3], offset: &Vec3f) -> Mat4 {
+        Mat4 {
+            m: [
+                rows[0][0], rows[0][1], rows[0][2], offset.x, //
+                rows[1][0], rows[1][1], rows[1][2], offset.y, //
+                rows[2][0], rows[2][1], rows[2][2], offset.z, //
+                0.0, 0.0, 0.0, 1.0, //
+            ],
+        }
+    }
+
+    pub fn translation(offset: &Vec3f) -> Mat4 {
+        Mat4::affine([[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]], offset)
+    }
+
+    /// Scale by a (possibly different) factor along each axis
+    pub fn scaling(factors: &Vec3f) -> Mat4 {
+        Mat4::affine(
+            [
+                [factors.x, 0.0, 0.0],
+                [0.0, factors.y, 0.0],
+                [0.0, 0.0, factors.z],
+            ],
+            &Vec3f {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+        )
+    }
+
+    /// Rotate by "angle" radians around "axis", counterclockwise when looking
+    /// back along the axis (ie, following the right hand rule). The axis need not
+    /// be unit length.
+    pub fn rotation(axis: &Vec3f, angle: f32) -> Mat4 {
+        // Rodrigues' rotation formula
+        let Vec3f { x, y, z } = axis.normalize();
+        let (s, c) = angle.sin_cos();
+        let t = 1.0 - c;
+
+        Mat4::affine(
+            [
+                [t * x * x + c, t * x * y - s * z, t * x * z + s * y],
+                [t * x * y + s * z, t * y * y + c, t * y * z - s * x],
+                [t * x * z - s * y, t * y * z + s * x, t * z * z + c],
+            ],
+            &Vec3f {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+        )
+    }
+
+    pub fn get(&self, row: usize, column: usize) -> f32 {
+        self.m[row * 4 + column]
+    }
+
+    /// Matrix product self * other, ie the transform which applies "other" first
+    /// and then "self"
+    pub fn mul_mat(&self, other: &Mat4) -> Mat4 {
+        let mut m = [0.0; 16];
+        for (i, element) in m.iter_mut().enumerate() {
+            let (row, column) = (i / 4, i % 4);
+            *element = (0..4)
+                .map(|k| self.get(row, k) * other.get(k, column))
+                .sum();
+        }
+
+        Mat4 { m }
+    }
+
+    /// Transform a point, including the translation
+    pub fn mul_point(&self, point: &Vec3f) -> Vec3f {
+        self.mul_vector(point).add(&Vec3f {
+            x: self.get(0, 3),
+            y: self.get(1, 3),
+            z: self.get(2, 3),
+        })
+    }
+
+    /// Transform a vector, such as a direction, which ignores the translation
+    pub fn mul_vector(&self, vector: &Vec3f) -> Vec3f {
+        let row =
+            |r| self.get(r, 0) * vector.x + self.get(r, 1) * vector.y + self.get(r, 2) * vector.z;
+
+        Vec3f {
+            x: row(0),
+            y: row(1),
+            z: row(2),
+        }
+    }
+
+    pub fn transpose(&self) -> Mat4 {
+        let mut m = [0.0; 16];
+        for (i, element) in m.iter_mut().enumerate() {
+            *element = self.get(i % 4, i / 4);
+        }
+
+        Mat4 { m }
+    }
+
+    /// Returns the inverse of the matrix, or None if it is singular. Works for any
+    /// 4x4 matrix, not just affine transforms, by dividing the adjugate (found
+    /// from the 2x2 minors of the top and bottom halves) by the determinant.
+    pub fn inverse(&self) -> Option<Mat4> {
+        let a = |r, c| self.get(r, c);
+
+        // 2x2 minors of the top two rows (s) and the bottom two rows (c)
+        let s0 = a(0, 0) * a(1, 1) - a(1, 0) * a(0, 1);
+        let s1 = a(0, 0) * a(1, 2) - a(1, 0) * a(0, 2);
+        let s2 = a(0, 0) * a(1, 3) - a(1, 0) * a(0, 3);
+        let s3 = a(0, 1) * a(1, 2) - a(1, 1) * a(0, 2);
+        let s4 = a(0, 1) * a(1, 3) - a(1, 1) * a(0, 3);
+        let s5 = a(0, 2) * a(1, 3) - a(1, 2) * a(0, 3);
+        let c5 = a(2, 2) * a(3, 3) - a(3, 2) * a(2, 3);
+        let c4 = a(2, 1) * a(3, 3) - a(3, 1) * a(2, 3);
+        let c3 = a(2, 1) * a(3, 2) - a(3, 1) * a(2, 2);
+        let c2 = a(2, 0) * a(3, 3) - a(3, 0) * a(2, 3);
+        let c1 = a(2, 0) * a(3, 2) - a(3, 0) * a(2, 2);
+        let c0 = a(2, 0) * a(3, 1) - a(3, 0) * a(2, 1);
+
+        let det = s0 * c5 - s1 * c4 + s2 * c3 + s3 * c2 - s4 * c1 + s5 * c0;
+        if det == 0.0 || !det.is_finite() {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+
+        let adjugate = [
+            a(1, 1) * c5 - a(1, 2) * c4 + a(1, 3) * c3,
+            -a(0, 1) * c5 + a(0, 2) * c4 - a(0, 3) * c3,
+            a(3, 1) * s5 - a(3, 2) * s4 + a(3, 3) * s3,
+            -a(2, 1) * s5 + a(2, 2) * s4 - a(2, 3) * s3,
+            -a(1, 0) * c5 + a(1, 2) * c2 - a(1, 3) * c1,
+            a(0, 0) * c5 - a(0, 2) * c2 + a(0, 3) * c1,
+            -a(3, 0) * s5 + a(3, 2) * s2 - a(3, 3) * s1,
+            a(2, 0) * s5 - a(2, 2) * s2 + a(2, 3) * s1,
+            a(1, 0) * c4 - a(1, 1) * c2 + a(1, 3) * c0,
+            -a(0, 0) * c4 + a(0, 1) * c2 - a(0, 3) * c0,
+            a(3, 0) * s4 - a(3, 1) * s2 + a(3, 3) * s0,
+            -a(2, 0) * s4 + a(2, 1) * s2 - a(2, 3) * s0,
+            -a(1, 0) * c3 + a(1, 1) * c1 - a(1, 2) * c0,
+            a(0, 0) * c3 - a(0, 1) * c1 + a(0, 2) * c0,
+            -a(3, 0) * s3 + a(3, 1) * s1 - a(3, 2) * s0,
+            a(2, 0) * s3 - a(2, 1) * s1 + a(2, 2) * s0,
+        ];
+
+        Some(Mat4 {
+            m: adjugate.map(|x| x * inv_det),
+        })
+    }
+
+    /// Returns true if none of the elements are NaN or infinite
+    pub fn is_finite(&self) -> bool {
+        self.m.iter().all(|x| x.is_finite())
+    }
+}
+
+/// Linear values below 2^SRGB_TABLE_MIN_EXPONENT encode to 0 in 8-bit sRGB
+const SRGB_TABLE_MIN_EXPONENT: i32 = -20;
+/// Number of mantissa bits used to index SRGB_TABLE, within each power of two
+const SRGB_TABLE_MANTISSA_BITS: u32 = 7;
+
+/// 8-bit sRGB code for linear values from 2^SRGB_TABLE_MIN_EXPONENT up to 1.0.
+/// The table is indexed by the exponent and the top mantissa bits of the value,
+/// so that the buckets get smaller towards zero where the transfer function is
+/// steepest, which keeps the result within one code value of using powf().
+static SRGB_TABLE: OnceLock<Vec<u8>> = OnceLock::new();
+
+fn srgb_table_min_bits() -> u32 {
+    2.0f32.powi(SRGB_TABLE_MIN_EXPONENT).to_bits()
+}
+
+/// Converts a linear value to an 8-bit sRGB code, like linear_to_srgb() followed
+/// by rgb24(), but using a lookup table instead of calling powf()
+pub fn linear_to_srgb8(x: f32) -> u8 {
+    let bucket_shift = 23 - SRGB_TABLE_MANTISSA_BITS;
+    let table = SRGB_TABLE.get_or_init(|| {
+        let buckets = (-SRGB_TABLE_MIN_EXPONENT as u32) << SRGB_TABLE_MANTISSA_BITS;
+        (0..buckets)
+            .map(|bucket| {
+                // Use the value in the middle of the bucket
+                let bits =
+                    srgb_table_min_bits() + (bucket << bucket_shift) + (1 << (bucket_shift - 1));
+                let (code, _, _) = Rgb {
+                    red: f32::from_bits(bits),
+                    green: 0.0,
+                    blue: 0.0,
+                }
+                .linear_to_srgb()
+                .rgb24();
+                code
+            })
+            .collect()
+    });
+
+    // NaN also encodes to 0, as it does with powf()
+    if x.is_nan() || x < f32::from_bits(srgb_table_min_bits()) {
+        0
+    } else if x >= 1.0 {
+        255
+    } else {
+        table[((x.to_bits() - srgb_table_min_bits()) >> bucket_shift) as usize]
+    }
 }
 
 /// Finds the roots of the equation ax^2 + bx + c = 0. Returns None if there is
-/// no solution,
-pub fn solve_quadratic(a: f32, b: f32, c: f32) -> Option<(f32, f32)> {
+/// no solution. The single solution (tangent) case tends to be degenerate, so it
+/// is only returned, as a repeated root, if include_tangent is set.
+pub fn solve_quadratic(a: f32, b: f32, c: f32, include_tangent: bool) -> Option<(f32, f32)> {
     let discriminant = (b * b) - 4.0 * a * c;
-    // The single solution case tends to be degenerate, we only find the two solution case
+
     if discriminant > 0.0 {
-        let scale = 1.0 / (2.0 * a);
-        let x = -b * scale;
-        let delta = discriminant.sqrt() * scale;
+        // Computing both roots as (-b +/- sqrt(discriminant)) / 2a loses precision
+        // in whichever one subtracts two similar quantities. Instead, find the root
+        // which adds quantities of the same sign, and then use the fact that the
+        // product of the roots is c/a to find the other one.
+        let q = -0.5 * (b + discriminant.sqrt().copysign(b));
 
-        Some((x + delta, x - delta))
+        Some((q / a, c / q))
+    } else if discriminant == 0.0 && include_tangent {
+        let x = -b / (2.0 * a);
+
+        Some((x, x))
     } else {
         None
     }
 }
 
+/// Finds the real roots of the equation x^3 + ax^2 + bx + c = 0, returning either
+/// one or three of them (repeated roots are returned repeatedly).
+pub fn solve_cubic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    // Substituting x = t - a/3 gives the depressed cubic t^3 + pt + q = 0
+    let shift = -a / 3.0;
+    let p = b - a * a / 3.0;
+    let q = (2.0 * a * a * a) / 27.0 - (a * b) / 3.0 + c;
+    let discriminant = (q * q) / 4.0 + (p * p * p) / 27.0;
+
+    if discriminant > 0.0 || p == 0.0 {
+        // One real root (Cardano's formula)
+        let sqrt_discriminant = discriminant.max(0.0).sqrt();
+        let t = (-q / 2.0 + sqrt_discriminant).cbrt() + (-q / 2.0 - sqrt_discriminant).cbrt();
+        vec![t + shift]
+    } else {
+        // Three real roots, which are found trigonometrically
+        let m = 2.0 * (-p / 3.0).sqrt();
+        let theta = ((3.0 * q) / (p * m)).clamp(-1.0, 1.0).acos() / 3.0;
+        (0..3)
+            .map(|k| m * (theta - (2.0 * std::f64::consts::PI * k as f64) / 3.0).cos() + shift)
+            .collect()
+    }
+}
+
+/// Finds the real roots of the equation x^4 + ax^3 + bx^2 + cx + d = 0, in no
+/// particular order, using Ferrari's method. The roots are then polished with a
+/// few Newton-Raphson steps, since the closed form loses precision when eg a ray
+/// only just grazes a torus.
+pub fn solve_quartic(a: f64, b: f64, c: f64, d: f64) -> Vec<f64> {
+    // Substituting x = y - a/4 gives the depressed quartic y^4 + py^2 + qy + r = 0
+    let shift = -a / 4.0;
+    let a2 = a * a;
+    let p = b - (3.0 * a2) / 8.0;
+    let q = c - (a * b) / 2.0 + (a2 * a) / 8.0;
+    let r = d - (a * c) / 4.0 + (a2 * b) / 16.0 - (3.0 * a2 * a2) / 256.0;
+
+    let mut roots = Vec::with_capacity(4);
+    let mut push_quadratic_roots = |b: f64, c: f64| {
+        let discriminant = b * b - 4.0 * c;
+        if discriminant >= 0.0 {
+            let sqrt_discriminant = discriminant.sqrt();
+            roots.push((-b + sqrt_discriminant) / 2.0);
+            roots.push((-b - sqrt_discriminant) / 2.0);
+        }
+    };
+
+    if q.abs() < 1e-12 {
+        // Biquadratic: a quadratic in y^2
+        let discriminant = p * p - 4.0 * r;
+        if discriminant >= 0.0 {
+            for z in [
+                (-p + discriminant.sqrt()) / 2.0,
+                (-p - discriminant.sqrt()) / 2.0,
+            ] {
+                push_quadratic_roots(0.0, -z);
+            }
+        }
+    } else {
+        // Add 2my^2 + m^2 + pm to both sides, for an "m" which makes the right hand
+        // side a perfect square, so the quartic factors into two quadratics. Such
+        // an m is a positive root of the resolvent cubic 8m^3 + 8pm^2 + (2p^2 - 8r)m
+        // - q^2 = 0, which always exists since the cubic is -q^2 at m = 0.
+        let m = solve_cubic(p, (p * p) / 4.0 - r, -(q * q) / 8.0)
+            .into_iter()
+            .fold(f64::NEG_INFINITY, f64::max);
+        if m > 0.0 {
+            let s = (2.0 * m).sqrt();
+            push_quadratic_roots(-s, p / 2.0 + m + q / (2.0 * s));
+            push_quadratic_roots(s, p / 2.0 + m - q / (2.0 * s));
+        }
+    }
+
+    roots
+        .into_iter()
+        .map(|root| {
+            let mut x = root + shift;
+            for _ in 0..2 {
+                let value = (((x + a) * x + b) * x + c) * x + d;
+                let slope = ((4.0 * x + 3.0 * a) * x + 2.0 * b) * x + c;
+                if slope != 0.0 {
+                    x -= value / slope;
+                }
+            }
+            x
+        })
+        .collect()
+}
+
+/// Returns two unit vectors which, together with the unit vector "n", form an
+/// orthonormal basis.
+/// See "Building an Orthonormal Basis, Revisited" (Duff et al., 2017)
+pub fn orthonormal_basis(n: &Vec3f) -> (Vec3f, Vec3f) {
+    let sign = 1.0_f32.copysign(n.z);
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+
+    (
+        Vec3f {
+            x: 1.0 + sign * n.x * n.x * a,
+            y: sign * b,
+            z: -sign * n.x,
+        },
+        Vec3f {
+            x: b,
+            y: sign + n.y * n.y * a,
+            z: -n.y,
+        },
+    )
+}
+
 /// Finds the angle of reflection of an incident ray against a surface with the
 /// normal vector.
 pub fn angle_of_reflection(incident: &Vec3f, normal: &Vec3f) -> Vec3f {
-    incident.sub(&normal.scale(2.0 * incident.dot(normal)))
+    incident.reflect(normal)
 }
 
 /// Generates a gaussian shaped filter for, eg, a Gaussian blur
@@ -154,13 +739,43 @@ pub fn gaussian_kernel(sigma: f32) -> Vec<f32> {
         .collect()
 }
 
+/// Returns the number of output samples produced by convolving a kernel of the
+/// given length along an input of the given length, keeping every
+/// "decimation_factor"-th sample. Only positions where the kernel fits entirely
+/// within the input are produced, so this is (L - (K - 1))/D, rounded down.
+pub fn convolution_output_length(
+    input_length: usize,
+    kernel_length: usize,
+    decimation_factor: usize,
+) -> usize {
+    input_length.saturating_sub(kernel_length - 1) / decimation_factor
+}
+
+/// The inverse of convolution_output_length: returns the smallest input length
+/// which produces exactly "output_length" samples.
+pub fn convolution_input_length(
+    output_length: usize,
+    kernel_length: usize,
+    decimation_factor: usize,
+) -> usize {
+    (output_length * decimation_factor) + (kernel_length - 1)
+}
+
 /// Performs a two dimensional convolution against the provided image and returns
-/// a new image. For a W by H image with kernel length K and decimation factor D, the
-/// output dimensions will be (W - (K - 1))/D by (H - (K - 1))/D
-pub fn convolve_2d(image: &Array2D<Rgb>, kernel: &[f32], decimation_factor: usize) -> Array2D<Rgb> {
+/// a new image, using separate kernels and decimation factors horizontally and
+/// vertically. For a W by H image with kernel lengths Kx and Ky and decimation
+/// factors Dx and Dy, the output dimensions will be (W - (Kx - 1))/Dx by
+/// (H - (Ky - 1))/Dy, see convolution_output_length.
+pub fn convolve_2d(
+    image: &Array2D<Rgb>,
+    kernel_x: &[f32],
+    decimation_x: usize,
+    kernel_y: &[f32],
+    decimation_y: usize,
+) -> Array2D<Rgb> {
     // We convolve & transpose twice, which results in an untransposed image
-    let flipped = convolve_and_transpose(image, kernel, decimation_factor);
-    convolve_and_transpose(&flipped, kernel, decimation_factor)
+    let flipped = convolve_and_transpose(image, kernel_x, decimation_x);
+    convolve_and_transpose(&flipped, kernel_y, decimation_y)
 }
 
 /// Convolves the given kernel across the image horizontally, and returns a
@@ -177,7 +792,7 @@ fn convolve_and_transpose(
     let input_height = image.rows;
     let kernel_length = kernel.len();
     let output_width = input_height;
-    let output_height = (input_width - (kernel_length - 1)) / decimation_factor;
+    let output_height = convolution_output_length(input_width, kernel_length, decimation_factor);
     let mut output_image = Array2D::new(output_height, output_width, &Rgb::BLACK);
 
     // Create jobs that blur the rows of the input image into the columns of
@@ -238,6 +853,22 @@ pub fn mandelbrot_escape_time(c: Complex<f32>) -> Option<f32> {
     Some(escape_time)
 }
 
+/// Returns the diffuse lighting factor for a light at an angle with the given cosine
+/// to the surface normal, ie max(cos_angle, 0.0), but fading smoothly to zero over
+/// the last "softening" of cosine before the terminator (where the light grazes the
+/// surface) instead of abruptly. This hides the faceted looking terminator caused
+/// by coarse geometry or shadow rays hitting the surface they started from.
+pub fn soften_terminator(cos_angle: f32, softening: f32) -> f32 {
+    if cos_angle.is_nan() || cos_angle <= 0.0 {
+        0.0
+    } else if cos_angle >= softening {
+        cos_angle
+    } else {
+        let t = cos_angle / softening;
+        cos_angle * t * t * (3.0 - 2.0 * t) // smoothstep
+    }
+}
+
 /// Linearlly interpolates into a grid of colors, wrapping a circular manner if index
 /// exceeds the length of the grid.
 pub fn linear_interpolation(grid: &[Rgb], index: f32) -> Rgb {
@@ -246,6 +877,184 @@ pub fn linear_interpolation(grid: &[Rgb], index: f32) -> Rgb {
     let a = grid[base_index % grid.len()];
     let b = grid[(base_index + 1) % grid.len()];
 
-    a.scale(1.0 - fractional_index)
-        .add(&b.scale(fractional_index))
+    a.lerp(&b, fractional_index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn luminance_weights_green_most_and_blue_least() {
+        assert!((Rgb::WHITE.luminance() - 1.0).abs() < 1e-4);
+        let blue = Rgb {
+            red: 0.0,
+            green: 0.0,
+            blue: 1.0,
+        };
+        assert!(blue.luminance() < 0.1);
+        let green = Rgb {
+            red: 0.0,
+            green: 1.0,
+            blue: 0.0,
+        };
+        assert!(green.luminance() > 0.5);
+
+        let gray = blue.to_gray();
+        assert_eq!(gray, Rgb::gray(blue.luminance()));
+    }
+
+    fn vec3f(x: f32, y: f32, z: f32) -> Vec3f {
+        Vec3f { x, y, z }
+    }
+
+    fn assert_vec_close(actual: &Vec3f, expected: &Vec3f) {
+        assert!(
+            actual.distance(expected) < 1e-4,
+            "expected {:?}, got {:?}",
+            expected,
+            actual
+        );
+    }
+
+    fn assert_identity(matrix: &Mat4) {
+        for (actual, expected) in matrix.m.iter().zip(Mat4::IDENTITY.m.iter()) {
+            assert!(
+                (actual - expected).abs() < 1e-5,
+                "expected the identity, got {:?}",
+                matrix
+            );
+        }
+    }
+
+    #[test]
+    fn translation_inverse_round_trip() {
+        let offset = vec3f(3.0, -2.0, 7.5);
+        let point = vec3f(0.25, 4.0, -1.0);
+        let translation = Mat4::translation(&offset);
+
+        let moved = translation.mul_point(&point);
+        assert_vec_close(&moved, &(point + offset));
+        let inverse = translation.inverse().unwrap();
+        assert_vec_close(&inverse.mul_point(&moved), &point);
+    }
+
+    #[test]
+    fn mul_mat_applies_right_hand_side_first() {
+        let scale_then_translate =
+            Mat4::translation(&vec3f(1.0, 2.0, 3.0)).mul_mat(&Mat4::scaling(&vec3f(2.0, 3.0, 4.0)));
+        assert_vec_close(
+            &scale_then_translate.mul_point(&vec3f(1.0, 1.0, 1.0)),
+            &vec3f(3.0, 5.0, 7.0),
+        );
+        // Directions are scaled but not translated
+        assert_vec_close(
+            &scale_then_translate.mul_vector(&vec3f(1.0, 1.0, 1.0)),
+            &vec3f(2.0, 3.0, 4.0),
+        );
+    }
+
+    #[test]
+    fn inverse_of_composed_transform() {
+        let transform = Mat4::translation(&vec3f(-4.0, 0.5, 9.0))
+            .mul_mat(&Mat4::scaling(&vec3f(2.0, 0.5, 3.0)))
+            .mul_mat(&Mat4::rotation(&vec3f(1.0, 2.0, -0.5), 0.7));
+        let inverse = transform.inverse().unwrap();
+
+        assert_identity(&inverse.mul_mat(&transform));
+        assert_identity(&transform.mul_mat(&inverse));
+
+        let point = vec3f(1.5, -3.0, 0.25);
+        assert_vec_close(&inverse.mul_point(&transform.mul_point(&point)), &point);
+    }
+
+    #[test]
+    fn inverse_of_non_affine_matrix() {
+        // Every element is non-zero in the bottom row too, so all of the adjugate
+        // terms are exercised
+        let matrix = Mat4 {
+            m: [
+                2.0, 1.0, 0.5, 3.0, //
+                1.0, 3.0, 1.0, -1.0, //
+                0.0, 1.0, 4.0, 1.0, //
+                1.0, -2.0, 2.0, 5.0, //
+            ],
+        };
+        let inverse = matrix.inverse().unwrap();
+
+        assert_identity(&inverse.mul_mat(&matrix));
+        assert_identity(&matrix.mul_mat(&inverse));
+    }
+
+    #[test]
+    fn singular_matrix_has_no_inverse() {
+        assert!(Mat4::scaling(&vec3f(1.0, 0.0, 1.0)).inverse().is_none());
+    }
+
+    /// Roots in increasing order
+    fn sorted_roots(a: f32, b: f32, c: f32, include_tangent: bool) -> Option<(f32, f32)> {
+        solve_quadratic(a, b, c, include_tangent).map(|(x1, x2)| (x1.min(x2), x1.max(x2)))
+    }
+
+    #[test]
+    fn quadratic_roots_of_very_different_sizes_are_accurate() {
+        // The roots of x^2 - 10000x + 1 are about 10000 and 0.0001. The textbook
+        // formula gets 0 for the small one in f32, since sqrt(b^2 - 4ac) rounds to b.
+        let (small, large) = sorted_roots(1.0, -10_000.0, 1.0, false).unwrap();
+        let exact_small = (10_000.0 - (1e8f64 - 4.0).sqrt()) / 2.0;
+        let exact_large = (10_000.0 + (1e8f64 - 4.0).sqrt()) / 2.0;
+        assert!(
+            ((small as f64) / exact_small - 1.0).abs() < 1e-6,
+            "{}",
+            small
+        );
+        assert!(
+            ((large as f64) / exact_large - 1.0).abs() < 1e-6,
+            "{}",
+            large
+        );
+
+        // The same with the signs of the roots flipped, so b is positive
+        let (small, _) = sorted_roots(1.0, 10_000.0, 1.0, false).unwrap();
+        assert!(
+            ((small as f64) / -exact_large - 1.0).abs() < 1e-6,
+            "{}",
+            small
+        );
+
+        // A zero root
+        assert_eq!(sorted_roots(2.0, -4.0, 0.0, false), Some((0.0, 2.0)));
+    }
+
+    #[test]
+    fn quadratic_tangent_case_is_optional() {
+        // (x - 3)^2 = x^2 - 6x + 9
+        assert_eq!(sorted_roots(1.0, -6.0, 9.0, true), Some((3.0, 3.0)));
+        assert_eq!(sorted_roots(1.0, -6.0, 9.0, false), None);
+        // No real roots at all
+        assert_eq!(sorted_roots(1.0, 0.0, 1.0, true), None);
+    }
+
+    #[test]
+    fn srgb_table_is_within_one_code_of_powf() {
+        let powf_code = |x: f32| Rgb::gray(x).linear_to_srgb().rgb24().0;
+        // Evenly spaced values, and then values spread out towards zero where the
+        // table's buckets get small
+        let linear = (0..=200_000).map(|i| (i as f32) / 200_000.0);
+        let logarithmic = (0..=200_000).map(|i| 2.0f32.powf(-30.0 * (i as f32) / 200_000.0));
+        for x in linear.chain(logarithmic) {
+            let difference = (linear_to_srgb8(x) as i32 - powf_code(x) as i32).abs();
+            assert!(
+                difference <= 1,
+                "{} encodes to {} rather than {}",
+                x,
+                linear_to_srgb8(x),
+                powf_code(x)
+            );
+        }
+
+        for x in [-1.0, 0.0, 1.0, 2.0, f32::INFINITY, f32::NAN] {
+            assert_eq!(linear_to_srgb8(x), powf_code(x), "{}", x);
+        }
+    }
 }
@@ -0,0 +1,245 @@
+use std::fs;
+use std::io;
+
+use crate::bvh::Bvh;
+use crate::math::Vec3f;
+use crate::surface::{Aabb, Surface, SurfaceProperties, SurfaceSample, Triangle};
+use crate::util::rand_f32;
+
+/// Surface made up of many triangles, eg loaded from an OBJ file. If the
+/// triangles have normals given at their corners, the normal is interpolated
+/// across each triangle, so that curved surfaces look smooth.
+pub struct Mesh {
+    triangles: Vec<Triangle>,
+    /// Normals at the corners of each triangle, if known
+    corner_normals: Vec<Option<[Vec3f; 3]>>,
+    bvh: Bvh,
+}
+
+impl Mesh {
+    /// Loads a Wavefront OBJ file, see parse_obj()
+    pub fn load_obj(filename: &str) -> io::Result<Mesh> {
+        let text = fs::read_to_string(filename)?;
+
+        parse_obj(&text).map_err(|msg| io::Error::new(io::ErrorKind::InvalidData, msg))
+    }
+
+    /// Finds the triangle that a point on the mesh's surface lies on
+    fn triangle_at(&self, point: &Vec3f) -> usize {
+        // The closest triangle to the point which contains it (allowing for some
+        // rounding error), or failing that, just the one whose plane is closest
+        let plane_distance = |triangle: &Triangle| {
+            let (u, v) = triangle.barycentric(point);
+            let inside = u >= -1e-4 && v >= -1e-4 && u + v <= 1.0 + 1e-4;
+            let distance = triangle
+                .at_point(point)
+                .normal
+                .dot(&point.sub(&triangle.a()));
+            (!inside, distance.abs())
+        };
+
+        // Only triangles whose bounding boxes contain the point need considering
+        let mut candidates = self.bvh.items_containing(point);
+        if candidates.is_empty() {
+            candidates = (0..self.triangles.len()).collect();
+        }
+
+        candidates
+            .into_iter()
+            .min_by(|&i, &j| {
+                let (outside_i, distance_i) = plane_distance(&self.triangles[i]);
+                let (outside_j, distance_j) = plane_distance(&self.triangles[j]);
+                outside_i
+                    .cmp(&outside_j)
+                    .then(distance_i.total_cmp(&distance_j))
+            })
+            .unwrap_or(0)
+    }
+}
+
+impl Surface for Mesh {
+    fn intersection_with_ray(&self, ray_origin: &Vec3f, ray_direction: &Vec3f) -> Option<f32> {
+        self.bvh
+            .nearest(ray_origin, ray_direction, f32::INFINITY, |index| {
+                self.triangles[index].intersection_with_ray(ray_origin, ray_direction)
+            })
+            .map(|(_, dist)| dist)
+    }
+
+    fn at_point(&self, point_on_surface: &Vec3f) -> SurfaceProperties {
+        let index = self.triangle_at(point_on_surface);
+        let surf_prop = self.triangles[index].at_point(point_on_surface);
+
+        match self.corner_normals[index] {
+            Some([normal_a, normal_b, normal_c]) => {
+                let (u, v) = (surf_prop.u, surf_prop.v);
+                let normal = normal_a
+                    .scale(1.0 - u - v)
+                    .add(&normal_b.scale(u))
+                    .add(&normal_c.scale(v))
+                    .normalize();
+                SurfaceProperties {
+                    normal,
+                    ..surf_prop
+                }
+            }
+            None => surf_prop,
+        }
+    }
+
+    fn sample_point(&self) -> Option<SurfaceSample> {
+        // Pick a triangle with probability proportional to its area, and then a
+        // point on it, which overall is uniform over the mesh
+        let total_area = self.area()?;
+        let mut remaining = rand_f32() * total_area;
+        let triangle = self
+            .triangles
+            .iter()
+            .find(|triangle| {
+                remaining -= triangle.area().unwrap_or(0.0);
+                remaining <= 0.0
+            })
+            .or(self.triangles.last())?;
+
+        triangle.sample_point().map(|sample| SurfaceSample {
+            pdf: 1.0 / total_area,
+            ..sample
+        })
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.triangles
+            .iter()
+            .filter_map(|triangle| triangle.bounding_box())
+            .reduce(|bounds, triangle_bounds| bounds.union(&triangle_bounds))
+    }
+
+    fn area(&self) -> Option<f32> {
+        Some(
+            self.triangles
+                .iter()
+                .filter_map(|triangle| triangle.area())
+                .sum(),
+        )
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.triangles.is_empty() {
+            return Err(String::from("Mesh has no triangles"));
+        }
+        // Meshes often contain a few degenerate triangles, which are harmless
+        // since they are never hit, so only check that the corners are finite
+        if self.triangles.iter().all(|triangle| {
+            triangle.validate().is_ok() || triangle.area().is_some_and(|area| area == 0.0)
+        }) {
+            Ok(())
+        } else {
+            Err(String::from("Mesh has triangles with non-finite corners"))
+        }
+    }
+}
+
+/// Parses the subset of the Wavefront OBJ format needed for triangle meshes:
+/// vertices ("v x y z"), vertex normals ("vn x y z") and faces ("f" followed by
+/// three or more "v", "v/vt", "v/vt/vn" or "v//vn" corners, with 1-based or
+/// negative relative indices). Faces with more than three corners are split
+/// into a fan of triangles. Faces without normals at every corner use the flat
+/// normal of each triangle. Everything else, such as comments, texture
+/// coordinates and groups, is ignored.
+pub fn parse_obj(text: &str) -> Result<Mesh, String> {
+    let mut vertices: Vec<Vec3f> = Vec::new();
+    let mut normals: Vec<Vec3f> = Vec::new();
+    let mut triangles = Vec::new();
+    let mut corner_normals = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let error = |msg: &str| format!("Line {}: {}", index + 1, msg);
+        let mut fields = line.split_whitespace();
+
+        match fields.next() {
+            Some("v") => vertices.push(parse_vector(fields).ok_or(error("Invalid vertex"))?),
+            Some("vn") => normals.push(
+                parse_vector(fields)
+                    .ok_or(error("Invalid normal"))?
+                    .normalize(),
+            ),
+            Some("f") => {
+                let corners = fields
+                    .map(|corner| parse_corner(corner, vertices.len(), normals.len()))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or(error("Invalid face"))?;
+                if corners.len() < 3 {
+                    return Err(error("Face has fewer than three corners"));
+                }
+
+                for i in 1..(corners.len() - 1) {
+                    let fan = [corners[0], corners[i], corners[i + 1]];
+                    triangles.push(Triangle::new(
+                        &vertices[fan[0].0],
+                        &vertices[fan[1].0],
+                        &vertices[fan[2].0],
+                    ));
+                    corner_normals.push(match (fan[0].1, fan[1].1, fan[2].1) {
+                        (Some(a), Some(b), Some(c)) => Some([normals[a], normals[b], normals[c]]),
+                        _ => None,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let bounds: Vec<_> = triangles
+        .iter()
+        .map(|triangle| triangle.bounding_box())
+        .collect();
+
+    Ok(Mesh {
+        bvh: Bvh::build(&bounds),
+        triangles,
+        corner_normals,
+    })
+}
+
+fn parse_vector<'a>(mut fields: impl Iterator<Item = &'a str>) -> Option<Vec3f> {
+    Some(Vec3f {
+        x: fields.next()?.parse().ok()?,
+        y: fields.next()?.parse().ok()?,
+        z: fields.next()?.parse().ok()?,
+    })
+}
+
+/// Parses one corner of a face, returning the (zero-based) indices of its vertex
+/// and its normal, if any
+fn parse_corner(
+    corner: &str,
+    vertex_count: usize,
+    normal_count: usize,
+) -> Option<(usize, Option<usize>)> {
+    let mut indices = corner.split('/');
+    let vertex = resolve_index(indices.next()?, vertex_count)?;
+    let _texture_coordinate = indices.next();
+    let normal = match indices.next() {
+        Some(normal) => Some(resolve_index(normal, normal_count)?),
+        None => None,
+    };
+
+    Some((vertex, normal))
+}
+
+/// Converts an OBJ index, which counts from 1, or backwards from the most
+/// recent element if negative, into an index into a list of "count" elements
+fn resolve_index(index: &str, count: usize) -> Option<usize> {
+    let index: i64 = index.parse().ok()?;
+    let resolved = if index < 0 {
+        (count as i64) + index
+    } else {
+        index - 1
+    };
+
+    if (0..count as i64).contains(&resolved) {
+        Some(resolved as usize)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,139 @@
+use std::fs;
+use std::io;
+
+use crate::math::Rgb;
+
+/// Loads a list of colors from a file, either a GIMP palette (.gpl) or a CSV file
+/// with one "red,green,blue" triple per line. Components are 0 to 255, and the
+/// colors are returned sRGB encoded (but scaled to [0.0, 1.0]), as used by eg
+/// MandelbrotSet's colormap.
+pub fn load_palette(filename: &str) -> io::Result<Vec<Rgb>> {
+    let text = fs::read_to_string(filename)?;
+
+    let colors = if text.starts_with("GIMP Palette") {
+        parse_gpl(&text)
+    } else {
+        parse_csv(&text)
+    }
+    .map_err(|msg| io::Error::new(io::ErrorKind::InvalidData, msg))?;
+
+    if colors.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Palette contains no colors",
+        ));
+    }
+
+    Ok(colors)
+}
+
+/// Parses a GIMP palette, which has a "GIMP Palette" header, optional "Name:"
+/// and "Columns:" lines, comments starting with '#', and then lines of the form
+/// "red green blue [name]".
+pub fn parse_gpl(text: &str) -> Result<Vec<Rgb>, String> {
+    text.lines()
+        .enumerate()
+        .skip(1) // "GIMP Palette"
+        .filter(|(_, line)| {
+            let line = line.trim();
+            !(line.is_empty()
+                || line.starts_with('#')
+                || line.starts_with("Name:")
+                || line.starts_with("Columns:"))
+        })
+        .map(|(index, line)| {
+            let components: Vec<&str> = line.split_whitespace().take(3).collect();
+            parse_components(&components).ok_or(format!(
+                "Line {}: expected \"red green blue\", got \"{}\"",
+                index + 1,
+                line
+            ))
+        })
+        .collect()
+}
+
+/// Parses lines of "red,green,blue", ignoring blank lines and comments starting
+/// with '#'
+pub fn parse_csv(text: &str) -> Result<Vec<Rgb>, String> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !(line.trim().is_empty() || line.trim().starts_with('#')))
+        .map(|(index, line)| {
+            let components: Vec<&str> = line.split(',').map(|c| c.trim()).collect();
+            parse_components(&components).ok_or(format!(
+                "Line {}: expected \"red,green,blue\", got \"{}\"",
+                index + 1,
+                line
+            ))
+        })
+        .collect()
+}
+
+fn parse_components(components: &[&str]) -> Option<Rgb> {
+    let component = |s: &str| -> Option<f32> {
+        let value: u8 = s.parse().ok()?;
+        Some((value as f32) / 255.0)
+    };
+
+    match components {
+        [red, green, blue] => Some(Rgb {
+            red: component(red)?,
+            green: component(green)?,
+            blue: component(blue)?,
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_gimp_palette() {
+        let text = "GIMP Palette\n\
+                    Name: Test\n\
+                    Columns: 2\n\
+                    # A comment\n\
+                    \n\
+                    255   0   0\tRed\n\
+                      0 255 255 Cyan with spaces in its name\n\
+                     51 102 204\n";
+
+        let colors = parse_gpl(text).unwrap();
+        assert_eq!(
+            colors,
+            [
+                Rgb {
+                    red: 1.0,
+                    green: 0.0,
+                    blue: 0.0
+                },
+                Rgb {
+                    red: 0.0,
+                    green: 1.0,
+                    blue: 1.0
+                },
+                Rgb {
+                    red: 0.2,
+                    green: 0.4,
+                    blue: 0.8
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn gimp_palette_errors_name_the_line() {
+        let err = parse_gpl("GIMP Palette\n255 0 0\n12 34\n").unwrap_err();
+        assert!(err.starts_with("Line 3:"), "{}", err);
+        assert!(parse_gpl("GIMP Palette\n256 0 0\n").is_err());
+    }
+
+    #[test]
+    fn parses_csv_palette() {
+        let colors = parse_csv("# red, green, blue\n0, 0, 0\n\n255,255,255\n").unwrap();
+        assert_eq!(colors, [Rgb::BLACK, Rgb::WHITE]);
+        assert!(parse_csv("1,2,3,4\n").is_err());
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+
+use crate::math::{angle_of_reflection, Rgb, Vec3f};
+use crate::scene::{Scene, VisObj, FLOAT_BIAS, MAX_DEPTH};
+
+/// Light from the scene's light sources which has been focused by reflective or
+/// transparent objects onto other surfaces (ie, caustics). Ordinary ray tracing
+/// can't find this light, since shadow rays travel in a straight line to the
+/// light, so it is estimated in a pre-pass by following "photons" from the
+/// lights and recording where they land in a grid.
+pub struct CausticMap {
+    cell_size: f32,
+    /// Total power of the photons which landed in each grid cell, per channel
+    cells: HashMap<[i32; 3], Rgb>,
+}
+
+impl CausticMap {
+    /// Shoots "photons_per_object" photons from each light source at each
+    /// reflective or transparent object in the scene, and records where they land
+    /// after bouncing off or passing through it. Objects which can't be sampled,
+    /// such as infinite planes, are skipped.
+    pub fn build(scene: &Scene, photons_per_object: usize, cell_size: f32) -> CausticMap {
+        let mut caustics = CausticMap {
+            cell_size,
+            cells: HashMap::new(),
+        };
+
+        for light_source in &scene.light_sources {
+            for vobj in &scene.objects {
+                if vobj.reflectivity == 0.0 && vobj.transparency == 0.0 {
+                    continue;
+                }
+
+                for _ in 0..photons_per_object {
+                    let sample = match vobj.surface.sample_point() {
+                        Some(sample) => sample,
+                        None => break,
+                    };
+
+                    // Each photon carries a share of the light falling on the lit
+                    // side of the object, in proportion to how brightly lit its
+                    // sample point is.
+                    let (dir_to_light, light_distance) =
+                        light_source.direction_from(&sample.position);
+                    let photon_dir = dir_to_light.scale(-1.0);
+                    let cos_angle = sample.normal.dot(&dir_to_light);
+                    if cos_angle <= 0.0 {
+                        continue;
+                    }
+                    let lit_from = sample.position.add(&sample.normal.scale(FLOAT_BIAS));
+                    if !light_source.shines_through_portal(&lit_from, &dir_to_light) {
+                        continue;
+                    }
+                    if scene
+                        .trace_to_nearest_object_within(&lit_from, &dir_to_light, light_distance)
+                        .is_some()
+                    {
+                        continue;
+                    }
+
+                    let power = light_source.color.scale(
+                        light_source.intensity_at(&dir_to_light, light_distance) * cos_angle
+                            / (sample.pdf * (photons_per_object as f32)),
+                    );
+                    caustics.scatter_photon(
+                        scene,
+                        vobj,
+                        &sample.position,
+                        &sample.normal,
+                        &photon_dir,
+                        true,
+                        power,
+                        MAX_DEPTH - 1,
+                    );
+                }
+            }
+        }
+
+        caustics
+    }
+
+    /// Follows a photon until it hits a surface, stores it there and then scatters
+    /// it further if the surface is reflective or transparent.
+    fn trace_photon(
+        &mut self,
+        scene: &Scene,
+        origin: &Vec3f,
+        direction: &Vec3f,
+        power: Rgb,
+        max_depth: i32,
+    ) {
+        if max_depth == 0 {
+            return;
+        }
+
+        let (index, dist) = match scene.trace_to_nearest_object(origin, direction) {
+            Some(hit) => hit,
+            None => return,
+        };
+        let vobj = &scene.objects[index];
+        let position = origin.add(&direction.scale(dist));
+        let mut normal = vobj.surface.at_point(&position).normal;
+        let entering = normal.dot(direction) <= 0.0;
+        if !entering {
+            normal = normal.scale(-1.0);
+        }
+
+        let cell = self
+            .cells
+            .entry(self.cell_of(&position))
+            .or_insert(Rgb::BLACK);
+        *cell = cell.add(&power);
+
+        self.scatter_photon(
+            scene, vobj, &position, &normal, direction, entering, power, max_depth,
+        );
+    }
+
+    /// Sends the reflected and transmitted parts of a photon which arrived at
+    /// "position" on "vobj" on to the next surfaces they hit. This mirrors the way
+    /// Scene::shade_hit follows reflected and transmitted rays.
+    #[allow(clippy::too_many_arguments)]
+    fn scatter_photon(
+        &mut self,
+        scene: &Scene,
+        vobj: &VisObj,
+        position: &Vec3f,
+        normal: &Vec3f,
+        direction: &Vec3f,
+        entering: bool,
+        power: Rgb,
+        max_depth: i32,
+    ) {
+        if vobj.reflectivity != 0.0 {
+            let reflect_dir = angle_of_reflection(direction, normal);
+            let reflect_origin = position.add(&normal.scale(FLOAT_BIAS));
+            self.trace_photon(
+                scene,
+                &reflect_origin,
+                &reflect_dir,
+                power.scale(vobj.reflectivity),
+                max_depth - 1,
+            );
+        }
+
+        if vobj.transparency != 0.0 {
+            let (continue_origin, continue_direction) =
+                vobj.transmitted_ray(position, normal, direction, entering);
+            self.trace_photon(
+                scene,
+                &continue_origin,
+                &continue_direction,
+                power.scale(vobj.transparency),
+                max_depth - 1,
+            );
+        }
+    }
+
+    fn cell_of(&self, position: &Vec3f) -> [i32; 3] {
+        [
+            (position.x / self.cell_size).floor() as i32,
+            (position.y / self.cell_size).floor() as i32,
+            (position.z / self.cell_size).floor() as i32,
+        ]
+    }
+
+    /// Estimates the focused light arriving at a point on a
+    /// surface, by averaging over the 3x3x3 block of cells around it. The photons
+    /// are assumed to lie on a surface cutting through the block, which has an
+    /// area of roughly 3x3 cells.
+    pub fn light_at(&self, position: &Vec3f) -> Rgb {
+        let [x, y, z] = self.cell_of(position);
+        let mut power = Rgb::BLACK;
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    if let Some(cell) = self.cells.get(&[x + dx, y + dy, z + dz]) {
+                        power = power.add(cell);
+                    }
+                }
+            }
+        }
+
+        power.scale(1.0 / (9.0 * self.cell_size * self.cell_size))
+    }
+}
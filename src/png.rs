@@ -0,0 +1,107 @@
+/// Encodes an RGB image as a PNG file. "pixel_data" holds the rows one after
+/// another, with 8 bit samples, or with 16 bit samples most significant byte
+/// first if "bit_depth" is 16, as produced for a PPM file.
+///
+/// The image data is stored without compression, which keeps this small and
+/// dependency free. That makes the files as large as a PPM, but they can be
+/// shown directly by browsers and other tools which don't read PPM.
+pub fn encode(width: usize, height: usize, bit_depth: u32, pixel_data: &[u8]) -> Vec<u8> {
+    let row_length = pixel_data.len() / height.max(1);
+
+    let mut png = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+    let mut header = Vec::new();
+    header.extend((width as u32).to_be_bytes());
+    header.extend((height as u32).to_be_bytes());
+    // Bit depth, then color type 2 (RGB), deflate compression, adaptive filtering
+    // and no interlacing
+    header.extend([bit_depth as u8, 2, 0, 0, 0]);
+    write_chunk(&mut png, b"IHDR", &header);
+
+    // Each row starts with its filter type, 0 for none
+    let mut scanlines = Vec::with_capacity(pixel_data.len() + height);
+    for row in pixel_data.chunks(row_length.max(1)) {
+        scanlines.push(0);
+        scanlines.extend_from_slice(row);
+    }
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&scanlines));
+
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+    png.extend(chunk_type);
+    png.extend(data);
+    let crc = crc32(chunk_type.iter().chain(data));
+    png.extend(crc.to_be_bytes());
+}
+
+/// Wraps "data" in a zlib stream made of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // Deflate with a 32K window, no preset dictionary, and the check bits which
+    // make the header a multiple of 31
+    let mut stream = vec![0x78, 0x01];
+
+    let mut blocks = data.chunks(u16::MAX as usize).peekable();
+    if blocks.peek().is_none() {
+        // Even an empty stream needs a final block
+        stream.extend([1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let is_final = blocks.peek().is_none();
+        let length = block.len() as u16;
+        stream.push(is_final as u8);
+        stream.extend(length.to_le_bytes());
+        stream.extend((!length).to_le_bytes());
+        stream.extend(block);
+    }
+
+    stream.extend(adler32(data).to_be_bytes());
+    stream
+}
+
+fn crc32<'a>(bytes: impl Iterator<Item = &'a u8>) -> u32 {
+    let crc = bytes.fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ (byte as u32), |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    });
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MODULUS: u32 = 65521;
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % MODULUS;
+        (a, (b + a) % MODULUS)
+    });
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums_match_known_values() {
+        // Every PNG ends with this IEND chunk checksum
+        assert_eq!(crc32(b"IEND".iter()), 0xae42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn large_images_are_split_into_several_blocks() {
+        let data = vec![7u8; 100_000];
+        let stream = zlib_stored(&data);
+        // Header, two block headers, the data and the checksum
+        assert_eq!(stream.len(), 2 + 5 + 5 + data.len() + 4);
+        assert_eq!(stream[2], 0);
+        assert_eq!(stream[2 + 5 + u16::MAX as usize], 1);
+    }
+}
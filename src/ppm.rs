@@ -1,27 +1,210 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::io;
 use std::io::Write;
 
+use crate::math::Rgb;
+use crate::util::Array2D;
+
+/// Returns the header of a binary PPM file, which should be followed directly by
+/// the pixel data
+pub fn header(width: i32, height: i32, max_value: u16) -> String {
+    // The trailing space is important, there should only be a single whitespace
+    // between the header and the binary image data
+    format!("P6\n{} {}\n{} ", width, height, max_value)
+}
+
 pub struct PPMWriter {
     file_handle: io::BufWriter<File>,
 }
 
 impl PPMWriter {
-    pub fn new(output_filename: &str, width: i32, height: i32) -> io::Result<PPMWriter> {
+    /// Creates the file and writes the header. A max_value of 255 gives one byte
+    /// per sample, anything larger (up to 65535) gives two bytes per sample, most
+    /// significant byte first.
+    pub fn new(
+        output_filename: &str,
+        width: i32,
+        height: i32,
+        max_value: u16,
+    ) -> io::Result<PPMWriter> {
         let f = File::create(output_filename)?;
         let mut buffered = io::BufWriter::new(f);
-        let max_value = 255;
 
-        // The trailing space is important, there should only be a single whitespace
-        // between the header and the binary image data
-        write!(&mut buffered, "P6\n{} {}\n{} ", width, height, max_value)?;
+        write!(&mut buffered, "{}", header(width, height, max_value))?;
 
         Ok(PPMWriter {
             file_handle: buffered,
         })
     }
 
-    pub fn write(&mut self, red: u8, green: u8, blue: u8) -> io::Result<()> {
-        self.file_handle.write_all(&[red, green, blue])
+    /// Write any number of pixels at once, as consecutive (red, green, blue) samples
+    /// in the format given by the max_value passed to new()
+    pub fn write_rows(&mut self, pixel_data: &[u8]) -> io::Result<()> {
+        self.file_handle.write_all(pixel_data)
+    }
+
+    /// Flush any buffered data out to the file. Errors that occur while flushing
+    /// would be silently ignored if the PPMWriter were simply dropped, so this
+    /// should always be called once all pixels have been written.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.file_handle.flush()
+    }
+}
+
+/// Writes a binary PGM file, the single channel (grayscale) counterpart of a PPM
+/// file, eg for an alpha mask. There is one byte per sample, in row order.
+pub fn write_pgm(output_filename: &str, width: i32, height: i32, samples: &[u8]) -> io::Result<()> {
+    let f = File::create(output_filename)?;
+    let mut buffered = io::BufWriter::new(f);
+
+    // As with header(), a single whitespace must separate the header and the data
+    write!(&mut buffered, "P5\n{} {}\n255 ", width, height)?;
+    buffered.write_all(samples)?;
+    buffered.flush()
+}
+
+/// Reads a binary (P6) PPM file into an image of linear colors, see parse_ppm()
+pub fn read_ppm(filename: &str) -> io::Result<Array2D<Rgb>> {
+    let data = fs::read(filename)?;
+
+    parse_ppm(&data).map_err(|msg| io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+/// Parses the contents of a binary (P6) PPM file, with one or two bytes per
+/// sample, into an image of linear colors. The samples are assumed to be sRGB
+/// encoded, as in the files that PPMWriter writes.
+pub fn parse_ppm(data: &[u8]) -> Result<Array2D<Rgb>, String> {
+    // Reads the next whitespace separated header field, skipping comments
+    fn next_field<'a>(data: &'a [u8], pos: &mut usize) -> Result<&'a [u8], String> {
+        loop {
+            match data.get(*pos) {
+                Some(b'#') => {
+                    while data.get(*pos).is_some_and(|&c| c != b'\n') {
+                        *pos += 1;
+                    }
+                }
+                Some(c) if c.is_ascii_whitespace() => *pos += 1,
+                Some(_) => break,
+                None => return Err(String::from("Unexpected end of header")),
+            }
+        }
+        let start = *pos;
+        while data.get(*pos).is_some_and(|c| !c.is_ascii_whitespace()) {
+            *pos += 1;
+        }
+        Ok(&data[start..*pos])
+    }
+
+    let mut pos = 0;
+    if next_field(data, &mut pos)? != b"P6" {
+        return Err(String::from("Not a binary PPM file (expected P6)"));
+    }
+    let mut next_number = |name: &str| -> Result<usize, String> {
+        std::str::from_utf8(next_field(data, &mut pos)?)
+            .ok()
+            .and_then(|field| field.parse().ok())
+            .ok_or(format!("Could not parse {}", name))
+    };
+    let width = next_number("width")?;
+    let height = next_number("height")?;
+    let max_value = next_number("max value")?;
+    if width == 0 || height == 0 {
+        return Err(format!("Image is empty ({}x{} pixels)", width, height));
+    }
+    if !(1..=65535).contains(&max_value) {
+        return Err(format!(
+            "Max value should be 1 to 65535, but is {}",
+            max_value
+        ));
+    }
+
+    // Exactly one whitespace character separates the header from the pixel data
+    let pixel_data = data.get(pos + 1..).unwrap_or(&[]);
+    let bytes_per_sample = if max_value < 256 { 1 } else { 2 };
+    let expected_len = width
+        .checked_mul(height)
+        .and_then(|pixels| pixels.checked_mul(3 * bytes_per_sample))
+        .ok_or(format!("Image is too large ({}x{} pixels)", width, height))?;
+    if pixel_data.len() < expected_len {
+        return Err(format!(
+            "Expected {} bytes of pixel data, but found {}",
+            expected_len,
+            pixel_data.len()
+        ));
+    }
+
+    let samples: Vec<f32> = pixel_data[..expected_len]
+        .chunks_exact(bytes_per_sample)
+        .map(|sample| {
+            let value = sample
+                .iter()
+                .fold(0, |value, &byte| (value << 8) | byte as u32);
+            (value as f32) / (max_value as f32)
+        })
+        .collect();
+    let pixels = samples
+        .chunks_exact(3)
+        .map(|rgb| {
+            Rgb {
+                red: rgb[0],
+                green: rgb[1],
+                blue: rgb[2],
+            }
+            .srgb_to_linear()
+        })
+        .collect();
+
+    Ok(Array2D::from_vec(height, width, pixels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_valid_file() {
+        // 2x1 pixels, white then black, with a comment in the header
+        let mut data = b"P6\n# made by hand\n2 1\n255\n".to_vec();
+        data.extend([255, 255, 255, 0, 0, 0]);
+
+        let image = parse_ppm(&data).unwrap();
+        assert_eq!((image.rows, image.columns), (1, 2));
+        assert_eq!(*image.get(0, 0), Rgb::WHITE);
+        assert_eq!(*image.get(0, 1), Rgb::BLACK);
+    }
+
+    #[test]
+    fn parses_what_the_writer_writes() {
+        let mut data = header(1, 2, 65535).into_bytes();
+        data.extend([0xff, 0xff, 0, 0, 0xff, 0xff, 0, 0, 0, 0, 0, 0]);
+
+        let image = parse_ppm(&data).unwrap();
+        assert_eq!((image.rows, image.columns), (2, 1));
+        assert_eq!(image.get(0, 0).green, 0.0);
+        assert_eq!(image.get(0, 0).blue, 1.0);
+        assert_eq!(*image.get(1, 0), Rgb::BLACK);
+    }
+
+    #[test]
+    fn rejects_a_truncated_file() {
+        let mut data = header(2, 2, 255).into_bytes();
+        data.extend([1; 11]);
+        let err = parse_ppm(&data).err().unwrap();
+        assert!(err.contains("Expected 12 bytes"), "{}", err);
+
+        assert!(parse_ppm(b"P6\n2 2").is_err());
+    }
+
+    #[test]
+    fn rejects_a_bad_header() {
+        for data in [
+            &b"P3\n1 1\n255\n0 0 0"[..],
+            b"P6\n1 x\n255\n\0\0\0",
+            b"P6\n0 1\n255\n",
+            b"P6\n1 1\n70000\n\0\0\0\0\0\0",
+            b"",
+        ] {
+            assert!(parse_ppm(data).is_err(), "{:?} was accepted", data);
+        }
     }
 }
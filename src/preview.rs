@@ -0,0 +1,120 @@
+use minifb::{Key, MouseButton, MouseMode, ScaleMode, Window, WindowOptions};
+
+use crate::math::{ColorSpace, Vec3f};
+use crate::scene::{Camera, Scene};
+
+/// Distance moved per frame while a movement key is held down, in world units
+const MOVE_SPEED: f32 = 0.2;
+/// Angle turned per frame while an arrow key is held down, in radians
+const TURN_SPEED: f32 = 0.04;
+/// Angle turned per pixel the mouse is dragged across the window, in radians
+const MOUSE_TURN_SPEED: f32 = 0.005;
+
+/// Shows the scene in a window, re-rendering whenever the camera moves, until the
+/// window is closed or Escape is pressed. WASD moves the camera, Q and E move it
+/// down and up, and the arrow keys or dragging with the mouse turn it.
+///
+/// The image is traced at width by height, without oversampling, and stretched to
+/// fill the window, so it is quick enough to move around interactively.
+pub fn run(
+    scene: &Scene,
+    camera: Camera,
+    width: usize,
+    height: usize,
+    colorspace: ColorSpace,
+) -> Result<(), String> {
+    let mut window = Window::new(
+        "raymond preview (WASD/QE to move, arrows or drag to turn, Esc to quit)",
+        width.max(640),
+        height * width.max(640) / width,
+        WindowOptions {
+            resize: true,
+            scale_mode: ScaleMode::Stretch,
+            ..WindowOptions::default()
+        },
+    )
+    .map_err(|err| format!("Could not open preview window: {}", err))?;
+    window.set_target_fps(30);
+
+    let mut position = *camera.position();
+    let direction = camera.direction();
+    // Pitch is kept away from straight up and down, where the camera is degenerate
+    let mut yaw = direction.y.atan2(direction.x);
+    let mut pitch = direction.z.asin().clamp(-1.5, 1.5);
+
+    let mut buffer = vec![0u32; width * height];
+    let mut needs_render = true;
+    let mut last_mouse_pos = None;
+
+    while window.is_open() && !window.is_key_down(Key::Escape) {
+        let turns = [
+            (Key::Left, TURN_SPEED, 0.0),
+            (Key::Right, -TURN_SPEED, 0.0),
+            (Key::Up, 0.0, TURN_SPEED),
+            (Key::Down, 0.0, -TURN_SPEED),
+        ];
+        for (key, yaw_change, pitch_change) in turns {
+            if window.is_key_down(key) {
+                yaw += yaw_change;
+                pitch += pitch_change;
+                needs_render = true;
+            }
+        }
+
+        let mouse_pos = window.get_mouse_pos(MouseMode::Pass);
+        if window.get_mouse_down(MouseButton::Left) {
+            if let (Some((x0, y0)), Some((x1, y1))) = (last_mouse_pos, mouse_pos) {
+                if (x0, y0) != (x1, y1) {
+                    yaw -= (x1 - x0) * MOUSE_TURN_SPEED;
+                    pitch -= (y1 - y0) * MOUSE_TURN_SPEED;
+                    needs_render = true;
+                }
+            }
+        }
+        last_mouse_pos = mouse_pos;
+        pitch = pitch.clamp(-1.5, 1.5);
+
+        // Movement is relative to the direction the camera is facing, but kept
+        // level so that looking down doesn't make "forward" go into the floor
+        let forward = Vec3f {
+            x: yaw.cos(),
+            y: yaw.sin(),
+            z: 0.0,
+        };
+        let right = forward.cross(&Vec3f::UP);
+        let moves = [
+            (Key::W, forward),
+            (Key::S, forward.scale(-1.0)),
+            (Key::D, right),
+            (Key::A, right.scale(-1.0)),
+            (Key::E, Vec3f::UP),
+            (Key::Q, Vec3f::UP.scale(-1.0)),
+        ];
+        for (key, movement) in moves {
+            if window.is_key_down(key) {
+                position = position.add(&movement.scale(MOVE_SPEED));
+                needs_render = true;
+            }
+        }
+
+        if needs_render {
+            let direction = Vec3f {
+                x: yaw.cos() * pitch.cos(),
+                y: yaw.sin() * pitch.cos(),
+                z: pitch.sin(),
+            };
+            let image = scene.trace_image(&camera.moved_to(position, direction), width, height);
+            for (out_pixel, in_pixel) in buffer.iter_mut().zip(image.iter_rows().flatten()) {
+                let (red, green, blue) = colorspace.encode_rgb24(in_pixel);
+                *out_pixel = ((red as u32) << 16) | ((green as u32) << 8) | (blue as u32);
+            }
+            needs_render = false;
+        }
+
+        window
+            .update_with_buffer(&buffer, width, height)
+            .map_err(|err| format!("Could not update preview window: {}", err))?;
+    }
+
+    Ok(())
+}
@@ -1,31 +1,514 @@
-use crate::math::{angle_of_reflection, convolve_2d, gaussian_kernel, Rgb, Vec3f};
-use crate::surface::Surface;
-use crate::texture::Texture;
-use crate::util::{run_parallel_jobs, Array2D};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::bvh::Bvh;
+use crate::differential::RayDifferential;
+use crate::emitter::EmitterDistribution;
+use crate::fog::FogVolume;
+use crate::material::Material;
+use crate::math::{
+    angle_of_reflection, convolution_input_length, convolve_2d, gaussian_kernel, orthonormal_basis,
+    soften_terminator, Rgb, Vec3f,
+};
+use crate::photon::CausticMap;
+use crate::sky::Environment;
+use crate::stats::{self, RayKind};
+use crate::surface::{Quad, Surface};
+use crate::texture::{Texture, TexturePoint};
+use crate::util::{
+    counter_rand_f32, rand_f32, rand_in_cone, rand_in_unit_disk, rand_u32, run_parallel_jobs,
+    Array2D,
+};
 
 // If we try to trace from the exact position on a surface, sometimes we will
 // detect the object that we are on due to floating point rounding issues.
 // Therefore, we add a slight bias in the direction of the surface normal to
 // avoid this.
-const FLOAT_BIAS: f32 = 0.001;
+pub const FLOAT_BIAS: f32 = 0.001;
+
+// Shadow rays leaving a surface at a grazing angle stay close to it for longer,
+// so they get a larger bias, up to this multiple of FLOAT_BIAS.
+const MAX_SHADOW_BIAS_SCALE: f32 = 10.0;
+
+// Maximum number of times a ray can recurse (via reflections, portals, etc.)
+pub const MAX_DEPTH: i32 = 10;
+
+// Number of output rows traced at once by trace_rows(). More rows give the
+// threads more work to share, at the cost of memory.
+const STREAMED_ROWS_PER_BAND: usize = 32;
+
+// Number of samples every pixel gets with adaptive sampling, before its noise is
+// estimated. With fewer, the variance estimate is too unreliable to stop on.
+const ADAPTIVE_MIN_SAMPLES: usize = 8;
+
+// Number of reflection rays cast from a rough surface hit by a primary ray. This
+// halves with each further bounce, since blurry reflections of reflections
+// contribute little to the image.
+const GLOSSY_SAMPLES: usize = 8;
+
+// Number of points on emissive objects which each surface point is lit from.
+// These are shared between all the emitters, with brighter ones getting more.
+const EMITTER_SAMPLES: usize = 4;
+
+// Reflections off a rough surface recurse at most GLOSSY_DEPTH_SCALE / roughness
+// more times, so the rougher the surface, the sooner they are cut off.
+const GLOSSY_DEPTH_SCALE: f32 = 0.2;
 
 #[derive(Debug, Copy, Clone)]
 pub struct LightSource {
-    pub dir_to_light: Vec3f,
+    pub kind: LightKind,
+    /// Brightness of the light. For lights which fall off with distance, this is
+    /// the brightness at a distance of 1.0.
     pub intensity: f32,
+    /// Color of the light, which tints everything it falls on. This multiplies
+    /// the intensity, so it's usually kept to a maximum of 1.0 per channel.
+    pub color: Rgb,
+    /// Angular radius of the light as seen from the scene, in radians. (The sun is
+    /// roughly 0.0047.) Non-zero values give shadows soft edges.
+    pub angular_radius: f32,
+    /// Number of shadow rays used to estimate how much of a light with a non-zero
+    /// angular radius (or of a disk light) is visible. More samples give smoother
+    /// soft shadows, but take proportionally longer.
+    pub shadow_samples: usize,
+    /// Whether objects block this light. Turning this off is useful for fill
+    /// lights, which should brighten the scene without adding extra shadows.
+    pub casts_shadows: bool,
+    /// If present, the light only reaches points from which it shines through the
+    /// portal, eg the sunlight coming in through a window in an interior scene
+    pub portal: Option<LightPortal>,
+}
+
+/// Where a light source is, which determines the direction its light arrives
+/// from and how it falls off with distance
+#[derive(Debug, Copy, Clone)]
+pub enum LightKind {
+    /// Infinitely far away, like the sun, so its light arrives in parallel from
+    /// the direction "dir_to_light" and is equally bright everywhere
+    Directional { dir_to_light: Vec3f },
+    /// A point (or small sphere) at "position", like a lamp, whose light falls off
+    /// with the square of the distance from it
+    Point { position: Vec3f },
+    /// A disk of the given radius centered on "position", which always faces the
+    /// point being lit, so that it looks like a glowing sphere. Its light falls
+    /// off like a point light's, but it casts soft shadows, with penumbras which
+    /// widen as the disk gets larger or the occluder gets further from the
+    /// shadowed surface.
+    Disk { position: Vec3f, radius: f32 },
+    /// A point light at "position" which only shines within a cone around
+    /// "direction", like a stage light. Within "inner_angle" of the direction (in
+    /// radians) it is at full intensity, beyond "outer_angle" it gives no light,
+    /// and in between it fades out smoothly, giving the lit area a soft edge.
+    Spot {
+        position: Vec3f,
+        direction: Vec3f,
+        inner_angle: f32,
+        outer_angle: f32,
+    },
+    /// A glowing rectangle, like a window or a ceiling panel, which shines from
+    /// the side its normal faces. Its light falls off like a point light's from
+    /// its center, and is dimmer when the rectangle is seen at an angle. It casts
+    /// soft shadows, whose shadow rays are spread evenly over the solid angle the
+    /// rectangle covers, so they stay smooth even where it is seen edge on.
+    Rect { quad: Quad },
+}
+
+impl LightSource {
+    /// Creates a white light which casts hard shadows
+    pub fn new(kind: LightKind, intensity: f32) -> LightSource {
+        LightSource {
+            kind,
+            intensity,
+            color: Rgb::WHITE,
+            angular_radius: 0.0,
+            shadow_samples: 1,
+            casts_shadows: true,
+            portal: None,
+        }
+    }
+
+    /// Returns the unit vector from "position" towards the light, and the
+    /// distance to the light (infinite for directional lights)
+    pub fn direction_from(&self, position: &Vec3f) -> (Vec3f, f32) {
+        match self.kind {
+            LightKind::Directional { dir_to_light } => (dir_to_light.normalize(), f32::INFINITY),
+            LightKind::Point {
+                position: light_position,
+            }
+            | LightKind::Disk {
+                position: light_position,
+                ..
+            }
+            | LightKind::Spot {
+                position: light_position,
+                ..
+            } => {
+                let offset = light_position - *position;
+                let distance = offset.length();
+                (offset.scale(1.0 / distance), distance)
+            }
+            LightKind::Rect { quad } => {
+                let offset = quad.center() - *position;
+                let distance = offset.length();
+                (offset.scale(1.0 / distance), distance)
+            }
+        }
+    }
+
+    /// Returns the intensity of the light arriving at a point, given the direction
+    /// and distance to the light from there (as returned by direction_from())
+    pub fn intensity_at(&self, dir_to_light: &Vec3f, distance: f32) -> f32 {
+        match self.kind {
+            LightKind::Directional { .. } => self.intensity,
+            LightKind::Point { .. } | LightKind::Disk { .. } => {
+                self.intensity / (distance * distance)
+            }
+            LightKind::Spot {
+                direction,
+                inner_angle,
+                outer_angle,
+                ..
+            } => {
+                let cos_angle = -dir_to_light.dot(&direction.normalize());
+                let (cos_inner, cos_outer) = (inner_angle.cos(), outer_angle.cos());
+                let cone_factor = if cos_angle >= cos_inner {
+                    1.0
+                } else if cos_angle <= cos_outer {
+                    0.0
+                } else {
+                    let t = (cos_angle - cos_outer) / (cos_inner - cos_outer);
+                    t * t * (3.0 - 2.0 * t) // smoothstep
+                };
+
+                cone_factor * self.intensity / (distance * distance)
+            }
+            LightKind::Rect { quad } => {
+                let cos_at_light = -dir_to_light.dot(&quad.normal());
+                cos_at_light.max(0.0) * self.intensity / (distance * distance)
+            }
+        }
+    }
+
+    /// Returns true if the light has a size, so that its shadows have soft edges
+    /// and need several shadow rays
+    pub fn has_soft_shadows(&self) -> bool {
+        match self.kind {
+            LightKind::Disk { radius, .. } => radius > 0.0,
+            LightKind::Rect { .. } => true,
+            _ => self.angular_radius > 0.0,
+        }
+    }
+
+    /// Returns the direction of, and distance along, the shadow ray from
+    /// "trace_pos" to a random point on the light. "dir_to_light" and
+    /// "light_distance" are as returned by direction_from(). For lights with soft
+    /// shadows, the points are jittered within "samples" equal sectors of the
+    /// light, and "sample" selects the sector, except for rectangular lights,
+    /// whose points are spread over the solid angle they cover instead.
+    pub fn shadow_ray(
+        &self,
+        trace_pos: &Vec3f,
+        dir_to_light: &Vec3f,
+        light_distance: f32,
+        sample: usize,
+        samples: usize,
+    ) -> (Vec3f, f32) {
+        match self.kind {
+            LightKind::Disk { position, radius } if radius > 0.0 => {
+                let angle =
+                    2.0 * std::f32::consts::PI * ((sample as f32) + rand_f32()) / (samples as f32);
+                let distance_from_center = radius * rand_f32().sqrt();
+                let (tangent, bitangent) = orthonormal_basis(dir_to_light);
+                let light_point = position
+                    + tangent * (distance_from_center * angle.cos())
+                    + bitangent * (distance_from_center * angle.sin());
+
+                let offset = light_point - *trace_pos;
+                let distance = offset.length();
+                (offset.scale(1.0 / distance), distance)
+            }
+            LightKind::Rect { quad } => match quad.sample_visible_point(trace_pos) {
+                Some(light_sample) => {
+                    let offset = light_sample.position - *trace_pos;
+                    let distance = offset.length();
+                    (offset.scale(1.0 / distance), distance)
+                }
+                // In the plane of the light, so it gives no light anyway
+                None => (*dir_to_light, light_distance),
+            },
+            _ if self.angular_radius > 0.0 => (
+                rand_in_cone(dir_to_light, self.angular_radius),
+                light_distance,
+            ),
+            _ => (*dir_to_light, light_distance),
+        }
+    }
+
+    /// Returns true if light arriving at "position" from "dir_to_light" came
+    /// through the light's portal (or if it doesn't have one)
+    pub fn shines_through_portal(&self, position: &Vec3f, dir_to_light: &Vec3f) -> bool {
+        self.portal
+            .is_none_or(|portal| portal.is_crossed_by(position, dir_to_light))
+    }
+}
+
+/// Parallelogram (usually a rectangle) which a light source shines through
+#[derive(Debug, Copy, Clone)]
+pub struct LightPortal {
+    corner: Vec3f,
+    edge1: Vec3f,
+    edge2: Vec3f,
+}
+
+impl LightPortal {
+    /// Creates a portal with one corner at "corner", and sides running along
+    /// "edge1" and "edge2" from there
+    pub fn new(corner: Vec3f, edge1: Vec3f, edge2: Vec3f) -> LightPortal {
+        LightPortal {
+            corner,
+            edge1,
+            edge2,
+        }
+    }
+
+    /// Returns true if a ray from "origin" heading in "direction" passes through
+    /// the portal
+    pub fn is_crossed_by(&self, origin: &Vec3f, direction: &Vec3f) -> bool {
+        let normal = self.edge1.cross(&self.edge2);
+        let denominator = direction.dot(&normal);
+        if denominator == 0.0 {
+            return false;
+        }
+
+        let dist = self.corner.sub(origin).dot(&normal) / denominator;
+        if dist <= 0.0 {
+            return false;
+        }
+
+        let offset = *origin + *direction * dist - self.corner;
+        let a = offset.dot(&self.edge1) / self.edge1.dot(&self.edge1);
+        let b = offset.dot(&self.edge2) / self.edge2.dot(&self.edge2);
+
+        (0.0..=1.0).contains(&a) && (0.0..=1.0).contains(&b)
+    }
 }
 
 pub struct VisObj {
     pub surface: Box<dyn Surface>,
     pub texture: Box<dyn Texture>,
+    /// Fraction of light reflected like a mirror. The reflection is added on top of
+    /// the lit texture color, rather than replacing part of it, so a clean mirror
+    /// should use a black texture.
     pub reflectivity: f32,
+    /// Half angle (in radians) of the cone that reflections are blurred over. 0.0
+    /// gives a perfect mirror.
+    pub roughness: f32,
+    /// Brightness of the highlights where light sources glint off the surface
+    /// (Blinn-Phong specular reflection). Unlike "reflectivity", this shows the
+    /// light sources themselves rather than other objects. 0.0 disables it.
+    pub specular_intensity: f32,
+    /// Blinn-Phong exponent, which controls how small and sharp the highlights
+    /// are: higher is shinier
+    pub shininess: f32,
+    /// Fraction of light which passes through the surface, showing whatever is
+    /// behind it
+    pub transparency: f32,
+    /// Ratio of the speed of light outside the object to inside it, which sets how
+    /// much light passing through the surface is bent, eg 1.5 for glass. 1.0 means
+    /// it carries on straight. Only meaningful for closed surfaces, since the
+    /// direction of the normal is taken to show which side is inside.
+    pub index_of_refraction: f32,
+    /// Scales the scene's ambient light for this object, eg to make an object look
+    /// darker or brighter in the shadows
+    pub ambient_multiplier: f32,
+    /// Light given off by the object itself, eg for a screen showing an image. This
+    /// is added to the color of the object regardless of the lighting, but doesn't
+    /// light up other objects.
+    pub emission_texture: Option<Box<dyn Texture>>,
+    /// Blend the object into the background with distance from the ray origin, eg
+    /// so an infinite floor dissolves before it starts to shimmer at the horizon
+    pub fade: Option<DistanceFade>,
+    /// Optional name used to pick out the object, eg for debugging
+    pub tag: Option<String>,
+}
+
+/// Range of distances over which an object fades into the background
+#[derive(Debug, Copy, Clone)]
+pub struct DistanceFade {
+    /// Distance at which the object starts to fade
+    pub start: f32,
+    /// Distance beyond which only the background is visible
+    pub end: f32,
+}
+
+impl DistanceFade {
+    /// Returns how much of the background shows through at the given distance,
+    /// from 0.0 (none) to 1.0 (all), following a smoothstep curve
+    pub fn background_fraction(&self, distance: f32) -> f32 {
+        let t = ((distance - self.start) / (self.end - self.start)).clamp(0.0, 1.0);
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
+impl VisObj {
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tag.as_deref() == Some(tag)
+    }
+
+    /// Replaces the object's surface properties with those of the material
+    pub fn set_material(&mut self, material: &Material) {
+        self.reflectivity = material.reflectivity;
+        self.roughness = material.roughness;
+        self.specular_intensity = material.specular_intensity;
+        self.shininess = material.shininess;
+        self.transparency = material.transparency;
+        self.index_of_refraction = material.index_of_refraction;
+        self.ambient_multiplier = material.ambient_multiplier;
+    }
+
+    /// Sets one of the object's numeric properties by name, eg from the command
+    /// line. Returns an error for unknown (or non-numeric) properties.
+    pub fn set_property(&mut self, property: &str, value: f32) -> Result<(), String> {
+        match property {
+            "reflectivity" => self.reflectivity = value,
+            "roughness" => self.roughness = value,
+            "specular_intensity" => self.specular_intensity = value,
+            "shininess" => self.shininess = value,
+            "transparency" => self.transparency = value,
+            "index_of_refraction" => self.index_of_refraction = value,
+            "ambient_multiplier" => self.ambient_multiplier = value,
+            _ => return Err(format!("Unknown object property \"{}\"", property)),
+        }
+        Ok(())
+    }
+
+    /// Finds the ray which carries on through the object's surface from
+    /// "position", for a ray arriving in "direction" (a unit vector), returning
+    /// its origin and direction. "normal" must face against the arriving ray, and
+    /// "entering" says whether the ray is going into the object. Light which
+    /// can't leave the object because of total internal reflection is reflected
+    /// back inside instead.
+    pub fn transmitted_ray(
+        &self,
+        position: &Vec3f,
+        normal: &Vec3f,
+        direction: &Vec3f,
+        entering: bool,
+    ) -> (Vec3f, Vec3f) {
+        if self.index_of_refraction == 1.0 {
+            return (*position + *direction * FLOAT_BIAS, *direction);
+        }
+
+        let eta = if entering {
+            1.0 / self.index_of_refraction
+        } else {
+            self.index_of_refraction
+        };
+        match direction.refract(normal, eta) {
+            Some(refracted) => (*position - *normal * FLOAT_BIAS, refracted),
+            None => (*position + *normal * FLOAT_BIAS, direction.reflect(normal)),
+        }
+    }
 }
 
 pub struct Scene {
     pub background: Rgb,
-    pub ambient_light_intensity: f32,
+    /// If present, gives the color of rays which miss every object, in place of
+    /// the flat background color
+    pub environment: Option<Box<dyn Environment>>,
+    /// Light which falls equally on every surface, regardless of shadows
+    pub ambient_light: Rgb,
+    /// Width of the band (in cosine of the angle to the light) over which direct
+    /// light fades out before the terminator, see soften_terminator(). 0.0 gives
+    /// the usual hard terminator.
+    pub terminator_softening: f32,
+    /// Number of bounces before MAX_DEPTH over which rays fade to black, so that
+    /// long chains of reflections (eg between mirrors) darken gradually instead of
+    /// ending in a flash of background color. 0 disables the fade.
+    pub depth_fade_bounces: i32,
     pub light_sources: Vec<LightSource>,
     pub objects: Vec<VisObj>,
+    /// Hierarchy of bounding boxes over the objects, to speed up finding which
+    /// one a ray hits. This must be rebuilt with build_bvh() after changing the
+    /// objects, until then rays are tested against every object.
+    pub bvh: Option<Bvh>,
+    pub fog_volumes: Vec<FogVolume>,
+    /// Light focused onto surfaces by reflective and transparent objects, if it
+    /// has been computed
+    pub caustics: Option<CausticMap>,
+    /// Objects with an emission texture, for picking which of them to light
+    /// surfaces from, if they have been found. Like bvh, this must be rebuilt
+    /// after changing the objects.
+    pub emitters: Option<EmitterDistribution>,
+    /// Time at which the scene is rendered, in seconds, for animated textures
+    pub time: f32,
+    /// If present, called as each pass over the image finishes rows, see
+    /// ProgressCallback. Edge antialiasing makes two passes, each of which counts
+    /// up to the total, and trace_rows() doesn't report progress.
+    pub progress: Option<ProgressCallback>,
+}
+
+/// Called with the number of rows of an image traced so far and the total number
+/// of rows. Each call is made on whichever worker thread finished the row, so
+/// calls can overlap and may arrive slightly out of order. To keep the workers
+/// from waiting on it, it is only called when another whole percent of the rows
+/// is done.
+pub type ProgressCallback = Box<dyn Fn(usize, usize) + Send + Sync>;
+
+/// Shape of a camera's aperture, which determines the shape that out of focus
+/// highlights (bokeh) take on
+#[derive(Debug, Copy, Clone)]
+pub enum ApertureShape {
+    Circle,
+    /// Regular polygon with this many sides, like the opening formed by the blades
+    /// of a lens diaphragm, rotated by "rotation" radians
+    Polygon {
+        sides: u32,
+        rotation: f32,
+    },
+}
+
+impl ApertureShape {
+    /// Returns a random point uniformly distributed over the aperture, which has a
+    /// radius of 1.0 (ie, the corners of a polygon lie on the unit circle)
+    pub fn sample_point(&self) -> (f32, f32) {
+        match *self {
+            ApertureShape::Circle => rand_in_unit_disk(),
+            ApertureShape::Polygon { sides, rotation } => {
+                // The polygon is a fan of identical triangles around the center, so
+                // pick one of them, and then a uniform point within it
+                let sides = sides.max(3);
+                let wedge = 2.0 * std::f32::consts::PI / (sides as f32);
+                let start_angle = rotation + wedge * ((rand_u32() % sides) as f32);
+                let corner1 = (start_angle.cos(), start_angle.sin());
+                let corner2 = ((start_angle + wedge).cos(), (start_angle + wedge).sin());
+
+                let (mut a, mut b) = (rand_f32(), rand_f32());
+                if a + b > 1.0 {
+                    a = 1.0 - a;
+                    b = 1.0 - b;
+                }
+                (a * corner1.0 + b * corner2.0, a * corner1.1 + b * corner2.1)
+            }
+        }
+    }
+
+    /// Returns true if the point lies within the aperture
+    #[cfg(test)]
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        match *self {
+            ApertureShape::Circle => x * x + y * y <= 1.0,
+            ApertureShape::Polygon { sides, rotation } => {
+                let sides = sides.max(3);
+                let wedge = 2.0 * std::f32::consts::PI / (sides as f32);
+                // Distance from the center to the middle of each edge
+                let apothem = (wedge / 2.0).cos();
+                (0..sides).all(|i| {
+                    let edge_angle = rotation + wedge * ((i as f32) + 0.5);
+                    x * edge_angle.cos() + y * edge_angle.sin() <= apothem + 1e-6
+                })
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -34,48 +517,255 @@ pub struct Camera {
     direction: Vec3f,
     delta_x: Vec3f,
     delta_y: Vec3f,
+    shift_x: f32,
+    shift_y: f32,
+    /// Radius of the lens, 0.0 for a pinhole camera which has everything in focus
+    aperture: f32,
+    aperture_shape: ApertureShape,
+    /// Distance along the view direction to the plane which is in sharp focus
+    focus_distance: f32,
+    /// Whether all rays are parallel to the view direction, starting from points
+    /// spread over the image plane, instead of fanning out from the position
+    orthographic: bool,
 }
 
 impl Camera {
     pub fn new(position: Vec3f, direction: Vec3f, fov_degrees: f32) -> Camera {
-        // TODO: Using cross products like this to means that the camera can't point
-        // straight up or straight down, because otherwise the cross with Vec3f::UP
-        // yields the zero vector, and then normalizing results in NaNs.
+        Camera::oriented(position, direction, &Vec3f::UP, fov_degrees)
+    }
 
+    /// Camera at "position" pointing towards "target", rolled so that "up" points
+    /// upwards in the image (as far as possible, it needn't be at right angles to
+    /// the view direction)
+    pub fn look_at(position: Vec3f, target: Vec3f, up: Vec3f, fov_degrees: f32) -> Camera {
+        Camera::oriented(position, (target - position).normalize(), &up, fov_degrees)
+    }
+
+    fn oriented(position: Vec3f, direction: Vec3f, up: &Vec3f, fov_degrees: f32) -> Camera {
         let fov_radians = fov_degrees * ((2.0 * std::f32::consts::PI) / 360.0);
         let fov_scale = (fov_radians / 2.0).tan();
+        let (delta_x, delta_y) = image_plane_axes(&direction, up, fov_scale, fov_scale);
 
-        let delta_x = direction.cross(&Vec3f::UP).normalize().scale(fov_scale);
+        Camera {
+            position,
+            direction: direction.normalize(),
+            delta_x,
+            delta_y,
+            shift_x: 0.0,
+            shift_y: 0.0,
+            aperture: 0.0,
+            aperture_shape: ApertureShape::Circle,
+            focus_distance: 1.0,
+            orthographic: false,
+        }
+    }
 
-        let delta_y = direction.cross(&delta_x).normalize().scale(fov_scale);
+    /// Shift the image plane relative to the direction the camera is pointing, like
+    /// a perspective control lens. This allows, for example, the top of a tall
+    /// building to be brought into view while keeping the camera level so that
+    /// vertical lines stay parallel. Shifts are in units of half the image width,
+    /// with positive values moving the view right and down.
+    pub fn with_lens_shift(self, shift_x: f32, shift_y: f32) -> Camera {
+        Camera {
+            shift_x,
+            shift_y,
+            ..self
+        }
+    }
+
+    /// Switch the camera to a parallel (orthographic) projection, in which objects
+    /// appear the same size however far away they are. The image spans
+    /// "view_width" world units across, centered on the camera position.
+    pub fn with_orthographic_projection(self, view_width: f32) -> Camera {
+        Camera {
+            delta_x: self.delta_x.normalize().scale(view_width / 2.0),
+            delta_y: self.delta_y.normalize().scale(view_width / 2.0),
+            orthographic: true,
+            ..self
+        }
+    }
+
+    /// Give the camera a lens of the given radius (in world units), focused on the
+    /// plane "focus_distance" in front of it, so that objects nearer or further
+    /// away are blurred. Larger apertures give more blur.
+    pub fn with_depth_of_field(self, aperture: f32, focus_distance: f32) -> Camera {
+        Camera {
+            aperture,
+            focus_distance,
+            ..self
+        }
+    }
+
+    /// Change the shape of the lens opening from a circle, eg to a polygon so that
+    /// out of focus highlights look like those from a camera with a diaphragm.
+    /// The aperture given to with_depth_of_field() is then the radius of the
+    /// polygon's corners.
+    pub fn with_aperture_shape(self, aperture_shape: ApertureShape) -> Camera {
+        Camera {
+            aperture_shape,
+            ..self
+        }
+    }
+
+    /// Adjust the camera for pixels which are not square. The ratio is the width
+    /// of a pixel divided by its height, so for example a ratio of 2.0 means each
+    /// pixel covers half as much of the scene vertically as it would otherwise,
+    /// which looks right once the pixels are displayed twice as wide as tall.
+    pub fn with_pixel_aspect_ratio(self, ratio: f32) -> Camera {
+        Camera {
+            delta_y: self.delta_y.scale(1.0 / ratio),
+            ..self
+        }
+    }
+
+    /// Returns a copy of the camera moved to a new position and pointing in a new
+    /// direction, keeping its field of view and other settings
+    #[cfg_attr(not(feature = "preview"), allow(dead_code))]
+    pub fn moved_to(&self, position: Vec3f, direction: Vec3f) -> Camera {
+        let (delta_x, delta_y) = image_plane_axes(
+            &direction,
+            &Vec3f::UP,
+            self.delta_x.length(),
+            self.delta_y.length(),
+        );
 
         Camera {
             position,
             direction: direction.normalize(),
             delta_x,
             delta_y,
+            ..self.clone()
         }
     }
 
-    pub fn ray_origin(&self) -> &Vec3f {
+    /// Unit vector in the direction the camera is pointing
+    pub fn direction(&self) -> &Vec3f {
+        &self.direction
+    }
+
+    #[cfg_attr(not(feature = "preview"), allow(dead_code))]
+    pub fn position(&self) -> &Vec3f {
         &self.position
     }
 
+    /// Starting point of the ray through (x, y) on the image plane, which is the
+    /// camera position unless the camera is orthographic
+    pub fn ray_origin(&self, x: f32, y: f32) -> Vec3f {
+        if self.orthographic {
+            self.position + self.delta_x * (x + self.shift_x) + self.delta_y * (y + self.shift_y)
+        } else {
+            self.position
+        }
+    }
+
+    /// Returns the origin and direction of a ray through the camera's lens which
+    /// passes through the same point on the plane of focus as the ray from
+    /// "origin" in "direction" (eg from ray_origin() and ray_direction()). The
+    /// point on the lens is random, so averaging many rays blurs whatever is out
+    /// of focus. For a pinhole camera, this is just "origin" and "direction".
+    pub fn lens_ray(&self, origin: &Vec3f, direction: &Vec3f) -> (Vec3f, Vec3f) {
+        if self.aperture == 0.0 {
+            return (*origin, *direction);
+        }
+
+        let focus_point =
+            *origin + *direction * (self.focus_distance / direction.dot(&self.direction));
+        let (lens_x, lens_y) = self.aperture_shape.sample_point();
+        let origin = *origin
+            + self.delta_x.normalize() * (lens_x * self.aperture)
+            + self.delta_y.normalize() * (lens_y * self.aperture);
+
+        (origin, (focus_point - origin).normalize())
+    }
+
+    /// Unit vector in the direction of the ray through (x, y) on the image plane.
+    /// Rays are normalized so that distances along them are in world units.
     pub fn ray_direction(&self, x: f32, y: f32) -> Vec3f {
-        self.direction
-            .add(&self.delta_x.scale(x))
-            .add(&self.delta_y.scale(y))
+        if self.orthographic {
+            return self.direction;
+        }
+
+        (self.direction + self.delta_x * (x + self.shift_x) + self.delta_y * (y + self.shift_y))
+            .normalize()
     }
 }
 
-impl Scene {
-    pub fn trace_image(&self, camera: &Camera, width: usize, height: usize) -> Array2D<Rgb> {
-        let mut image = Array2D::new(height, width, &Rgb::BLACK);
+/// Returns the vectors along which the x and y coordinates of a camera's image
+/// plane run, with the given lengths, for a camera pointing in "direction". x runs
+/// to the right and y downwards, as seen with "up" pointing up.
+fn image_plane_axes(direction: &Vec3f, up: &Vec3f, x_length: f32, y_length: f32) -> (Vec3f, Vec3f) {
+    // Looking straight along "up" (eg straight down at the floor), "up" can't say
+    // which way is right, and the cross product would be the zero vector, which
+    // normalizes to NaNs. Roll the camera so that a world axis at a large angle to
+    // the direction is up instead.
+    let mut right = direction.cross(up);
+    if right.length_squared() <= 1e-10 * direction.length_squared() * up.length_squared() {
+        let axis =
+            if direction.x.abs() >= direction.y.abs() && direction.x.abs() >= direction.z.abs() {
+                Vec3f {
+                    x: 0.0,
+                    y: 1.0,
+                    z: 0.0,
+                }
+            } else {
+                Vec3f {
+                    x: 1.0,
+                    y: 0.0,
+                    z: 0.0,
+                }
+            };
+        right = direction.cross(&axis);
+    }
+
+    let delta_x = right.normalize().scale(x_length);
+    let delta_y = direction.cross(&delta_x).normalize().scale(y_length);
 
-        let largest_dimension = width.max(height) as f32;
-        let x_offset = (width as f32) / 2.0;
-        let y_offset = (height as f32) / 2.0;
-        let camera_scale = 2.0 / largest_dimension;
+    (delta_x, delta_y)
+}
+
+/// Maps pixel coordinates of an image onto the (x, y) coordinates accepted by
+/// Camera::ray_direction(). The camera's field of view spans the width of the
+/// image, and the vertical extent follows from the image's aspect ratio. Whole
+/// numbered pixel coordinates refer to the middle of the pixel, so pixel (x, y)
+/// covers from (x - 0.5, y - 0.5) to (x + 0.5, y + 0.5).
+#[derive(Debug, Copy, Clone)]
+struct ImagePlane {
+    x_offset: f32,
+    y_offset: f32,
+    scale: f32,
+}
+
+impl ImagePlane {
+    fn new(width: usize, height: usize) -> ImagePlane {
+        ImagePlane {
+            x_offset: (width as f32) / 2.0,
+            y_offset: (height as f32) / 2.0,
+            scale: 2.0 / (width as f32),
+        }
+    }
+
+    fn camera_coords(&self, x: f32, y: f32) -> (f32, f32) {
+        (
+            (x + 0.5 - self.x_offset) * self.scale,
+            (y + 0.5 - self.y_offset) * self.scale,
+        )
+    }
+}
+
+impl Scene {
+    /// Runs "trace_pixel" for every (x, y) pixel coordinate of a width by height
+    /// image in parallel, and collects the results into an Array2D. Progress is
+    /// reported to "progress", if given, as rows are finished.
+    fn trace_pixels<T: Clone + Send>(
+        width: usize,
+        height: usize,
+        fill_element: &T,
+        progress: Option<&ProgressCallback>,
+        trace_pixel: impl Fn(usize, usize) -> T + Sync,
+    ) -> Array2D<T> {
+        let mut image = Array2D::new(height, width, fill_element);
+        let trace_pixel = &trace_pixel;
+        let rows_done = &AtomicUsize::new(0);
 
         // Create jobs vector, one job per row in the output image
         let jobs: Vec<_> = image
@@ -84,13 +774,15 @@ impl Scene {
             .map(|(row, y)| {
                 move || {
                     for (pixel, x) in row.iter_mut().zip(0..width) {
-                        let camera_x = ((x as f32) - x_offset) * camera_scale;
-                        let camera_y = ((y as f32) - y_offset) * camera_scale;
-                        *pixel = self.cast(
-                            camera.ray_origin(),
-                            &camera.ray_direction(camera_x, camera_y),
-                            10,
-                        );
+                        *pixel = trace_pixel(x, y);
+                    }
+                    stats::merge_thread_local();
+
+                    if let Some(progress) = progress {
+                        let done = rows_done.fetch_add(1, Ordering::Relaxed) + 1;
+                        if done * 100 / height != (done - 1) * 100 / height {
+                            progress(done, height);
+                        }
                     }
                 }
             })
@@ -102,97 +794,1358 @@ impl Scene {
         image
     }
 
+    /// Casts a primary ray from the camera through the given point on the image
+    /// plane. "fast_light" should be the result of fast_path_light().
+    fn cast_primary(
+        &self,
+        camera: &Camera,
+        image_plane: &ImagePlane,
+        fast_light: Option<&LightSource>,
+        x: f32,
+        y: f32,
+    ) -> Rgb {
+        let (camera_x, camera_y) = image_plane.camera_coords(x, y);
+        let (next_x, next_y) = image_plane.camera_coords(x + 1.0, y + 1.0);
+        let pinhole_origin = camera.ray_origin(camera_x, camera_y);
+        let pinhole_direction = camera.ray_direction(camera_x, camera_y);
+        let (ray_origin, ray_direction) = camera.lens_ray(&pinhole_origin, &pinhole_direction);
+
+        // For perspective cameras, all primary rays start at (or, with depth of
+        // field, near) the camera position, so only the direction changes from
+        // pixel to pixel. For orthographic cameras, it's the other way around.
+        let differential = RayDifferential {
+            origin_dx: camera.ray_origin(next_x, camera_y) - pinhole_origin,
+            origin_dy: camera.ray_origin(camera_x, next_y) - pinhole_origin,
+            direction_dx: camera.ray_direction(next_x, camera_y) - pinhole_direction,
+            direction_dy: camera.ray_direction(camera_x, next_y) - pinhole_direction,
+        };
+
+        stats::record_ray(RayKind::Primary);
+        match fast_light {
+            Some(light_source) => {
+                self.cast_simple(&ray_origin, &ray_direction, &differential, light_source)
+            }
+            None => {
+                self.cast_with_differential(&ray_origin, &ray_direction, &differential, MAX_DEPTH)
+            }
+        }
+    }
+
+    pub fn trace_image(&self, camera: &Camera, width: usize, height: usize) -> Array2D<Rgb> {
+        let image_plane = ImagePlane::new(width, height);
+        let fast_light = self.fast_path_light();
+
+        Self::trace_pixels(
+            width,
+            height,
+            &Rgb::BLACK,
+            self.progress.as_ref(),
+            |x, y| self.cast_primary(camera, &image_plane, fast_light, x as f32, y as f32),
+        )
+    }
+
+    /// Traces an image with antialiasing applied only along the silhouette edges of
+    /// objects. A cheap pre-pass finds which object each pixel sees, and only pixels
+    /// with a neighbor seeing a different object get a samples by samples grid of
+    /// rays, the rest get a single ray.
+    pub fn trace_image_edge_antialiased(
+        &self,
+        camera: &Camera,
+        width: usize,
+        height: usize,
+        samples: usize,
+    ) -> Array2D<Rgb> {
+        let image_plane = ImagePlane::new(width, height);
+        let object_ids = self.trace_object_ids(camera, width, height);
+        let object_ids = &object_ids;
+        let fast_light = self.fast_path_light();
+
+        Self::trace_pixels(
+            width,
+            height,
+            &Rgb::BLACK,
+            self.progress.as_ref(),
+            |x, y| {
+                if !is_edge_pixel(object_ids, x, y) {
+                    return self.cast_primary(camera, &image_plane, fast_light, x as f32, y as f32);
+                }
+
+                // Subsample a grid of points centered on the single sample point
+                let step = 1.0 / (samples as f32);
+                let start = (step - 1.0) / 2.0;
+                (0..samples)
+                    .flat_map(|i| (0..samples).map(move |j| (i, j)))
+                    .map(|(i, j)| {
+                        self.cast_primary(
+                            camera,
+                            &image_plane,
+                            fast_light,
+                            (x as f32) + start + step * (i as f32),
+                            (y as f32) + start + step * (j as f32),
+                        )
+                    })
+                    .fold(Rgb::BLACK, |acc, color| acc.add(&color))
+                    .scale(1.0 / ((samples * samples) as f32))
+            },
+        )
+    }
+
+    /// Traces an image with a varying number of randomly placed samples per pixel.
+    /// Each pixel is sampled until the 95% confidence interval of its luminance is
+    /// narrower than plus or minus "noise_threshold", or until it has had
+    /// max_samples samples, so that flat regions finish quickly while noisy ones
+    /// (eg soft shadows, rough reflections and edges) get more samples. Returns the
+    /// image along with the number of samples each pixel took.
+    ///
+    /// Where the samples land within each pixel is determined by "seed". Using a
+    /// different seed for each frame of an animation keeps the pattern of noise and
+    /// aliasing from staying fixed on the screen, so it averages out instead.
+    pub fn trace_image_adaptive(
+        &self,
+        camera: &Camera,
+        width: usize,
+        height: usize,
+        max_samples: usize,
+        noise_threshold: f32,
+        seed: u32,
+    ) -> (Array2D<Rgb>, Array2D<usize>) {
+        let image_plane = ImagePlane::new(width, height);
+        let fast_light = self.fast_path_light();
+
+        let traced = Self::trace_pixels(
+            width,
+            height,
+            &(Rgb::BLACK, 0),
+            self.progress.as_ref(),
+            |x, y| {
+                // Running mean and variance of the luminance, using Welford's algorithm
+                let mut total = Rgb::BLACK;
+                let mut mean_luminance = 0.0;
+                let mut sum_squared_deviations = 0.0;
+
+                let pixel_index = (y * width + x) as u64;
+                let mut samples = 0;
+                while samples < max_samples.max(1) {
+                    let jitter_x = counter_rand_f32(pixel_index, 2 * samples as u32, seed);
+                    let jitter_y = counter_rand_f32(pixel_index, 2 * samples as u32 + 1, seed);
+                    let color = self.cast_primary(
+                        camera,
+                        &image_plane,
+                        fast_light,
+                        (x as f32) + jitter_x - 0.5,
+                        (y as f32) + jitter_y - 0.5,
+                    );
+                    samples += 1;
+                    total = total.add(&color);
+
+                    let luminance = color.luminance();
+                    let deviation = luminance - mean_luminance;
+                    mean_luminance += deviation / (samples as f32);
+                    sum_squared_deviations += deviation * (luminance - mean_luminance);
+
+                    if samples >= ADAPTIVE_MIN_SAMPLES {
+                        let variance = sum_squared_deviations / ((samples - 1) as f32);
+                        let half_width = 1.96 * (variance / (samples as f32)).sqrt();
+                        if half_width < noise_threshold {
+                            break;
+                        }
+                    }
+                }
+
+                (total.scale(1.0 / (samples as f32)), samples)
+            },
+        );
+
+        let mut image = Array2D::new(height, width, &Rgb::BLACK);
+        let mut sample_counts = Array2D::new(height, width, &0);
+        for y in 0..height {
+            for x in 0..width {
+                let (color, samples) = traced.get(y, x);
+                image.set(y, x, color);
+                sample_counts.set(y, x, samples);
+            }
+        }
+
+        (image, sample_counts)
+    }
+
+    /// Finds the fraction of each pixel covered by objects, for use as an alpha mask
+    /// when compositing the image over something else (ie, the background counts as
+    /// transparent). Each pixel is sampled with an oversampling_x by oversampling_y
+    /// grid of rays. Objects fading into the background become transparent as they
+    /// fade.
+    pub fn trace_coverage(
+        &self,
+        camera: &Camera,
+        width: usize,
+        height: usize,
+        oversampling_x: usize,
+        oversampling_y: usize,
+    ) -> Array2D<f32> {
+        let image_plane = ImagePlane::new(width, height);
+        let (oversampling_x, oversampling_y) = (oversampling_x.max(1), oversampling_y.max(1));
+
+        Self::trace_pixels(width, height, &0.0, self.progress.as_ref(), |x, y| {
+            let total: f32 = (0..oversampling_x)
+                .flat_map(|i| (0..oversampling_y).map(move |j| (i, j)))
+                .map(|(i, j)| {
+                    // Subsample a grid centered on the pixel's usual sample point
+                    let sub_x = (x as f32) + ((i as f32) + 0.5) / (oversampling_x as f32) - 0.5;
+                    let sub_y = (y as f32) + ((j as f32) + 0.5) / (oversampling_y as f32) - 0.5;
+                    let (camera_x, camera_y) = image_plane.camera_coords(sub_x, sub_y);
+
+                    stats::record_ray(RayKind::Primary);
+                    match self.trace_to_nearest_object(
+                        &camera.ray_origin(camera_x, camera_y),
+                        &camera.ray_direction(camera_x, camera_y),
+                    ) {
+                        Some((index, dist)) => match &self.objects[index].fade {
+                            Some(fade) => 1.0 - fade.background_fraction(dist),
+                            None => 1.0,
+                        },
+                        None => 0.0,
+                    }
+                })
+                .sum();
+
+            total / ((oversampling_x * oversampling_y) as f32)
+        })
+    }
+
+    /// Finds the index of the object seen by each pixel of the image, if any
+    fn trace_object_ids(
+        &self,
+        camera: &Camera,
+        width: usize,
+        height: usize,
+    ) -> Array2D<Option<usize>> {
+        let image_plane = ImagePlane::new(width, height);
+
+        Self::trace_pixels(width, height, &None, self.progress.as_ref(), |x, y| {
+            let (camera_x, camera_y) = image_plane.camera_coords(x as f32, y as f32);
+
+            stats::record_ray(RayKind::Primary);
+            self.trace_to_nearest_object(
+                &camera.ray_origin(camera_x, camera_y),
+                &camera.ray_direction(camera_x, camera_y),
+            )
+            .map(|(index, _)| index)
+        })
+    }
+
+    /// Traces an image at a higher resolution and then filters it down to the
+    /// requested size with a Gaussian filter. The oversampling factor can differ
+    /// between the two axes, eg to resolve fine horizontal detail in a wide image
+    /// without paying for extra rows. The filter's sigma is "sigma_scale" output
+    /// pixels, smaller values give a sharper image at the expense of more aliasing.
     pub fn trace_image_oversampled(
         &self,
         camera: &Camera,
         width: usize,
         height: usize,
-        oversampling_factor: usize,
+        oversampling_x: usize,
+        oversampling_y: usize,
+        sigma_scale: f32,
     ) -> Array2D<Rgb> {
-        if oversampling_factor > 1 {
-            let sigma = (oversampling_factor as f32) * 0.4;
-            let resampling_kernel = gaussian_kernel(sigma);
-            let extra_points_needed = resampling_kernel.len() - 1;
+        if oversampling_x > 1 || oversampling_y > 1 {
+            let kernel_x = resampling_kernel(oversampling_x, sigma_scale);
+            let kernel_y = resampling_kernel(oversampling_y, sigma_scale);
 
-            let oversampled_width = (width * oversampling_factor) + extra_points_needed;
-            let oversampled_height = (height * oversampling_factor) + extra_points_needed;
+            // Pad the oversampled image so that the convolution, which only
+            // produces outputs where the kernel fits entirely within the input,
+            // gives back exactly the requested dimensions.
+            let oversampled_width = convolution_input_length(width, kernel_x.len(), oversampling_x);
+            let oversampled_height =
+                convolution_input_length(height, kernel_y.len(), oversampling_y);
 
-            let oversampled_image = self.trace_image(camera, oversampled_width, oversampled_height);
+            // The oversampled pixels aren't square unless both factors match
+            let oversampled_camera = camera
+                .clone()
+                .with_pixel_aspect_ratio((oversampling_y as f32) / (oversampling_x as f32));
+            let oversampled_image =
+                self.trace_image(&oversampled_camera, oversampled_width, oversampled_height);
 
-            convolve_2d(&oversampled_image, &resampling_kernel, oversampling_factor)
+            let image = convolve_2d(
+                &oversampled_image,
+                &kernel_x,
+                oversampling_x,
+                &kernel_y,
+                oversampling_y,
+            );
+            assert!(
+                image.columns == width && image.rows == height,
+                "Resampled image is {}x{}, expected {}x{}",
+                image.columns,
+                image.rows,
+                width,
+                height
+            );
+            image
         } else {
             self.trace_image(camera, width, height)
         }
     }
-    fn trace_to_nearest_object(
+
+    /// Traces the same image as trace_image_oversampled(), but returns it one row
+    /// at a time, so that eg each row can be written out and dropped without ever
+    /// holding the whole image in memory. Rows are traced in parallel in bands of
+    /// STREAMED_ROWS_PER_BAND output rows, and only the oversampled rows needed by
+    /// the filter for the current band are kept.
+    pub fn trace_rows<'a>(
+        &'a self,
+        camera: &Camera,
+        width: usize,
+        height: usize,
+        oversampling_x: usize,
+        oversampling_y: usize,
+        sigma_scale: f32,
+    ) -> impl Iterator<Item = Vec<Rgb>> + 'a {
+        let oversampled = oversampling_x > 1 || oversampling_y > 1;
+        let (kernel_x, kernel_y) = if oversampled {
+            (
+                resampling_kernel(oversampling_x, sigma_scale),
+                resampling_kernel(oversampling_y, sigma_scale),
+            )
+        } else {
+            (vec![1.0], vec![1.0])
+        };
+        let oversampled_width = convolution_input_length(width, kernel_x.len(), oversampling_x);
+        let oversampled_height = convolution_input_length(height, kernel_y.len(), oversampling_y);
+        let oversampled_camera = if oversampled {
+            camera
+                .clone()
+                .with_pixel_aspect_ratio((oversampling_y as f32) / (oversampling_x as f32))
+        } else {
+            camera.clone()
+        };
+        let image_plane = ImagePlane::new(oversampled_width, oversampled_height);
+        let fast_light = self.fast_path_light();
+
+        // Oversampled rows from window_start onwards, which overlap between bands
+        // when the filter kernel is longer than the oversampling factor
+        let mut window: Vec<Rgb> = Vec::new();
+        let mut window_start = 0;
+        let mut finished_rows: VecDeque<Vec<Rgb>> = VecDeque::new();
+        let mut next_row = 0;
+
+        std::iter::from_fn(move || {
+            if finished_rows.is_empty() && next_row < height {
+                let band_end = (next_row + STREAMED_ROWS_PER_BAND).min(height);
+                let needed_start = next_row * oversampling_y;
+                let needed_end = needed_start
+                    + convolution_input_length(band_end - next_row, kernel_y.len(), oversampling_y);
+
+                // Drop the rows that are no longer needed, and trace the new ones
+                let window_end = window_start + window.len() / oversampled_width;
+                window.drain(..(needed_start - window_start) * oversampled_width);
+                window_start = needed_start;
+                let new_rows = Self::trace_pixels(
+                    oversampled_width,
+                    needed_end - window_end,
+                    &Rgb::BLACK,
+                    None,
+                    |x, y| {
+                        self.cast_primary(
+                            &oversampled_camera,
+                            &image_plane,
+                            fast_light,
+                            x as f32,
+                            (window_end + y) as f32,
+                        )
+                    },
+                );
+                window.extend(new_rows.iter_rows().flatten());
+
+                // The window is lent to the convolution and then taken back, rather
+                // than copied, since it can be large
+                let window_image = Array2D::from_vec(
+                    needed_end - needed_start,
+                    oversampled_width,
+                    std::mem::take(&mut window),
+                );
+                if oversampled {
+                    let band = convolve_2d(
+                        &window_image,
+                        &kernel_x,
+                        oversampling_x,
+                        &kernel_y,
+                        oversampling_y,
+                    );
+                    finished_rows.extend(band.iter_rows().map(|row| row.to_vec()));
+                } else {
+                    finished_rows.extend(window_image.iter_rows().map(|row| row.to_vec()));
+                }
+                window = window_image.into_vec();
+                next_row = band_end;
+            }
+
+            finished_rows.pop_front()
+        })
+    }
+
+    /// Builds the bounding volume hierarchy over the scene's objects, see bvh
+    pub fn build_bvh(&mut self) {
+        let bounds: Vec<_> = self
+            .objects
+            .iter()
+            .map(|vobj| vobj.surface.bounding_box())
+            .collect();
+        self.bvh = Some(Bvh::build(&bounds));
+    }
+
+    /// Finds the nearest object hit by the ray, returning its index in the objects
+    /// vector and the distance to it.
+    pub fn trace_to_nearest_object(
         &self,
         ray_origin: &Vec3f,
         ray_direction: &Vec3f,
-    ) -> Option<(&VisObj, f32)> {
+    ) -> Option<(usize, f32)> {
+        self.trace_to_nearest_object_within(ray_origin, ray_direction, f32::INFINITY)
+    }
+
+    /// Like trace_to_nearest_object(), but ignores objects "max_distance" or more
+    /// along the ray, eg those beyond a light when checking whether it is blocked
+    pub fn trace_to_nearest_object_within(
+        &self,
+        ray_origin: &Vec3f,
+        ray_direction: &Vec3f,
+        max_distance: f32,
+    ) -> Option<(usize, f32)> {
+        if let Some(bvh) = self
+            .bvh
+            .as_ref()
+            .filter(|bvh| bvh.len() == self.objects.len())
+        {
+            return bvh.nearest(ray_origin, ray_direction, max_distance, |index| {
+                self.objects[index]
+                    .surface
+                    .intersection_with_ray(ray_origin, ray_direction)
+                    .filter(|dist| !dist.is_nan())
+            });
+        }
+
         self.objects
             .iter()
-            // Get a list of intersecting spheres with their distances as a 2-tuple
-            .filter_map(|vobj| {
+            .enumerate()
+            // Get a list of intersecting spheres with their distances as a 2-tuple,
+            // ignoring any bogus NaN distances so a single misbehaving surface can't
+            // bring down the whole render, and any beyond the maximum distance.
+            .filter_map(|(index, vobj)| {
                 vobj.surface
                     .intersection_with_ray(ray_origin, ray_direction)
-                    .map(|dist| (vobj, dist))
+                    .filter(|dist| !dist.is_nan() && *dist < max_distance)
+                    .map(|dist| (index, dist))
+            })
+            // Select (index, distance) 2-tuple with the minimum distance. On exact
+            // ties, min_by() keeps the first, so the earliest object in the scene wins.
+            .min_by(|(_, d1), (_, d2)| d1.total_cmp(d2))
+    }
+
+    /// Returns the fraction of the light source which is visible from trace_pos,
+    /// ie, 0.0 if it is entirely in shadow, and 1.0 if there is nothing in the way
+    /// (or the light doesn't cast shadows). Light which doesn't come through the
+    /// light's portal counts as blocked. Only objects closer than "light_distance"
+    /// can block the light, not those beyond it.
+    fn light_visibility(
+        &self,
+        trace_pos: &Vec3f,
+        dir_to_light: &Vec3f,
+        light_distance: f32,
+        light_source: &LightSource,
+    ) -> f32 {
+        if !light_source.casts_shadows && light_source.portal.is_none() {
+            return 1.0;
+        }
+
+        let samples = if light_source.has_soft_shadows() {
+            light_source.shadow_samples.max(1)
+        } else {
+            1
+        };
+
+        let unobstructed = (0..samples)
+            .filter(|&sample| {
+                // Aim each shadow ray at a random point on the light's disk (or
+                // rectangle), so the fraction that get through approximates how
+                // much is visible.
+                let (shadow_ray, shadow_distance) = light_source.shadow_ray(
+                    trace_pos,
+                    dir_to_light,
+                    light_distance,
+                    sample,
+                    samples,
+                );
+
+                // Rays which miss the portal are in shadow without tracing them
+                if !light_source.shines_through_portal(trace_pos, &shadow_ray) {
+                    return false;
+                }
+                if !light_source.casts_shadows {
+                    return true;
+                }
+
+                stats::record_ray(RayKind::Shadow);
+                self.trace_to_nearest_object_within(trace_pos, &shadow_ray, shadow_distance)
+                    .is_none()
             })
-            // Select (vobj, distance) 2-tuple with the minimum distance
-            .min_by(|(_, d1), (_, d2)| d1.partial_cmp(d2).unwrap())
+            .count();
+
+        (unobstructed as f32) / (samples as f32)
     }
 
-    fn light_on_surface(&self, surface_position: &Vec3f, surface_normal: &Vec3f) -> f32 {
-        let trace_pos = surface_position.add(&surface_normal.scale(FLOAT_BIAS));
+    /// Finds the light arriving from the light sources and emissive objects at a
+    /// point on a surface, either directly or focused by other objects. (ie,
+    /// excluding ambient light)
+    ///
+    /// Also returns the light in the specular highlight for a surface with the
+    /// given shininess seen from "view_direction" (a unit vector from the viewer
+    /// towards the surface), or black if shininess is None.
+    fn light_on_surface(
+        &self,
+        surface_position: &Vec3f,
+        surface_normal: &Vec3f,
+        view_direction: &Vec3f,
+        shininess: Option<f32>,
+        max_depth: i32,
+    ) -> (Rgb, Rgb) {
+        let caustic_light = self
+            .caustics
+            .as_ref()
+            .map_or(Rgb::BLACK, |caustics| caustics.light_at(surface_position));
+        let emitted_light = self.emitters.as_ref().map_or(Rgb::BLACK, |emitters| {
+            self.light_from_emitters(surface_position, surface_normal, emitters, max_depth)
+        });
 
-        let lambert_light_intensity: f32 = self
+        let (direct_light, specular_light) = self
             .light_sources
             .iter()
             .map(|light_source| {
-                match self.trace_to_nearest_object(&trace_pos, &light_source.dir_to_light) {
-                    Some(_) => 0.0, // something is in the way
-                    None => {
-                        // There is a path to the light, apply it
-                        light_source
-                            .dir_to_light
-                            .normalize()
-                            .dot(surface_normal)
-                            .max(0.0)
-                            * light_source.intensity
-                    }
+                self.light_from_source(
+                    surface_position,
+                    surface_normal,
+                    view_direction,
+                    shininess,
+                    light_source,
+                )
+            })
+            .fold((Rgb::BLACK, Rgb::BLACK), |(diffuse, specular), (d, s)| {
+                (diffuse.add(&d), specular.add(&s))
+            });
+
+        (
+            direct_light.add(&caustic_light).add(&emitted_light),
+            specular_light,
+        )
+    }
+
+    /// Estimates the light arriving directly at a point on a surface from the
+    /// scene's emissive objects, by sampling points on emitters picked in
+    /// proportion to their power. Like ambient light, this is scaled so that an
+    /// emitter filling the whole hemisphere gives as much light as its emission.
+    fn light_from_emitters(
+        &self,
+        surface_position: &Vec3f,
+        surface_normal: &Vec3f,
+        emitters: &EmitterDistribution,
+        max_depth: i32,
+    ) -> Rgb {
+        let trace_pos = *surface_position + *surface_normal * FLOAT_BIAS;
+        let total = (0..EMITTER_SAMPLES)
+            .filter_map(|_| {
+                let (object_index, probability) = emitters.sample()?;
+                let vobj = &self.objects[object_index];
+                let emission_texture = vobj.emission_texture.as_ref()?;
+                let sample = vobj.surface.sample_point()?;
+
+                let offset = sample.position - trace_pos;
+                let distance = offset.length();
+                let dir_to_emitter = offset.scale(1.0 / distance);
+                let cos_at_surface = dir_to_emitter.dot(surface_normal);
+                // Emitters glow on both sides, like they look when seen directly
+                let cos_at_emitter = dir_to_emitter.dot(&sample.normal).abs();
+                if cos_at_surface <= 0.0 || cos_at_emitter == 0.0 {
+                    return None;
+                }
+
+                // Stop short of the emitter, so that it doesn't shadow itself
+                stats::record_ray(RayKind::Shadow);
+                if self
+                    .trace_to_nearest_object_within(
+                        &trace_pos,
+                        &dir_to_emitter,
+                        distance - FLOAT_BIAS,
+                    )
+                    .is_some()
+                {
+                    return None;
                 }
+
+                let surf_prop = vobj.surface.at_point(&sample.position);
+                let texture_point = TexturePoint {
+                    u: surf_prop.u,
+                    v: surf_prop.v,
+                    position: sample.position,
+                    footprint: 0.0,
+                };
+                let emission = emission_texture.color(self, max_depth - 1, &texture_point);
+
+                // Convert from sampling by area to light per unit solid angle
+                let weight = cos_at_surface * cos_at_emitter
+                    / (std::f32::consts::PI * distance * distance * sample.pdf * probability);
+                Some(emission.scale(weight))
+            })
+            .fold(Rgb::BLACK, |total, light| total.add(&light));
+
+        total.scale(1.0 / (EMITTER_SAMPLES as f32))
+    }
+
+    /// Finds the light arriving directly from one light source at a point on a
+    /// surface, and the light in its specular highlight, see light_on_surface()
+    fn light_from_source(
+        &self,
+        surface_position: &Vec3f,
+        surface_normal: &Vec3f,
+        view_direction: &Vec3f,
+        shininess: Option<f32>,
+        light_source: &LightSource,
+    ) -> (Rgb, Rgb) {
+        let (dir_to_light, light_distance) = light_source.direction_from(surface_position);
+        let cos_angle = dir_to_light.dot(surface_normal);
+
+        // The bias grows as 1/cos(angle), which keeps the distance between the
+        // shadow ray and the surface roughly constant.
+        let bias_scale = (1.0 / cos_angle.abs()).min(MAX_SHADOW_BIAS_SCALE);
+        let trace_pos = *surface_position + *surface_normal * (FLOAT_BIAS * bias_scale);
+        let visibility =
+            self.light_visibility(&trace_pos, &dir_to_light, light_distance, light_source);
+        let light = light_source
+            .color
+            .scale(light_source.intensity_at(&dir_to_light, light_distance) * visibility);
+
+        // Blinn-Phong: the highlight is brightest where the normal lies halfway
+        // between the directions to the light and to the viewer
+        let specular = match shininess {
+            Some(shininess) if cos_angle > 0.0 && visibility > 0.0 => {
+                let half_vector = dir_to_light.sub(view_direction).normalize();
+                light.scale(half_vector.dot(surface_normal).max(0.0).powf(shininess))
+            }
+            _ => Rgb::BLACK,
+        };
+
+        (
+            light.scale(soften_terminator(cos_angle, self.terminator_softening)),
+            specular,
+        )
+    }
+
+    /// Returns the scene's only light source if the scene is simple enough for
+    /// cast_simple(): a single light, and nothing which would make a ray hitting
+    /// an object do more than light its texture (ie, no reflective, transparent,
+    /// emissive or fading objects, fog or caustics).
+    fn fast_path_light(&self) -> Option<&LightSource> {
+        let simple_objects = self.objects.iter().all(|vobj| {
+            vobj.reflectivity == 0.0
+                && vobj.specular_intensity == 0.0
+                && vobj.transparency == 0.0
+                && vobj.emission_texture.is_none()
+                && vobj.fade.is_none()
+        });
+
+        match self.light_sources.as_slice() {
+            [light_source]
+                if simple_objects && self.fog_volumes.is_empty() && self.caustics.is_none() =>
+            {
+                Some(light_source)
+            }
+            _ => None,
+        }
+    }
+
+    /// Streamlined version of cast_with_differential() for primary rays in scenes
+    /// accepted by fast_path_light(). It does the same calculations in the same
+    /// order, so that the image doesn't depend on which is used, which means a
+    /// change to the lighting in one has to be made in the other as well.
+    fn cast_simple(
+        &self,
+        ray_origin: &Vec3f,
+        ray_direction: &Vec3f,
+        differential: &RayDifferential,
+        light_source: &LightSource,
+    ) -> Rgb {
+        stats::record_bounce(0);
+
+        let (index, dist) = match self.trace_to_nearest_object(ray_origin, ray_direction) {
+            Some(hit) => hit,
+            None => return self.background_color(ray_direction),
+        };
+        let vobj = &self.objects[index];
+
+        let intersection_pos = *ray_origin + *ray_direction * dist;
+        let mut surf_prop = vobj.surface.at_point(&intersection_pos);
+        if surf_prop.normal.dot(ray_direction) > 0.0 {
+            surf_prop.normal = -surf_prop.normal;
+        }
+        let hit_differential = differential.transfer(ray_direction, dist, &surf_prop.normal);
+        let (direct_light, _) = self.light_from_source(
+            &intersection_pos,
+            &surf_prop.normal,
+            ray_direction,
+            None,
+            light_source,
+        );
+        let light = self
+            .ambient_light
+            .scale(vobj.ambient_multiplier)
+            .add(&direct_light);
+        let texture_point = TexturePoint {
+            u: surf_prop.u,
+            v: surf_prop.v,
+            position: intersection_pos,
+            footprint: hit_differential.footprint(),
+        };
+
+        vobj.texture
+            .color(self, MAX_DEPTH, &texture_point)
+            .mul(&light)
+    }
+
+    /// Attenuates "color", which was found "distance" along the ray, by the fog
+    /// between it and the ray origin, and adds the light scattered towards the ray
+    /// origin by the fog.
+    fn apply_fog(
+        &self,
+        fog: &FogVolume,
+        ray_origin: &Vec3f,
+        ray_direction: &Vec3f,
+        distance: f32,
+        color: &Rgb,
+    ) -> Rgb {
+        let (t_near, t_far) = match fog.overlap(ray_origin, ray_direction, distance) {
+            Some(overlap) => overlap,
+            None => return *color,
+        };
+        let fog_length = t_far - t_near;
+        let step = fog_length / (fog.samples.max(1) as f32);
+
+        // Estimate the in-scattered light by sampling at random points within
+        // evenly sized segments of the ray.
+        let in_scattered = (0..fog.samples.max(1))
+            .map(|i| {
+                let t = t_near + step * ((i as f32) + rand_f32());
+                let sample_pos = *ray_origin + *ray_direction * t;
+
+                let light = self
+                    .light_sources
+                    .iter()
+                    .map(|light_source| {
+                        let (dir_to_light, light_distance) =
+                            light_source.direction_from(&sample_pos);
+                        let visibility = self.light_visibility(
+                            &sample_pos,
+                            &dir_to_light,
+                            light_distance,
+                            light_source,
+                        );
+
+                        light_source.color.scale(
+                            light_source.intensity_at(&dir_to_light, light_distance)
+                                * visibility
+                                * fog.phase(ray_direction.dot(&dir_to_light)),
+                        )
+                    })
+                    .fold(Rgb::BLACK, |total, light| total.add(&light));
+
+                light.scale(fog.scattering * fog.transmittance(t - t_near) * step)
             })
-            .sum();
+            .fold(Rgb::BLACK, |total, light| total.add(&light));
 
-        self.ambient_light_intensity + lambert_light_intensity
+        color
+            .scale(fog.transmittance(fog_length))
+            .add(&in_scattered)
     }
 
+    /// Finds the color of the surface of "vobj", which the ray hit at "dist".
+    fn shade_hit(
+        &self,
+        vobj: &VisObj,
+        ray_origin: &Vec3f,
+        ray_direction: &Vec3f,
+        differential: &RayDifferential,
+        dist: f32,
+        max_depth: i32,
+    ) -> Rgb {
+        let intersection_pos = *ray_origin + *ray_direction * dist;
+        let mut surf_prop = vobj.surface.at_point(&intersection_pos);
+        // Surfaces are double sided, so when hitting the back of a plane or quad
+        // (or the inside of a sphere), shade it as if the normal faced the ray.
+        let entering = surf_prop.normal.dot(ray_direction) <= 0.0;
+        if !entering {
+            surf_prop.normal = -surf_prop.normal;
+        }
+        let hit_differential = differential.transfer(ray_direction, dist, &surf_prop.normal);
+        let shininess = if vobj.specular_intensity != 0.0 {
+            Some(vobj.shininess)
+        } else {
+            None
+        };
+        let (direct_light, specular_light) = self.light_on_surface(
+            &intersection_pos,
+            &surf_prop.normal,
+            ray_direction,
+            shininess,
+            max_depth,
+        );
+        let light = self
+            .ambient_light
+            .scale(vobj.ambient_multiplier)
+            .add(&direct_light);
+        let texture_point = TexturePoint {
+            u: surf_prop.u,
+            v: surf_prop.v,
+            position: intersection_pos,
+            footprint: hit_differential.footprint(),
+        };
+        let vobj_color = vobj.texture.color(self, max_depth, &texture_point);
+
+        let reflected_color = if vobj.reflectivity != 0.0 {
+            let reflect_ray = angle_of_reflection(ray_direction, &surf_prop.normal);
+            let reflect_origin = intersection_pos + surf_prop.normal * FLOAT_BIAS;
+            let reflect_differential = hit_differential.reflect(&surf_prop.normal);
+
+            if vobj.roughness > 0.0 {
+                let samples = glossy_sample_count(max_depth);
+                let reflect_depth = (max_depth - 1).min(glossy_depth_limit(vobj.roughness));
+                let mut total = Rgb::BLACK;
+                for _ in 0..samples {
+                    // Rays blurred below the surface are folded back to the mirror
+                    // direction, rather than passing into the object
+                    let mut glossy_ray = rand_in_cone(&reflect_ray, vobj.roughness);
+                    if glossy_ray.dot(&surf_prop.normal) <= 0.0 {
+                        glossy_ray = reflect_ray;
+                    }
+
+                    stats::record_ray(RayKind::Secondary);
+                    total = total.add(&self.cast_with_differential(
+                        &reflect_origin,
+                        &glossy_ray,
+                        &reflect_differential,
+                        reflect_depth,
+                    ));
+                }
+                total.scale(vobj.reflectivity / (samples as f32))
+            } else {
+                stats::record_ray(RayKind::Secondary);
+                self.cast_with_differential(
+                    &reflect_origin,
+                    &reflect_ray,
+                    &reflect_differential,
+                    max_depth - 1,
+                )
+                .scale(vobj.reflectivity)
+            }
+        } else {
+            Rgb::BLACK
+        };
+
+        // For transparent objects, carry on through the surface (bending the ray
+        // if the object refracts), which finds the next surface behind this one.
+        // Stacks of transparent objects are handled by the recursion. The ray
+        // differential isn't bent along with the ray, which only affects how
+        // blurry textures seen through the object are.
+        let transmitted_color = if vobj.transparency != 0.0 {
+            let (continue_origin, continue_direction) = vobj.transmitted_ray(
+                &intersection_pos,
+                &surf_prop.normal,
+                ray_direction,
+                entering,
+            );
+
+            stats::record_ray(RayKind::Secondary);
+            self.cast_with_differential(
+                &continue_origin,
+                &continue_direction,
+                &hit_differential,
+                max_depth - 1,
+            )
+            .scale(vobj.transparency)
+        } else {
+            Rgb::BLACK
+        };
+
+        // All compositing happens in linear space. Only the texture color is lit by
+        // the light sources: the reflected and transmitted colors were already lit
+        // where their rays landed, and aren't tinted by the texture, so a black
+        // mirror shows exactly "reflectivity" times whatever it reflects.
+        let surface_color = vobj_color.mul(&light.scale(1.0 - vobj.transparency));
+
+        let emitted_color = match &vobj.emission_texture {
+            Some(emission_texture) => emission_texture.color(self, max_depth, &texture_point),
+            None => Rgb::BLACK,
+        };
+
+        // Highlights are the color of the light, not of the surface
+        let specular_color = specular_light.scale(vobj.specular_intensity);
+
+        let color = surface_color
+            .add(&specular_color)
+            .add(&emitted_color)
+            .add(&reflected_color)
+            .add(&transmitted_color);
+
+        // Fading to the background in the direction of the ray makes the object
+        // blend in seamlessly with the background just above it
+        match &vobj.fade {
+            Some(fade) => {
+                let background_fraction = fade.background_fraction(dist);
+                color.scale(1.0 - background_fraction).add(
+                    &self
+                        .background_color(ray_direction)
+                        .scale(background_fraction),
+                )
+            }
+            None => color,
+        }
+    }
+
+    /// Checks the scene, and the camera it will be viewed from, for mistakes which
+    /// would otherwise show up as black or garbled areas in the image. Returns a
+    /// description of each problem found.
+    pub fn validate(&self, camera: &Camera) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+
+        for (index, vobj) in self.objects.iter().enumerate() {
+            let name = match &vobj.tag {
+                Some(tag) => format!("Object {} ({})", index, tag),
+                None => format!("Object {}", index),
+            };
+
+            if let Err(msg) = vobj.surface.validate() {
+                problems.push(format!("{}: {}", name, msg));
+            }
+            for (property, value) in [
+                ("reflectivity", vobj.reflectivity),
+                ("transparency", vobj.transparency),
+            ] {
+                if !(0.0..=1.0).contains(&value) {
+                    problems.push(format!(
+                        "{}: {} must be between 0.0 and 1.0, but is {}",
+                        name, property, value
+                    ));
+                }
+            }
+            for (property, value) in [
+                ("specular_intensity", vobj.specular_intensity),
+                ("shininess", vobj.shininess),
+            ] {
+                if !(value.is_finite() && value >= 0.0) {
+                    problems.push(format!(
+                        "{}: {} must not be negative, but is {}",
+                        name, property, value
+                    ));
+                }
+            }
+            if !(vobj.index_of_refraction.is_finite() && vobj.index_of_refraction > 0.0) {
+                problems.push(format!(
+                    "{}: index_of_refraction must be positive, but is {}",
+                    name, vobj.index_of_refraction
+                ));
+            }
+            if let Some(fade) = &vobj.fade {
+                if !(fade.start.is_finite() && fade.end.is_finite() && fade.end > fade.start) {
+                    problems.push(format!(
+                        "{}: fade must end further away than it starts, but runs from {} to {}",
+                        name, fade.start, fade.end
+                    ));
+                }
+            }
+        }
+
+        for (index, light_source) in self.light_sources.iter().enumerate() {
+            match &light_source.kind {
+                LightKind::Directional { dir_to_light: dir } => {
+                    if !dir.is_finite() || dir.dot(dir) == 0.0 {
+                        problems.push(format!(
+                            "Light {}: direction to light is not a valid direction: {:?}",
+                            index, dir
+                        ));
+                    }
+                }
+                LightKind::Point { position } => {
+                    if !position.is_finite() {
+                        problems.push(format!(
+                            "Light {}: position is not finite: {:?}",
+                            index, position
+                        ));
+                    }
+                }
+                LightKind::Disk { position, radius } => {
+                    if !position.is_finite() {
+                        problems.push(format!(
+                            "Light {}: position is not finite: {:?}",
+                            index, position
+                        ));
+                    }
+                    if !(radius.is_finite() && *radius >= 0.0) {
+                        problems.push(format!(
+                            "Light {}: radius should be non-negative, but is {}",
+                            index, radius
+                        ));
+                    }
+                }
+                LightKind::Spot {
+                    position,
+                    direction,
+                    inner_angle,
+                    outer_angle,
+                } => {
+                    if !position.is_finite() {
+                        problems.push(format!(
+                            "Light {}: position is not finite: {:?}",
+                            index, position
+                        ));
+                    }
+                    if !direction.is_finite() || direction.dot(direction) == 0.0 {
+                        problems.push(format!(
+                            "Light {}: spotlight direction is not a valid direction: {:?}",
+                            index, direction
+                        ));
+                    }
+                    if !(0.0 <= *inner_angle
+                        && inner_angle <= outer_angle
+                        && *outer_angle <= std::f32::consts::PI)
+                    {
+                        problems.push(format!(
+                            "Light {}: spotlight cone angles should satisfy 0 <= inner <= outer <= pi, but are {} and {}",
+                            index, inner_angle, outer_angle
+                        ));
+                    }
+                }
+                LightKind::Rect { quad } => {
+                    if let Err(problem) = quad.validate() {
+                        problems.push(format!("Light {}: {}", index, problem));
+                    }
+                }
+            }
+            if !(light_source.intensity.is_finite() && light_source.intensity > 0.0) {
+                problems.push(format!(
+                    "Light {}: intensity should be positive, but is {}",
+                    index, light_source.intensity
+                ));
+            }
+            let color = light_source.color;
+            if ![color.red, color.green, color.blue]
+                .iter()
+                .all(|channel| channel.is_finite() && *channel >= 0.0)
+            {
+                problems.push(format!(
+                    "Light {}: color channels should be finite and non-negative: {:?}",
+                    index, color
+                ));
+            }
+        }
+
+        let camera_vectors = [
+            camera.position,
+            camera.direction,
+            camera.delta_x,
+            camera.delta_y,
+        ];
+        if !camera_vectors.iter().all(|v| v.is_finite()) {
+            problems.push(String::from(
+                "Camera: degenerate orientation (eg a zero direction vector)",
+            ));
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    /// Returns the color seen by a ray which escapes the scene in the given
+    /// direction. The background is treated as an infinitely distant sphere, so
+    /// this depends only on the direction and not on where the ray started.
+    pub fn background_color(&self, ray_direction: &Vec3f) -> Rgb {
+        match &self.environment {
+            Some(environment) => environment.color(ray_direction),
+            None => self.background,
+        }
+    }
+
+    /// Finds the color seen by a ray which isn't associated with a pixel, so
+    /// textures are point sampled
     pub fn cast(&self, ray_origin: &Vec3f, ray_direction: &Vec3f, max_depth: i32) -> Rgb {
+        self.cast_with_differential(ray_origin, ray_direction, &RayDifferential::ZERO, max_depth)
+    }
+
+    /// Finds the color seen by a ray, using its differential to estimate how much
+    /// of each surface it hits is covered by the pixel
+    pub fn cast_with_differential(
+        &self,
+        ray_origin: &Vec3f,
+        ray_direction: &Vec3f,
+        differential: &RayDifferential,
+        max_depth: i32,
+    ) -> Rgb {
+        // Rays which run out of bounces are treated as escaping the scene, so they
+        // see the same sky as any other ray heading in that direction, unless they
+        // are fading to black.
+        let depth_fade_bounces = self.depth_fade_bounces.clamp(0, MAX_DEPTH - 1);
         if max_depth == 0 {
-            return self.background;
+            return if depth_fade_bounces > 0 {
+                Rgb::BLACK
+            } else {
+                self.background_color(ray_direction)
+            };
+        }
+        stats::record_bounce((MAX_DEPTH - max_depth) as u32);
+
+        let hit = self.trace_to_nearest_object(ray_origin, ray_direction);
+        let color = match hit {
+            Some((index, dist)) => self.shade_hit(
+                &self.objects[index],
+                ray_origin,
+                ray_direction,
+                differential,
+                dist,
+                max_depth,
+            ),
+            None => self.background_color(ray_direction),
+        };
+
+        let hit_distance = hit.map_or(f32::INFINITY, |(_, dist)| dist);
+        let color = self.fog_volumes.iter().fold(color, |color, fog| {
+            self.apply_fog(fog, ray_origin, ray_direction, hit_distance, &color)
+        });
+
+        // Each ray in the fade is dimmed by max_depth / (max_depth + 1), which
+        // multiplies out along a chain of bounces to a linear fade, ie the ray
+        // with max_depth bounces left is dimmed by max_depth / (bounces + 1).
+        if max_depth <= depth_fade_bounces {
+            color.scale((max_depth as f32) / ((max_depth + 1) as f32))
+        } else {
+            color
         }
+    }
+}
 
-        match self.trace_to_nearest_object(ray_origin, ray_direction) {
-            Some((vobj, dist)) => {
-                let intersection_pos = ray_origin.add(&ray_direction.scale(dist));
-                let surf_prop = vobj.surface.at_point(&intersection_pos);
-                let light_intensity = self.light_on_surface(&intersection_pos, &surf_prop.normal);
-                let vobj_color = vobj
-                    .texture
-                    .color(self, max_depth, surf_prop.u, surf_prop.v);
+/// Returns the kernel used to filter an axis oversampled by the given factor. An
+/// axis which isn't oversampled is passed through unfiltered.
+fn resampling_kernel(oversampling_factor: usize, sigma_scale: f32) -> Vec<f32> {
+    if oversampling_factor > 1 {
+        gaussian_kernel((oversampling_factor as f32) * sigma_scale)
+    } else {
+        vec![1.0]
+    }
+}
+
+/// Checks whether any of the four neighbors of the pixel see a different object
+fn is_edge_pixel(object_ids: &Array2D<Option<usize>>, x: usize, y: usize) -> bool {
+    let id = object_ids.get(y, x);
+    let neighbors = [
+        (x.wrapping_sub(1), y),
+        (x + 1, y),
+        (x, y.wrapping_sub(1)),
+        (x, y + 1),
+    ];
+
+    neighbors
+        .iter()
+        .filter(|(nx, ny)| *nx < object_ids.columns && *ny < object_ids.rows)
+        .any(|(nx, ny)| object_ids.get(*ny, *nx) != id)
+}
 
-                let reflected_color = if vobj.reflectivity != 0.0 {
-                    let reflect_ray = angle_of_reflection(ray_direction, &surf_prop.normal);
-                    let reflect_origin = intersection_pos.add(&surf_prop.normal.scale(FLOAT_BIAS));
+/// Number of reflection rays to cast from a rough surface, for a ray which can
+/// recurse max_depth more times
+fn glossy_sample_count(max_depth: i32) -> usize {
+    let bounce = (MAX_DEPTH - max_depth).clamp(0, usize::BITS as i32 - 1);
+    (GLOSSY_SAMPLES >> bounce).max(1)
+}
 
-                    self.cast(&reflect_origin, &reflect_ray, max_depth - 1)
-                        .scale(vobj.reflectivity)
+/// Maximum number of times a reflection off a surface with the given (non-zero)
+/// roughness can recurse
+fn glossy_depth_limit(roughness: f32) -> i32 {
+    ((GLOSSY_DEPTH_SCALE / roughness).ceil() as i32).clamp(1, MAX_DEPTH)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene_file::SceneFile;
+    use crate::surface::Plane;
+
+    /// A sphere on a checkerboard floor, lit by one point light, which is simple
+    /// enough for the fast path and doesn't use any random numbers
+    fn simple_scene() -> (Scene, Camera) {
+        let scene_file = SceneFile::parse(
+            r#"{
+                "background": [0.1, 0.2, 0.3],
+                "cameras": [
+                    {"type": "Direction", "position": [0, -6, 2], "direction": [0, 1, -0.3], "fov_degrees": 60}
+                ],
+                "lights": [{"kind": {"type": "Point", "position": [3, -3, 6]}, "intensity": 40}],
+                "objects": [
+                    {"surface": {"type": "Plane", "position": [0, 0, 0], "u_basis": [1, 0, 0], "v_basis": [0, 1, 0]},
+                     "texture": {"type": "Checkerboard",
+                                 "texture1": {"type": "Color", "color": [0.9, 0.9, 0.9]},
+                                 "texture2": {"type": "Color", "color": [0.2, 0.4, 0.1]}}},
+                    {"surface": {"type": "Sphere", "center": [0, 0, 1], "radius": 1},
+                     "texture": {"type": "Color", "color": [0.8, 0.1, 0.1]}}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let camera = scene_file.cameras()[0].clone();
+        let mut scene = scene_file.build_scene(&camera, &[Rgb::BLACK]).unwrap();
+        scene.build_bvh();
+        (scene, camera)
+    }
+
+    #[test]
+    fn fast_path_matches_general_path() {
+        let (scene, camera) = simple_scene();
+        let (width, height) = (64, 48);
+        let image_plane = ImagePlane::new(width, height);
+        let fast_light = scene.fast_path_light();
+        assert!(fast_light.is_some());
+        for y in 0..height {
+            for x in 0..width {
+                let (x, y) = (x as f32, y as f32);
+                assert_eq!(
+                    scene.cast_primary(&camera, &image_plane, fast_light, x, y),
+                    scene.cast_primary(&camera, &image_plane, None, x, y),
+                    "pixel ({}, {}) differs",
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn edge_antialiasing_only_resamples_edges() {
+        let (scene, camera) = simple_scene();
+        let (width, height) = (64, 48);
+        let plain = scene.trace_image(&camera, width, height);
+        let antialiased = scene.trace_image_edge_antialiased(&camera, width, height, 4);
+        let object_ids = scene.trace_object_ids(&camera, width, height);
+
+        let mut edges = 0;
+        for y in 0..height {
+            for x in 0..width {
+                if is_edge_pixel(&object_ids, x, y) {
+                    edges += 1;
                 } else {
-                    Rgb::BLACK
-                };
+                    // Flat regions get a single ray, so they are just as before
+                    assert_eq!(antialiased.get(y, x), plain.get(y, x));
+                }
+            }
+        }
+        // The sphere's silhouette and the horizon, but only a small part of the image
+        assert!(
+            edges > 0 && edges < width * height / 4,
+            "{} edge pixels",
+            edges
+        );
+        let differing = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .filter(|&(x, y)| antialiased.get(y, x) != plain.get(y, x))
+            .count();
+        assert!(differing > 0);
+    }
+
+    #[test]
+    fn pixels_next_to_another_object_are_edges() {
+        // Object 1 covers the 2x2 block in the middle of a 4x4 image
+        let mut object_ids = Array2D::new(4, 4, &None);
+        for (x, y) in [(1, 1), (2, 1), (1, 2), (2, 2)] {
+            object_ids.set(y, x, &Some(1));
+        }
+        let flat = Array2D::new(4, 4, &Some(0));
 
-                vobj_color.scale(light_intensity).add(&reflected_color)
+        for y in 0..4 {
+            for x in 0..4 {
+                assert!(!is_edge_pixel(&flat, x, y));
+                // Only the corners have no neighbor on the block
+                let is_corner = (x == 0 || x == 3) && (y == 0 || y == 3);
+                assert_eq!(
+                    is_edge_pixel(&object_ids, x, y),
+                    !is_corner,
+                    "({}, {})",
+                    x,
+                    y
+                );
             }
-            None => self.background,
         }
     }
+
+    #[test]
+    fn polygon_aperture_samples_lie_inside_polygon() {
+        let hexagon = ApertureShape::Polygon {
+            sides: 6,
+            rotation: 0.3,
+        };
+        for _ in 0..1000 {
+            let (x, y) = hexagon.sample_point();
+            assert!(hexagon.contains(x, y), "({}, {}) is outside", x, y);
+        }
+        // A corner of the unit circle's bounding square is outside every aperture
+        assert!(!hexagon.contains(0.75, 0.75));
+        assert!(!ApertureShape::Circle.contains(0.75, 0.75));
+    }
+
+    #[test]
+    fn rect_light_shadow_rays_end_on_the_rect() {
+        let corner = Vec3f {
+            x: -2.0,
+            y: -1.0,
+            z: 8.0,
+        };
+        let x_axis = Vec3f {
+            x: 1.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        let y_axis = Vec3f {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        };
+        // y cross x faces down, towards the point being lit
+        let quad = Quad::new(Plane::new(&corner, &y_axis, &x_axis), 2.0, 4.0);
+        let light = LightSource::new(LightKind::Rect { quad }, 1.0);
+        let trace_pos = Vec3f {
+            x: 5.0,
+            y: 3.0,
+            z: 0.0,
+        };
+
+        let (dir_to_light, light_distance) = light.direction_from(&trace_pos);
+        assert!(light.intensity_at(&dir_to_light, light_distance) > 0.0);
+        for sample in 0..100 {
+            let (dir, distance) =
+                light.shadow_ray(&trace_pos, &dir_to_light, light_distance, sample, 100);
+            let end = trace_pos + dir * distance;
+            assert!((end.z - 8.0).abs() < 1e-3, "{:?} is off the plane", end);
+            assert!(
+                (-2.001..=2.001).contains(&end.x),
+                "{:?} is off the rect",
+                end
+            );
+            assert!(
+                (-1.001..=1.001).contains(&end.y),
+                "{:?} is off the rect",
+                end
+            );
+        }
+
+        // Nothing is lit behind the rect
+        let (dir_to_light, light_distance) = light.direction_from(&Vec3f {
+            x: 0.0,
+            y: 0.0,
+            z: 10.0,
+        });
+        assert_eq!(light.intensity_at(&dir_to_light, light_distance), 0.0);
+    }
 }
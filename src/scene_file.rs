@@ -0,0 +1,971 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::material::Material;
+use crate::math::{Mat4, Rgb, Vec3f};
+use crate::mesh::Mesh;
+use crate::scene::{Camera, DistanceFade, LightKind, LightPortal, LightSource, Scene, VisObj};
+use crate::surface::*;
+use crate::texture::*;
+use crate::util::EdgeMode;
+
+/// A scene read from a JSON file, so that it can be changed without recompiling.
+/// Vectors and colors are written as [x, y, z] and [red, green, blue] arrays, and
+/// the kind of each camera, light, surface and texture is given by its "type"
+/// field, eg {"type": "Sphere", "center": [0, 0, 1], "radius": 1}. See
+/// scenes/default.json for an example.
+///
+/// Surfaces and textures are trait objects, which can't be deserialized
+/// directly, so the file is first read into the descriptions below and then
+/// built into a Scene.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneFile {
+    background: [f32; 3],
+    #[serde(default = "default_ambient_light")]
+    ambient_light: [f32; 3],
+    #[serde(default)]
+    terminator_softening: f32,
+    cameras: Vec<CameraDesc>,
+    #[serde(default)]
+    lights: Vec<LightDesc>,
+    objects: Vec<ObjectDesc>,
+    /// Directory the file was loaded from, which the files it refers to (eg
+    /// meshes and images) are relative to, or None if it wasn't loaded from a
+    /// file, in which case it can't refer to any
+    #[serde(skip)]
+    directory: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", deny_unknown_fields)]
+enum CameraDesc {
+    /// See Camera::new()
+    Direction {
+        position: [f32; 3],
+        direction: [f32; 3],
+        fov_degrees: f32,
+    },
+    /// See Camera::look_at(), "up" defaults to the z axis
+    LookAt {
+        position: [f32; 3],
+        target: [f32; 3],
+        up: Option<[f32; 3]>,
+        fov_degrees: f32,
+    },
+}
+
+/// A LightSource, any of whose optional fields default to those given by
+/// LightSource::new()
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LightDesc {
+    kind: LightKindDesc,
+    intensity: f32,
+    color: Option<[f32; 3]>,
+    angular_radius: Option<f32>,
+    shadow_samples: Option<usize>,
+    casts_shadows: Option<bool>,
+    portal: Option<PortalDesc>,
+}
+
+/// A LightKind, with angles in radians
+#[derive(Deserialize)]
+#[serde(tag = "type", deny_unknown_fields)]
+enum LightKindDesc {
+    Directional {
+        dir_to_light: [f32; 3],
+    },
+    Point {
+        position: [f32; 3],
+    },
+    Disk {
+        position: [f32; 3],
+        radius: f32,
+    },
+    Spot {
+        position: [f32; 3],
+        direction: [f32; 3],
+        inner_angle: f32,
+        outer_angle: f32,
+    },
+    Rect {
+        position: [f32; 3],
+        u_basis: [f32; 3],
+        v_basis: [f32; 3],
+        width: f32,
+        height: f32,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PortalDesc {
+    corner: [f32; 3],
+    edge1: [f32; 3],
+    edge2: [f32; 3],
+}
+
+/// A VisObj. Its surface properties are taken from the material preset named by
+/// "material" (or from a plain diffuse material if there is none), and then any
+/// of them which are given individually override those.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ObjectDesc {
+    surface: SurfaceDesc,
+    texture: TextureDesc,
+    material: Option<String>,
+    reflectivity: Option<f32>,
+    roughness: Option<f32>,
+    specular_intensity: Option<f32>,
+    shininess: Option<f32>,
+    transparency: Option<f32>,
+    index_of_refraction: Option<f32>,
+    ambient_multiplier: Option<f32>,
+    emission_texture: Option<TextureDesc>,
+    fade: Option<FadeDesc>,
+    tag: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FadeDesc {
+    start: f32,
+    end: f32,
+}
+
+/// One of the Surfaces, mostly with the same parameters as its constructor. The
+/// Plane that a Quad or Slab lies in is given by its parameters inline.
+#[derive(Deserialize)]
+#[serde(tag = "type", deny_unknown_fields)]
+enum SurfaceDesc {
+    Sphere {
+        center: [f32; 3],
+        radius: f32,
+    },
+    Plane {
+        position: [f32; 3],
+        u_basis: [f32; 3],
+        v_basis: [f32; 3],
+        /// Point that (u,v) is measured from, if not the position
+        #[serde(default)]
+        uv_origin: Option<[f32; 3]>,
+    },
+    Quad {
+        position: [f32; 3],
+        u_basis: [f32; 3],
+        v_basis: [f32; 3],
+        width: f32,
+        height: f32,
+        #[serde(default)]
+        uv_alignment: UvAlignmentDesc,
+    },
+    Disk {
+        center: [f32; 3],
+        normal: [f32; 3],
+        radius: f32,
+    },
+    Slab {
+        position: [f32; 3],
+        u_basis: [f32; 3],
+        v_basis: [f32; 3],
+        width: f32,
+        height: f32,
+        thickness: f32,
+    },
+    Aabb {
+        min: [f32; 3],
+        max: [f32; 3],
+    },
+    Triangle {
+        a: [f32; 3],
+        b: [f32; 3],
+        c: [f32; 3],
+    },
+    Cylinder {
+        base: [f32; 3],
+        axis: [f32; 3],
+        radius: f32,
+        height: f32,
+    },
+    Torus {
+        center: [f32; 3],
+        axis: [f32; 3],
+        major_radius: f32,
+        minor_radius: f32,
+    },
+    /// Triangle mesh loaded from a Wavefront OBJ file
+    Mesh {
+        file: String,
+    },
+    /// Another surface, scaled by "scale" and then moved by "offset"
+    Instance {
+        surface: Box<SurfaceDesc>,
+        #[serde(default)]
+        offset: [f32; 3],
+        #[serde(default = "one")]
+        scale: f32,
+    },
+    /// Another surface, moved by each of the steps in turn
+    Transformed {
+        surface: Box<SurfaceDesc>,
+        steps: Vec<TransformStep>,
+    },
+}
+
+#[derive(Deserialize, Default)]
+enum UvAlignmentDesc {
+    #[default]
+    Corner,
+    Center,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", deny_unknown_fields)]
+enum TransformStep {
+    Translate { offset: [f32; 3] },
+    Rotate { axis: [f32; 3], degrees: f32 },
+    Scale { factors: [f32; 3] },
+}
+
+/// One of the Textures, mostly with the same fields as its struct. The Mandelbrot
+/// set uses the colormap, and the Portal the camera, that the scene is built with.
+#[derive(Deserialize)]
+#[serde(tag = "type", deny_unknown_fields)]
+enum TextureDesc {
+    Color {
+        color: [f32; 3],
+    },
+    /// Squares 1.0 across, or if "squares_u" and "squares_v" are given, that
+    /// many squares across a surface "u_extent" by "v_extent" in size
+    Checkerboard {
+        texture1: Box<TextureDesc>,
+        texture2: Box<TextureDesc>,
+        squares_u: Option<u32>,
+        squares_v: Option<u32>,
+        #[serde(default = "one")]
+        u_extent: f32,
+        #[serde(default = "one")]
+        v_extent: f32,
+    },
+    CoordinateTransform {
+        texture: Box<TextureDesc>,
+        #[serde(default)]
+        u_offset: f32,
+        #[serde(default)]
+        v_offset: f32,
+        #[serde(default = "one")]
+        u_scale: f32,
+        #[serde(default = "one")]
+        v_scale: f32,
+    },
+    Grayscale {
+        texture: Box<TextureDesc>,
+    },
+    Scroll {
+        texture: Box<TextureDesc>,
+        u_velocity: f32,
+        v_velocity: f32,
+    },
+    Mandelbrot {
+        #[serde(default = "one_subsample")]
+        subsamples: usize,
+        #[serde(default)]
+        subsample_spacing: f32,
+        #[serde(default)]
+        contour_lines: bool,
+        #[serde(default)]
+        contour_thickness: f32,
+    },
+    Portal {
+        #[serde(default = "one_subsample")]
+        subsamples: usize,
+        #[serde(default)]
+        subsample_spacing: f32,
+    },
+    Projection {
+        texture: Box<TextureDesc>,
+        #[serde(with = "ProjectionModeDesc")]
+        mode: ProjectionMode,
+        center: [f32; 3],
+    },
+    /// Picture loaded from a binary PPM file
+    Image {
+        file: String,
+        #[serde(with = "ImageFilterDesc", default = "default_image_filter")]
+        filter: ImageFilter,
+        #[serde(with = "EdgeModeDesc", default = "default_edge_mode")]
+        edges: EdgeMode,
+    },
+    Noise {
+        texture1: Box<TextureDesc>,
+        texture2: Box<TextureDesc>,
+        frequency: f32,
+        #[serde(default = "one_octave")]
+        octaves: u32,
+        #[serde(default)]
+        seed: u32,
+    },
+    Gradient {
+        texture1: Box<TextureDesc>,
+        texture2: Box<TextureDesc>,
+        #[serde(with = "GradientDirectionDesc")]
+        direction: GradientDirection,
+    },
+}
+
+// The plain enums are read by name, eg "Bilinear", through these copies of them
+
+#[derive(Deserialize)]
+#[serde(remote = "ProjectionMode")]
+enum ProjectionModeDesc {
+    Planar,
+    Cylindrical,
+    Spherical,
+}
+
+#[derive(Deserialize)]
+#[serde(remote = "ImageFilter")]
+enum ImageFilterDesc {
+    Nearest,
+    Bilinear,
+}
+
+#[derive(Deserialize)]
+#[serde(remote = "EdgeMode")]
+enum EdgeModeDesc {
+    Wrap,
+    Clamp,
+}
+
+#[derive(Deserialize)]
+#[serde(remote = "GradientDirection")]
+enum GradientDirectionDesc {
+    U,
+    V,
+    Diagonal,
+}
+
+fn default_ambient_light() -> [f32; 3] {
+    [0.25, 0.25, 0.25]
+}
+
+fn one() -> f32 {
+    1.0
+}
+
+fn one_subsample() -> usize {
+    1
+}
+
+fn one_octave() -> u32 {
+    1
+}
+
+fn default_image_filter() -> ImageFilter {
+    ImageFilter::Bilinear
+}
+
+fn default_edge_mode() -> EdgeMode {
+    EdgeMode::Wrap
+}
+
+fn vec3f(v: &[f32; 3]) -> Vec3f {
+    Vec3f {
+        x: v[0],
+        y: v[1],
+        z: v[2],
+    }
+}
+
+fn rgb(c: &[f32; 3]) -> Rgb {
+    Rgb {
+        red: c[0],
+        green: c[1],
+        blue: c[2],
+    }
+}
+
+/// Checks that a grid of subsamples is spread out, since with the default spacing
+/// of zero every subsample would land on the same point
+fn check_subsample_spacing(subsamples: usize, spacing: f32) -> Result<(), String> {
+    if subsamples > 1 && !(spacing > 0.0 && spacing.is_finite()) {
+        Err(String::from(
+            "subsample_spacing must be positive when there is more than one subsample",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns the path of a file that the scene refers to, which is relative to the
+/// scene file's directory, or an error if the scene isn't from a file
+fn referenced_file(directory: Option<&Path>, file: &str) -> Result<PathBuf, String> {
+    directory
+        .map(|directory| directory.join(file))
+        .ok_or_else(|| {
+            format!(
+                "Cannot load {}, only scenes loaded from a file can refer to other files",
+                file
+            )
+        })
+}
+
+/// What textures need from outside the file to be built
+struct TextureContext<'a> {
+    directory: Option<&'a Path>,
+    portal_camera: &'a Camera,
+    colormap: &'a [Rgb],
+}
+
+impl SceneFile {
+    pub fn load(filename: &str) -> io::Result<SceneFile> {
+        let json = fs::read_to_string(filename)?;
+        let mut scene_file = SceneFile::parse(&json)?;
+        scene_file.directory = Some(
+            Path::new(filename)
+                .parent()
+                .map_or(PathBuf::new(), Path::to_path_buf),
+        );
+
+        Ok(scene_file)
+    }
+
+    /// Reads a scene which didn't come from a file, eg one sent to the server.
+    /// Since there is nowhere for them to be relative to (and they could be
+    /// anywhere), it can't refer to other files such as meshes and images.
+    pub fn parse(json: &str) -> serde_json::Result<SceneFile> {
+        serde_json::from_str(json)
+    }
+
+    pub fn cameras(&self) -> Vec<Camera> {
+        self.cameras
+            .iter()
+            .map(|camera| match camera {
+                CameraDesc::Direction {
+                    position,
+                    direction,
+                    fov_degrees,
+                } => Camera::new(vec3f(position), vec3f(direction), *fov_degrees),
+                CameraDesc::LookAt {
+                    position,
+                    target,
+                    up,
+                    fov_degrees,
+                } => Camera::look_at(
+                    vec3f(position),
+                    vec3f(target),
+                    up.as_ref().map_or(Vec3f::UP, vec3f),
+                    *fov_degrees,
+                ),
+            })
+            .collect()
+    }
+
+    /// Builds the scene, loading any files that it refers to. As with the built in
+    /// scene, the portal shows the view from "portal_camera".
+    pub fn build_scene(&self, portal_camera: &Camera, colormap: &[Rgb]) -> Result<Scene, String> {
+        let context = TextureContext {
+            directory: self.directory.as_deref(),
+            portal_camera,
+            colormap,
+        };
+
+        let mut objects = Vec::new();
+        for object in &self.objects {
+            objects.push(object.build(&context)?);
+        }
+
+        Ok(Scene {
+            background: rgb(&self.background),
+            ambient_light: rgb(&self.ambient_light),
+            terminator_softening: self.terminator_softening,
+            depth_fade_bounces: 0,
+            light_sources: self.lights.iter().map(LightDesc::build).collect(),
+            objects,
+            bvh: None,
+            environment: None,
+            fog_volumes: Vec::new(),
+            caustics: None,
+            emitters: None,
+            time: 0.0,
+            progress: None,
+        })
+    }
+}
+
+impl LightDesc {
+    fn build(&self) -> LightSource {
+        let kind = match &self.kind {
+            LightKindDesc::Directional { dir_to_light } => LightKind::Directional {
+                dir_to_light: vec3f(dir_to_light),
+            },
+            LightKindDesc::Point { position } => LightKind::Point {
+                position: vec3f(position),
+            },
+            LightKindDesc::Disk { position, radius } => LightKind::Disk {
+                position: vec3f(position),
+                radius: *radius,
+            },
+            LightKindDesc::Spot {
+                position,
+                direction,
+                inner_angle,
+                outer_angle,
+            } => LightKind::Spot {
+                position: vec3f(position),
+                direction: vec3f(direction),
+                inner_angle: *inner_angle,
+                outer_angle: *outer_angle,
+            },
+            LightKindDesc::Rect {
+                position,
+                u_basis,
+                v_basis,
+                width,
+                height,
+            } => LightKind::Rect {
+                quad: Quad::new(
+                    Plane::new(&vec3f(position), &vec3f(u_basis), &vec3f(v_basis)),
+                    *width,
+                    *height,
+                ),
+            },
+        };
+        let defaults = LightSource::new(kind, self.intensity);
+
+        LightSource {
+            color: self.color.as_ref().map_or(defaults.color, rgb),
+            angular_radius: self.angular_radius.unwrap_or(defaults.angular_radius),
+            shadow_samples: self.shadow_samples.unwrap_or(defaults.shadow_samples),
+            casts_shadows: self.casts_shadows.unwrap_or(defaults.casts_shadows),
+            portal: self.portal.as_ref().map(|portal| {
+                LightPortal::new(
+                    vec3f(&portal.corner),
+                    vec3f(&portal.edge1),
+                    vec3f(&portal.edge2),
+                )
+            }),
+            ..defaults
+        }
+    }
+}
+
+impl ObjectDesc {
+    fn build(&self, context: &TextureContext) -> Result<VisObj, String> {
+        let material = match &self.material {
+            Some(name) => Material::preset(name).ok_or(format!(
+                "Unknown material \"{}\", expected one of: {}",
+                name,
+                Material::PRESET_NAMES.join(", ")
+            ))?,
+            None => Material {
+                reflectivity: 0.0,
+                roughness: 0.0,
+                specular_intensity: 0.0,
+                shininess: 32.0,
+                transparency: 0.0,
+                index_of_refraction: 1.0,
+                ambient_multiplier: 1.0,
+            },
+        };
+
+        Ok(VisObj {
+            surface: self.surface.build(context.directory)?,
+            texture: self.texture.build(context)?,
+            reflectivity: self.reflectivity.unwrap_or(material.reflectivity),
+            roughness: self.roughness.unwrap_or(material.roughness),
+            specular_intensity: self
+                .specular_intensity
+                .unwrap_or(material.specular_intensity),
+            shininess: self.shininess.unwrap_or(material.shininess),
+            transparency: self.transparency.unwrap_or(material.transparency),
+            index_of_refraction: self
+                .index_of_refraction
+                .unwrap_or(material.index_of_refraction),
+            ambient_multiplier: self
+                .ambient_multiplier
+                .unwrap_or(material.ambient_multiplier),
+            emission_texture: match &self.emission_texture {
+                Some(texture) => Some(texture.build(context)?),
+                None => None,
+            },
+            fade: self.fade.as_ref().map(|fade| DistanceFade {
+                start: fade.start,
+                end: fade.end,
+            }),
+            tag: self.tag.clone(),
+        })
+    }
+}
+
+impl SurfaceDesc {
+    fn build(&self, directory: Option<&Path>) -> Result<Box<dyn Surface>, String> {
+        let plane = |position, u_basis, v_basis| {
+            Plane::new(&vec3f(position), &vec3f(u_basis), &vec3f(v_basis))
+        };
+
+        Ok(match self {
+            SurfaceDesc::Sphere { center, radius } => {
+                Box::new(Sphere::new(&vec3f(center), *radius))
+            }
+            SurfaceDesc::Plane {
+                position,
+                u_basis,
+                v_basis,
+                uv_origin,
+            } => {
+                let plane = plane(position, u_basis, v_basis);
+                Box::new(match uv_origin {
+                    Some(origin) => plane.with_uv_origin(&vec3f(origin)),
+                    None => plane,
+                })
+            }
+            SurfaceDesc::Quad {
+                position,
+                u_basis,
+                v_basis,
+                width,
+                height,
+                uv_alignment,
+            } => Box::new(
+                Quad::new(plane(position, u_basis, v_basis), *width, *height).with_uv_alignment(
+                    match uv_alignment {
+                        UvAlignmentDesc::Corner => UvAlignment::Corner,
+                        UvAlignmentDesc::Center => UvAlignment::Center,
+                    },
+                ),
+            ),
+            SurfaceDesc::Disk {
+                center,
+                normal,
+                radius,
+            } => Box::new(Disk::new(&vec3f(center), &vec3f(normal), *radius)),
+            SurfaceDesc::Slab {
+                position,
+                u_basis,
+                v_basis,
+                width,
+                height,
+                thickness,
+            } => Box::new(Slab::new(
+                plane(position, u_basis, v_basis),
+                *width,
+                *height,
+                *thickness,
+            )),
+            SurfaceDesc::Aabb { min, max } => Box::new(Aabb::new(&vec3f(min), &vec3f(max))),
+            SurfaceDesc::Triangle { a, b, c } => {
+                Box::new(Triangle::new(&vec3f(a), &vec3f(b), &vec3f(c)))
+            }
+            SurfaceDesc::Cylinder {
+                base,
+                axis,
+                radius,
+                height,
+            } => Box::new(Cylinder::new(&vec3f(base), &vec3f(axis), *radius, *height)),
+            SurfaceDesc::Torus {
+                center,
+                axis,
+                major_radius,
+                minor_radius,
+            } => Box::new(Torus::new(
+                &vec3f(center),
+                &vec3f(axis),
+                *major_radius,
+                *minor_radius,
+            )),
+            SurfaceDesc::Mesh { file } => {
+                let path = referenced_file(directory, file)?;
+                Box::new(
+                    Mesh::load_obj(&path.to_string_lossy())
+                        .map_err(|err| format!("Error loading mesh {}: {}", path.display(), err))?,
+                )
+            }
+            SurfaceDesc::Instance {
+                surface,
+                offset,
+                scale,
+            } => Box::new(Instance::new(
+                Arc::from(surface.build(directory)?),
+                &vec3f(offset),
+                *scale,
+            )),
+            SurfaceDesc::Transformed { surface, steps } => {
+                let transform = steps.iter().fold(Mat4::IDENTITY, |transform, step| {
+                    let step = match step {
+                        TransformStep::Translate { offset } => Mat4::translation(&vec3f(offset)),
+                        TransformStep::Rotate { axis, degrees } => {
+                            Mat4::rotation(&vec3f(axis), degrees.to_radians())
+                        }
+                        TransformStep::Scale { factors } => Mat4::scaling(&vec3f(factors)),
+                    };
+                    step.mul_mat(&transform)
+                });
+                Box::new(Transformed::new(
+                    Arc::from(surface.build(directory)?),
+                    transform,
+                ))
+            }
+        })
+    }
+}
+
+impl TextureDesc {
+    fn build(&self, context: &TextureContext) -> Result<Box<dyn Texture>, String> {
+        Ok(match self {
+            TextureDesc::Color { color } => Box::new(rgb(color)),
+            TextureDesc::Checkerboard {
+                texture1,
+                texture2,
+                squares_u,
+                squares_v,
+                u_extent,
+                v_extent,
+            } => {
+                let (texture1, texture2) = (texture1.build(context)?, texture2.build(context)?);
+                match (squares_u, squares_v) {
+                    (None, None) => Box::new(Checkerboard::new(texture1, texture2)),
+                    (Some(squares_u), Some(squares_v)) => {
+                        if !(*u_extent > 0.0 && *v_extent > 0.0) {
+                            return Err(String::from("Checkerboard extents must be positive"));
+                        }
+                        Box::new(Checkerboard::with_square_count(
+                            texture1, texture2, *squares_u, *squares_v, *u_extent, *v_extent,
+                        ))
+                    }
+                    _ => {
+                        return Err(String::from(
+                            "Checkerboard needs both squares_u and squares_v, or neither",
+                        ))
+                    }
+                }
+            }
+            TextureDesc::CoordinateTransform {
+                texture,
+                u_offset,
+                v_offset,
+                u_scale,
+                v_scale,
+            } => Box::new(CoordinateTransform {
+                texture: texture.build(context)?,
+                u_offset: *u_offset,
+                v_offset: *v_offset,
+                u_scale: *u_scale,
+                v_scale: *v_scale,
+            }),
+            TextureDesc::Grayscale { texture } => Box::new(Grayscale {
+                texture: texture.build(context)?,
+            }),
+            TextureDesc::Scroll {
+                texture,
+                u_velocity,
+                v_velocity,
+            } => Box::new(Scroll {
+                texture: texture.build(context)?,
+                u_velocity: *u_velocity,
+                v_velocity: *v_velocity,
+            }),
+            TextureDesc::Mandelbrot {
+                subsamples,
+                subsample_spacing,
+                contour_lines,
+                contour_thickness,
+            } => {
+                check_subsample_spacing(*subsamples, *subsample_spacing)?;
+                Box::new(MandelbrotSet {
+                    colormap: context.colormap.to_vec(),
+                    subsamples: *subsamples,
+                    subsample_spacing: *subsample_spacing,
+                    contour_lines: *contour_lines,
+                    contour_thickness: *contour_thickness,
+                })
+            }
+            TextureDesc::Portal {
+                subsamples,
+                subsample_spacing,
+            } => {
+                check_subsample_spacing(*subsamples, *subsample_spacing)?;
+                Box::new(Portal {
+                    camera: context.portal_camera.clone(),
+                    subsamples: *subsamples,
+                    subsample_spacing: *subsample_spacing,
+                })
+            }
+            TextureDesc::Projection {
+                texture,
+                mode,
+                center,
+            } => Box::new(Projection {
+                texture: texture.build(context)?,
+                mode: *mode,
+                center: vec3f(center),
+            }),
+            TextureDesc::Image {
+                file,
+                filter,
+                edges,
+            } => {
+                let path = referenced_file(context.directory, file)?;
+                let image = ImageTexture::load(&path.to_string_lossy())
+                    .map_err(|err| format!("Error loading image {}: {}", path.display(), err))?;
+                Box::new(ImageTexture {
+                    filter: *filter,
+                    edges: *edges,
+                    ..image
+                })
+            }
+            TextureDesc::Noise {
+                texture1,
+                texture2,
+                frequency,
+                octaves,
+                seed,
+            } => Box::new(Noise::new(
+                texture1.build(context)?,
+                texture2.build(context)?,
+                *frequency,
+                *octaves,
+                *seed,
+            )),
+            TextureDesc::Gradient {
+                texture1,
+                texture2,
+                direction,
+            } => Box::new(Gradient {
+                texture1: texture1.build(context)?,
+                texture2: texture2.build(context)?,
+                direction: *direction,
+            }),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::MAX_DEPTH;
+    use crate::texture::TexturePoint;
+
+    /// Builds a scene sent as JSON, as the server does
+    fn build(json: &str) -> Result<Scene, String> {
+        let scene_file = SceneFile::parse(json).map_err(|err| err.to_string())?;
+        let camera = &scene_file.cameras()[0];
+        scene_file.build_scene(camera, &[Rgb::BLACK])
+    }
+
+    /// A scene with one object, which has the given surface
+    fn scene_with_surface(surface: &str) -> String {
+        format!(
+            r#"{{
+                "background": [0, 0, 0],
+                "cameras": [
+                    {{"type": "Direction", "position": [0, 0, 5], "direction": [0, 0, -1], "fov_degrees": 60}}
+                ],
+                "objects": [{{"surface": {}, "texture": {{"type": "Color", "color": [1, 1, 1]}}}}]
+            }}"#,
+            surface
+        )
+    }
+
+    #[test]
+    fn default_scene_file_builds() {
+        let scene_file = SceneFile::load("scenes/default.json").unwrap();
+        let cameras = scene_file.cameras();
+        assert!(!cameras.is_empty());
+        let scene = scene_file.build_scene(&cameras[0], &[Rgb::BLACK]).unwrap();
+        assert!(!scene.objects.is_empty());
+    }
+
+    #[test]
+    fn unknown_fields_and_types_are_rejected() {
+        let sphere = r#"{"type": "Sphere", "center": [0, 0, 0], "radius": 1}"#;
+        assert!(build(&scene_with_surface(sphere)).is_ok());
+
+        let misspelt_field = r#"{"type": "Sphere", "center": [0, 0, 0], "raduis": 1}"#;
+        assert!(build(&scene_with_surface(misspelt_field)).is_err());
+        let unknown_type = r#"{"type": "Teapot", "center": [0, 0, 0], "radius": 1}"#;
+        assert!(build(&scene_with_surface(unknown_type)).is_err());
+        assert!(
+            build(&scene_with_surface(sphere).replace("\"background\"", "\"backdrop\"")).is_err()
+        );
+    }
+
+    #[test]
+    fn parsed_scenes_cannot_refer_to_files() {
+        let mesh = r#"{"type": "Mesh", "file": "/etc/passwd"}"#;
+        let err = build(&scene_with_surface(mesh)).err().unwrap();
+        assert!(err.contains("only scenes loaded from a file"), "{}", err);
+    }
+
+    /// A scene with a quad, which has the given texture
+    fn scene_with_texture(texture: &str) -> String {
+        scene_with_surface(
+            r#"{"type": "Quad", "position": [0, 0, 0], "u_basis": [1, 0, 0],
+                 "v_basis": [0, 1, 0], "width": 4, "height": 2}"#,
+        )
+        .replace(r#"{"type": "Color", "color": [1, 1, 1]}"#, texture)
+    }
+
+    #[test]
+    fn subsamples_need_a_spacing() {
+        let mandelbrot = |fields| format!(r#"{{"type": "Mandelbrot"{}}}"#, fields);
+        assert!(build(&scene_with_texture(&mandelbrot(""))).is_ok());
+        assert!(build(&scene_with_texture(&mandelbrot(r#", "subsamples": 3"#))).is_err());
+        let spaced = mandelbrot(r#", "subsamples": 3, "subsample_spacing": 0.01"#);
+        assert!(build(&scene_with_texture(&spaced)).is_ok());
+
+        let portal = r#"{"type": "Portal", "subsamples": 2}"#;
+        assert!(build(&scene_with_texture(portal)).is_err());
+        let portal = r#"{"type": "Portal", "subsamples": 2, "subsample_spacing": 0.1}"#;
+        assert!(build(&scene_with_texture(portal)).is_ok());
+    }
+
+    #[test]
+    fn checkerboard_square_count_spans_the_quad() {
+        let scene = build(
+            r#"{
+                "background": [0, 0, 0],
+                "cameras": [
+                    {"type": "Direction", "position": [0, 0, 5], "direction": [0, 0, -1], "fov_degrees": 60}
+                ],
+                "objects": [{
+                    "surface": {"type": "Quad", "position": [0, 0, 0], "u_basis": [1, 0, 0],
+                                "v_basis": [0, 1, 0], "width": 4, "height": 2},
+                    "texture": {"type": "Checkerboard", "squares_u": 8, "squares_v": 8,
+                                "u_extent": 4, "v_extent": 2,
+                                "texture1": {"type": "Color", "color": [0, 0, 0]},
+                                "texture2": {"type": "Color", "color": [1, 1, 1]}}
+                }]
+            }"#,
+        )
+        .unwrap();
+        let object = &scene.objects[0];
+
+        // Changes of color along a line from one edge of the quad to the other
+        let transitions = |start: Vec3f, end: Vec3f| {
+            let colors: Vec<f32> = (0..400)
+                .map(|i| {
+                    let position = start + (end - start) * ((i as f32 + 0.5) / 400.0);
+                    let surface = object.surface.at_point(&position);
+                    let point = TexturePoint {
+                        u: surface.u,
+                        v: surface.v,
+                        position,
+                        footprint: 0.0,
+                    };
+                    object.texture.color(&scene, MAX_DEPTH, &point).red
+                })
+                .collect();
+            colors.windows(2).filter(|pair| pair[0] != pair[1]).count()
+        };
+        let point = |x, y| Vec3f { x, y, z: 0.0 };
+
+        assert_eq!(transitions(point(0.0, 0.3), point(4.0, 0.3)), 7);
+        assert_eq!(transitions(point(1.1, 0.0), point(1.1, 2.0)), 7);
+    }
+}
@@ -0,0 +1,190 @@
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::time::Duration;
+
+/// Largest request body which will be read, in bytes
+const MAX_BODY_LENGTH: usize = 1 << 20;
+
+/// How long a client can keep the server waiting while sending its request or
+/// receiving the response. Requests are handled one at a time, so a client which
+/// stalls holds up everyone else.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Handles HTTP requests arriving at the listener, forever. Each request's query
+/// string is decoded into (name, value) pairs, so that "/render?width=320&camera=1"
+/// gives [("width", "320"), ("camera", "1")], and handed to "render" along with
+/// the request's body (empty for a GET). It returns either the body of the
+/// response or an error message. If it panics, the client gets an error response
+/// and the server carries on. Requests are handled one at a time.
+pub fn serve(
+    listener: TcpListener,
+    content_type: &str,
+    render: impl Fn(Vec<(String, String)>, &[u8]) -> Result<Vec<u8>, String>,
+) -> io::Result<()> {
+    println!("Listening on http://{}/", listener.local_addr()?);
+
+    for stream in listener.incoming() {
+        // A misbehaving client shouldn't take down the server
+        if let Err(err) = stream.and_then(|stream| handle_client(stream, content_type, &render)) {
+            eprintln!("Error handling request: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+fn handle_client(
+    mut stream: TcpStream,
+    content_type: &str,
+    render: impl Fn(Vec<(String, String)>, &[u8]) -> Result<Vec<u8>, String>,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // Of the headers, only the length of the body matters to us
+    let mut content_length: Option<usize> = None;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim_end() != "" {
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().ok();
+            }
+        }
+        header.clear();
+    }
+
+    // eg "GET /render?width=320 HTTP/1.1"
+    let target = request_line.split_whitespace().nth(1).unwrap_or("/");
+    let query = target.split_once('?').map_or("", |(_, query)| query);
+
+    let result = match content_length {
+        Some(length) if length > MAX_BODY_LENGTH => Err(format!(
+            "Request body is too large, the limit is {} bytes",
+            MAX_BODY_LENGTH
+        )),
+        _ => {
+            let mut request_body = vec![0; content_length.unwrap_or(0)];
+            reader.read_exact(&mut request_body)?;
+            query_params(query).and_then(|params| {
+                panic::catch_unwind(AssertUnwindSafe(|| render(params, &request_body)))
+                    .unwrap_or_else(|_| Err(String::from("Rendering failed unexpectedly")))
+            })
+        }
+    };
+    let (status, content_type, body) = match result {
+        Ok(body) => ("200 OK", content_type, body),
+        Err(msg) => ("400 Bad Request", "text/plain", msg.into_bytes()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+    stream.flush()
+}
+
+/// Converts "a=1&b=x%2Cy" into [("a", "1"), ("b", "x,y")]. Parameters without a
+/// value, as in "a&b=2", are given an empty one, ie [("a", ""), ("b", "2")].
+fn query_params(query: &str) -> Result<Vec<(String, String)>, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((percent_decode(name)?, percent_decode(value)?))
+        })
+        .collect()
+}
+
+/// Undoes the escaping of a URL's query string, where "+" stands for a space and
+/// "%XX" for the byte with hex value XX
+fn percent_decode(text: &str) -> Result<String, String> {
+    let bad_escape = || format!("Could not decode query parameter {}", text);
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex_digit =
+                    |index: usize| rest.get(index).and_then(|&c| (c as char).to_digit(16));
+                match (hex_digit(0), hex_digit(1)) {
+                    (Some(high), Some(low)) => bytes.push((high * 16 + low) as u8),
+                    _ => return Err(bad_escape()),
+                }
+                rest = &rest[2..];
+            }
+            _ => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| bad_escape())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::thread;
+
+    /// Sends a raw HTTP request and returns the whole response
+    fn send_request(address: SocketAddr, request: &str) -> Vec<u8> {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn server_survives_a_panicking_render() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            serve(listener, "text/plain", |params, body| {
+                if params.iter().any(|(name, _)| name == "panic") {
+                    panic!("render failed");
+                }
+                Ok(body.to_vec())
+            })
+        });
+
+        let response = send_request(address, "GET /?panic HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with(b"HTTP/1.1 400 "));
+
+        let response = send_request(address, "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello");
+        assert!(response.starts_with(b"HTTP/1.1 200 "));
+        assert!(response.ends_with(b"\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn query_params_are_percent_decoded() {
+        let params = query_params("lens-shift=0.1%2C-0.2&only=big+sphere&linear").unwrap();
+        assert_eq!(
+            params,
+            vec![
+                (String::from("lens-shift"), String::from("0.1,-0.2")),
+                (String::from("only"), String::from("big sphere")),
+                (String::from("linear"), String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_escapes_are_rejected() {
+        assert!(query_params("width=%3").is_err());
+        assert!(query_params("width=%zz").is_err());
+        assert!(query_params("width=%+1").is_err());
+        // Not valid UTF-8
+        assert!(query_params("only=%ff").is_err());
+    }
+}
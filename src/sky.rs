@@ -0,0 +1,71 @@
+use crate::math::{Rgb, Vec3f};
+use crate::scene::LightSource;
+
+/// An Environment gives the color seen by rays which escape the scene without
+/// hitting anything, based only on their direction.
+pub trait Environment: Sync {
+    fn color(&self, direction: &Vec3f) -> Rgb;
+}
+
+/// A color can be used as an Environment, giving the same color in every direction
+impl Environment for Rgb {
+    fn color(&self, _direction: &Vec3f) -> Rgb {
+        *self
+    }
+}
+
+/// Procedural sky, with a gradient from the horizon up to the zenith and a sun
+/// disk. Directions below the horizon get the horizon color.
+pub struct Sky {
+    pub horizon_color: Rgb,
+    pub zenith_color: Rgb,
+    /// Unit vector pointing towards the center of the sun
+    pub sun_direction: Vec3f,
+    /// Angular radius of the sun disk, in radians
+    pub sun_angular_radius: f32,
+    /// Color of the sun disk, usually much brighter than the rest of the sky
+    pub sun_color: Rgb,
+}
+
+impl Sky {
+    /// Moves the sun so that it appears in the same direction as the given light,
+    /// so that shadows agree with the sun visible in the sky (and in reflections).
+    /// If the light has a nonzero angular radius, the sun disk is resized to match.
+    /// For lights which aren't infinitely far away, the sun is put in the light's
+    /// direction as seen from the origin.
+    pub fn with_sun_at_light(self, light: &LightSource) -> Sky {
+        let sun_angular_radius = if light.angular_radius > 0.0 {
+            light.angular_radius
+        } else {
+            self.sun_angular_radius
+        };
+
+        Sky {
+            sun_direction: light
+                .direction_from(&Vec3f {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                })
+                .0,
+            sun_angular_radius,
+            ..self
+        }
+    }
+}
+
+impl Environment for Sky {
+    fn color(&self, direction: &Vec3f) -> Rgb {
+        let direction = direction.normalize();
+
+        if direction.dot(&self.sun_direction) >= self.sun_angular_radius.cos() {
+            return self.sun_color;
+        }
+
+        // Sine of the elevation above the horizon
+        let elevation = direction.dot(&Vec3f::UP).max(0.0);
+        self.horizon_color
+            .scale(1.0 - elevation)
+            .add(&self.zenith_color.scale(elevation))
+    }
+}
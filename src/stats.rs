@@ -0,0 +1,94 @@
+use std::cell::Cell;
+use std::sync::Mutex;
+
+/// Counts of the rays cast while rendering an image
+#[derive(Debug, Default, Copy, Clone)]
+pub struct RayStats {
+    pub primary_rays: u64,
+    pub secondary_rays: u64,
+    pub shadow_rays: u64,
+    pub deepest_bounce: u32,
+}
+
+/// The different reasons a ray might be cast
+#[derive(Debug, Copy, Clone)]
+pub enum RayKind {
+    /// Cast from the camera through a pixel
+    Primary,
+    /// Cast from a surface, eg reflections or portals
+    Secondary,
+    /// Cast from a surface towards a light source
+    Shadow,
+}
+
+// Counting rays happens in the innermost loops of the renderer, so rather than
+// having every thread hammer on a shared atomic, each thread counts into its
+// own copy, which is periodically merged into the global totals.
+thread_local! {
+    static LOCAL_STATS: Cell<RayStats> = const {
+        Cell::new(RayStats {
+            primary_rays: 0,
+            secondary_rays: 0,
+            shadow_rays: 0,
+            deepest_bounce: 0,
+        })
+    };
+}
+
+static GLOBAL_STATS: Mutex<RayStats> = Mutex::new(RayStats {
+    primary_rays: 0,
+    secondary_rays: 0,
+    shadow_rays: 0,
+    deepest_bounce: 0,
+});
+
+impl RayStats {
+    pub fn total_rays(&self) -> u64 {
+        self.primary_rays + self.secondary_rays + self.shadow_rays
+    }
+
+    fn merge(&self, other: &RayStats) -> RayStats {
+        RayStats {
+            primary_rays: self.primary_rays + other.primary_rays,
+            secondary_rays: self.secondary_rays + other.secondary_rays,
+            shadow_rays: self.shadow_rays + other.shadow_rays,
+            deepest_bounce: self.deepest_bounce.max(other.deepest_bounce),
+        }
+    }
+}
+
+fn update_local(f: impl FnOnce(&mut RayStats)) {
+    LOCAL_STATS.with(|cell| {
+        let mut stats = cell.get();
+        f(&mut stats);
+        cell.set(stats);
+    })
+}
+
+/// Count a single ray cast on the current thread
+pub fn record_ray(kind: RayKind) {
+    update_local(|stats| match kind {
+        RayKind::Primary => stats.primary_rays += 1,
+        RayKind::Secondary => stats.secondary_rays += 1,
+        RayKind::Shadow => stats.shadow_rays += 1,
+    });
+}
+
+/// Note that a ray was cast after "bounce" levels of recursion
+pub fn record_bounce(bounce: u32) {
+    update_local(|stats| stats.deepest_bounce = stats.deepest_bounce.max(bounce));
+}
+
+/// Add the counts accumulated on the current thread to the global totals, and
+/// reset the thread's counts. This should be called at the end of each unit of
+/// parallel work, otherwise counts will be lost when worker threads exit.
+pub fn merge_thread_local() {
+    let local = LOCAL_STATS.with(|cell| cell.take());
+    let mut global = GLOBAL_STATS.lock().unwrap();
+    *global = global.merge(&local);
+}
+
+/// Returns the global totals merged so far, resetting them to zero
+pub fn take_totals() -> RayStats {
+    std::mem::take(&mut *GLOBAL_STATS.lock().unwrap())
+}
@@ -1,17 +1,51 @@
-use crate::math::{solve_quadratic, Vec3f};
+use std::sync::Arc;
+
+use crate::math::{orthonormal_basis, solve_quadratic, solve_quartic, Mat4, Vec3f};
+use crate::util::rand_f32;
 
 /// A Surface is a 2-D surface positioned and oriented in 3-D space which can be
 /// tested for intersection and points on the surface can be mapped to a 2-D
 /// (u, v) space, which is then typically translated to a color using a Texture.
-pub trait Surface: Sync {
+pub trait Surface: Send + Sync {
     /// Find an intersection with the suraface. Returns the scaling factor of ray_direction
     /// from ray_origin that results in an intersection with the surface, if it exists.
+    ///
+    /// All rays cast by the Scene have unit length directions, so the returned value
+    /// is the distance to the intersection in world units. Surfaces should not rely
+    /// on this however, since eg Instance passes along scaled directions.
     fn intersection_with_ray(&self, ray_origin: &Vec3f, ray_direction: &Vec3f) -> Option<f32>;
 
     /// For a point that was previously returned by intersection_with_ray(), find
     /// its properties. (Calling with a point not on the surface will probably yield
     /// non-sensical results.)
     fn at_point(&self, point_on_surface: &Vec3f) -> SurfaceProperties;
+
+    /// Pick a uniformly distributed random point on the surface, for use in eg area
+    /// lights. Returns None if the surface can't be sampled, such as when it is
+    /// infinite in extent.
+    fn sample_point(&self) -> Option<SurfaceSample> {
+        None
+    }
+
+    /// Total area of the surface, eg for weighting emissive objects by how much
+    /// light they give off. Returns None if the surface is infinite in extent.
+    fn area(&self) -> Option<f32> {
+        None
+    }
+
+    /// Axis aligned box which the surface lies entirely within, eg for skipping
+    /// over groups of objects which a ray can't hit. Returns None if the surface is
+    /// infinite in extent, in which case it has to be tested against every ray.
+    fn bounding_box(&self) -> Option<Aabb> {
+        None
+    }
+
+    /// Check the surface's parameters for mistakes which would make it render
+    /// incorrectly, such as NaNs or a zero size, returning a description of the
+    /// first problem found.
+    fn validate(&self) -> Result<(), String> {
+        Ok(())
+    }
 }
 
 /// SurfaceProperties describes a surface at a given point, consisting of the normal
@@ -23,6 +57,16 @@ pub struct SurfaceProperties {
     pub v: f32,
 }
 
+/// SurfaceSample is a randomly chosen point on a surface, along with the normal at
+/// that point and the probability density (with respect to surface area) of
+/// having chosen it.
+#[derive(Debug, Copy, Clone)]
+pub struct SurfaceSample {
+    pub position: Vec3f,
+    pub normal: Vec3f,
+    pub pdf: f32,
+}
+
 /// Perfect mathematical sphere
 #[derive(Debug, Copy, Clone)]
 pub struct Sphere {
@@ -30,7 +74,8 @@ pub struct Sphere {
     radius: f32,
 }
 
-/// Infinite plane including the point "position"
+/// Infinite plane including the point "position", which is also the origin of its
+/// texture coordinates
 #[derive(Debug, Copy, Clone)]
 pub struct Plane {
     position: Vec3f,
@@ -40,10 +85,117 @@ pub struct Plane {
 }
 
 /// Quadrilateral. (like a Plane, but finite in extent)
+///
+/// The Quad covers the half-open ranges [0, width) in u and [0, height) in v,
+/// so Quads placed edge to edge tile without gaps or overlaps: points on a shared
+/// edge belong to the Quad on the far side of it. Like the Plane, the normal is
+/// u_basis cross v_basis, so Quads built from the same bases face the same way.
+#[derive(Debug, Copy, Clone)]
 pub struct Quad {
     plane: Plane,
     width: f32,
     height: f32,
+    uv_alignment: UvAlignment,
+}
+
+/// Where the (u,v) origin of a Quad's texture coordinates lies
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum UvAlignment {
+    /// At the corner given by the plane's position, so (u,v) runs from (0,0) to
+    /// (width,height). This is the default.
+    Corner,
+    /// At the middle of the Quad, so (u,v) runs from (-width/2,-height/2) to
+    /// (width/2,height/2), which makes it easy to center a texture on it
+    Center,
+}
+
+/// Flat disk, like a Plane but only within "radius" of its center. The (u, v)
+/// coordinates are polar: u is the distance from the center as a fraction of the
+/// radius, and v the angle around it as a fraction of a full turn.
+#[derive(Debug, Copy, Clone)]
+pub struct Disk {
+    plane: Plane,
+    radius: f32,
+}
+
+/// Rectangular slab of material, like a Quad with thickness. The top face lies
+/// on the Plane, and the slab extends "thickness" units below it (opposite the
+/// plane's normal).
+pub struct Slab {
+    plane: Plane,
+    width: f32,
+    height: f32,
+    thickness: f32,
+}
+
+/// Axis aligned box between the corners "min" and "max". Each face is mapped with
+/// (u, v) as the distances from the "min" corner along the other two axes, in the
+/// order x, y, z.
+#[derive(Debug, Copy, Clone)]
+pub struct Aabb {
+    min: Vec3f,
+    max: Vec3f,
+}
+
+/// A positioned and uniformly scaled copy of a shared Surface. Many Instances can
+/// refer to the same underlying geometry, which is defined in its own local space.
+/// This is a cheaper special case of Transformed.
+pub struct Instance {
+    surface: Arc<dyn Surface>,
+    offset: Vec3f,
+    scale: f32,
+}
+
+/// A copy of a shared Surface moved into place by an arbitrary affine transform,
+/// such as a rotation. Like Instance, the ray is transformed into the surface's
+/// local space rather than the surface into world space, and since the direction
+/// isn't renormalized the distance along the ray is unchanged.
+///
+/// Intersections and normals are correct for any invertible transform, but
+/// area() and sample_point() assume the scaling is uniform, and surfaces which
+/// aren't rotationally symmetric (eg Aabb) are no longer axis aligned.
+pub struct Transformed {
+    surface: Arc<dyn Surface>,
+    /// Local to world
+    transform: Mat4,
+    /// World to local
+    inverse: Mat4,
+}
+
+/// Flat triangle with the corners "a", "b" and "c". The normal faces the side
+/// from which the corners appear counterclockwise, and (u, v) are the barycentric
+/// weights of "b" and "c", so a is at (0, 0), b at (1, 0) and c at (0, 1).
+#[derive(Debug, Copy, Clone)]
+pub struct Triangle {
+    a: Vec3f,
+    b: Vec3f,
+    c: Vec3f,
+    /// Unit normal, or zero for a degenerate triangle
+    normal: Vec3f,
+}
+
+/// Finite cylinder with closed ends. The axis runs from "base" for "height" units
+/// along the unit vector "axis". On the curved side, u is the angle around the
+/// axis (as a fraction of a full turn) and v the fraction of the way up it; the
+/// end caps have v of 0 (base) and 1 (top).
+#[derive(Debug, Copy, Clone)]
+pub struct Cylinder {
+    base: Vec3f,
+    axis: Vec3f,
+    radius: f32,
+    height: f32,
+}
+
+/// Torus (ring doughnut) around "center", in the plane perpendicular to the unit
+/// vector "axis". The middle of the tube is a circle of radius "major_radius", and
+/// the tube itself has radius "minor_radius". u is the angle around the axis and v
+/// the angle around the tube, both as fractions of a full turn.
+#[derive(Debug, Copy, Clone)]
+pub struct Torus {
+    center: Vec3f,
+    axis: Vec3f,
+    major_radius: f32,
+    minor_radius: f32,
 }
 
 impl Sphere {
@@ -58,14 +210,30 @@ impl Sphere {
 impl Surface for Sphere {
     fn intersection_with_ray(&self, ray_origin: &Vec3f, ray_direction: &Vec3f) -> Option<f32> {
         let origin_minus_center = ray_origin.sub(&self.center);
-        let a = ray_direction.dot(ray_direction); // Shouldn't this always be 1.0???
+        // Rays cast by the Scene have unit directions, making this 1.0, but Instance
+        // and Transformed pass scaled directions to the surfaces inside them
+        let a = ray_direction.dot(ray_direction);
         let b = 2.0 * ray_direction.dot(&origin_minus_center);
         let c = origin_minus_center.dot(&origin_minus_center) - (self.radius * self.radius);
 
+        // Most rays miss most spheres, so reject them cheaply before solving the
+        // quadratic. A ray starting outside the sphere (c > 0) and heading away from
+        // its center can't hit it, and nor can one whose closest approach to the
+        // center is further away than the radius.
+        let center_along_ray = b * -0.5;
+        if c > 0.0 && center_along_ray < 0.0 {
+            return None;
+        }
+        let closest_approach_squared = origin_minus_center.dot(&origin_minus_center)
+            - (center_along_ray * center_along_ray) / a;
+        if closest_approach_squared > self.radius * self.radius {
+            return None;
+        }
+
         // TODO: This is a little ugly. We want to max of t1 and t2, but only considering
         // those that are positive, since we don't want to detect objects behind us. Seems
         // like there should be a clearer way to do this.
-        match solve_quadratic(a, b, c) {
+        match solve_quadratic(a, b, c, false) {
             Some((t1, t2)) => match (t1 > 0.0, t2 > 0.0) {
                 (false, false) => None,
                 (false, true) => Some(t2),
@@ -84,6 +252,46 @@ impl Surface for Sphere {
 
         SurfaceProperties { normal, u, v }
     }
+
+    fn sample_point(&self) -> Option<SurfaceSample> {
+        // Picking z uniformly gives a uniform distribution over the surface area
+        // (Archimedes' hat-box theorem)
+        let z = 1.0 - 2.0 * rand_f32();
+        let phi = 2.0 * std::f32::consts::PI * rand_f32();
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let normal = Vec3f {
+            x: r * phi.cos(),
+            y: r * phi.sin(),
+            z,
+        };
+
+        Some(SurfaceSample {
+            position: self.center.add(&normal.scale(self.radius)),
+            normal,
+            pdf: 1.0 / (4.0 * std::f32::consts::PI * self.radius * self.radius),
+        })
+    }
+
+    fn area(&self) -> Option<f32> {
+        Some(4.0 * std::f32::consts::PI * self.radius * self.radius)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let radius = Vec3f {
+            x: self.radius,
+            y: self.radius,
+            z: self.radius,
+        };
+        Some(Aabb::new(
+            &self.center.sub(&radius),
+            &self.center.add(&radius),
+        ))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        validate_finite("Sphere center", &self.center)?;
+        validate_positive("Sphere radius", self.radius)
+    }
 }
 
 impl Plane {
@@ -97,6 +305,17 @@ impl Plane {
             normal,
         }
     }
+
+    /// Returns the same plane, but with (u,v) measured from "origin" (or rather
+    /// the point in the plane closest to it). Since a Plane is infinite, this is
+    /// how a texture is centered on a particular point.
+    pub fn with_uv_origin(&self, origin: &Vec3f) -> Plane {
+        let distance = origin.sub(&self.position).dot(&self.normal) / self.normal.dot(&self.normal);
+        Plane {
+            position: origin.sub(&self.normal.scale(distance)),
+            ..*self
+        }
+    }
 }
 
 impl Surface for Plane {
@@ -129,6 +348,18 @@ impl Surface for Plane {
             v,
         }
     }
+
+    fn validate(&self) -> Result<(), String> {
+        validate_finite("Plane position", &self.position)?;
+        validate_finite("Plane u basis", &self.u_basis)?;
+        validate_finite("Plane v basis", &self.v_basis)?;
+        // A zero normal means the basis vectors are zero or parallel
+        if self.normal.dot(&self.normal) > 0.0 {
+            Ok(())
+        } else {
+            Err(String::from("Plane basis vectors do not span a plane"))
+        }
+    }
 }
 
 impl Quad {
@@ -137,6 +368,119 @@ impl Quad {
             plane,
             width,
             height,
+            uv_alignment: UvAlignment::Corner,
+        }
+    }
+
+    /// Returns the point in the middle of the Quad
+    pub fn center(&self) -> Vec3f {
+        self.plane.position
+            + self.plane.u_basis * (self.width * 0.5)
+            + self.plane.v_basis * (self.height * 0.5)
+    }
+
+    /// Returns the direction the front of the Quad faces, see Plane
+    pub fn normal(&self) -> Vec3f {
+        self.plane.normal
+    }
+
+    /// Picks a random point on the Quad which is uniformly distributed over the
+    /// solid angle it covers as seen from "from", rather than over its area. For
+    /// an area light, this gives much less noise than sample_point() where the
+    /// light is seen at a grazing angle. The returned pdf is still with respect to
+    /// area, so the two can be used interchangeably. Returns None if "from" is (too
+    /// close to being) in the plane of the Quad.
+    ///
+    /// See "An Area-Preserving Parametrization for Spherical Rectangles" (Urena,
+    /// Fajardo and King, 2013). Like sample_point(), this assumes the basis vectors
+    /// are orthonormal.
+    pub fn sample_visible_point(&self, from: &Vec3f) -> Option<SurfaceSample> {
+        let x_axis = self.plane.u_basis;
+        let y_axis = self.plane.v_basis;
+        let mut z_axis = self.plane.normal;
+
+        // Work in a local frame with "from" at the origin, the Quad's edges along
+        // the x and y axes, and the Quad on the negative z side
+        let to_corner = self.plane.position.sub(from);
+        let mut z0 = to_corner.dot(&z_axis);
+        if z0 > 0.0 {
+            z_axis = z_axis.scale(-1.0);
+            z0 = -z0;
+        }
+        if z0 > -1e-6 {
+            return None;
+        }
+        let x0 = to_corner.dot(&x_axis);
+        let y0 = to_corner.dot(&y_axis);
+        let x1 = x0 + self.width;
+        let y1 = y0 + self.height;
+
+        // Normals of the planes through the origin and each edge, and the angles
+        // between them, which give the solid angle of the spherical rectangle
+        let edge_normal = |a: f32, b: f32, c: f32| {
+            let length = (a * a + b * b + c * c).sqrt();
+            (a / length, b / length, c / length)
+        };
+        let n0 = edge_normal(0.0, z0, -y0);
+        let n1 = edge_normal(-z0, 0.0, x1);
+        let n2 = edge_normal(0.0, -z0, y1);
+        let n3 = edge_normal(z0, 0.0, -x0);
+        let angle_between = |a: (f32, f32, f32), b: (f32, f32, f32)| {
+            (-(a.0 * b.0 + a.1 * b.1 + a.2 * b.2))
+                .clamp(-1.0, 1.0)
+                .acos()
+        };
+        let g0 = angle_between(n0, n1);
+        let g1 = angle_between(n1, n2);
+        let g2 = angle_between(n2, n3);
+        let g3 = angle_between(n3, n0);
+        let b0 = n0.2;
+        let b1 = n2.2;
+        let k = 2.0 * std::f32::consts::PI - g2 - g3;
+        let solid_angle = g0 + g1 - k;
+        if solid_angle <= 0.0 || !solid_angle.is_finite() {
+            return None;
+        }
+
+        // Pick the x coordinate so that the solid angle to the left of it is a
+        // uniformly distributed fraction of the total
+        let au = rand_f32() * solid_angle + k;
+        let fu = (au.cos() * b0 - b1) / au.sin();
+        let cu = ((1.0 / (fu * fu + b0 * b0).sqrt()) * fu.signum()).clamp(-1.0, 1.0);
+        let xu = (-(cu * z0) / (1.0 - cu * cu).max(1e-12).sqrt()).clamp(x0, x1);
+
+        // Then the y coordinate, uniformly in the solid angle along that column
+        let d = (xu * xu + z0 * z0).sqrt();
+        let h0 = y0 / (d * d + y0 * y0).sqrt();
+        let h1 = y1 / (d * d + y1 * y1).sqrt();
+        let hv = h0 + rand_f32() * (h1 - h0);
+        let yv = if hv * hv < 1.0 - 1e-6 {
+            (hv * d / (1.0 - hv * hv).sqrt()).clamp(y0, y1)
+        } else {
+            y1
+        };
+
+        let offset = x_axis
+            .scale(xu)
+            .add(&y_axis.scale(yv))
+            .add(&z_axis.scale(z0));
+        let distance_squared = offset.length_squared();
+        let cos_at_quad = (offset.dot(&z_axis) / distance_squared.sqrt()).abs();
+
+        // Convert the pdf from per unit solid angle to per unit area
+        Some(SurfaceSample {
+            position: from.add(&offset),
+            normal: self.plane.normal,
+            pdf: cos_at_quad / (solid_angle * distance_squared),
+        })
+    }
+
+    /// Moves the origin of the Quad's texture coordinates, see UvAlignment. This
+    /// only affects texturing, not the extent of the Quad.
+    pub fn with_uv_alignment(self, uv_alignment: UvAlignment) -> Quad {
+        Quad {
+            uv_alignment,
+            ..self
         }
     }
 }
@@ -151,11 +495,1213 @@ impl Surface for Quad {
                 let surf_prop = self.plane.at_point(&point);
 
                 (surf_prop.u >= 0.0 && surf_prop.u < self.width)
-                    && (surf_prop.v >= 0.0 && surf_prop.v <= self.height)
+                    && (surf_prop.v >= 0.0 && surf_prop.v < self.height)
+            })
+    }
+
+    fn at_point(&self, point_on_surface: &Vec3f) -> SurfaceProperties {
+        let surf_prop = self.plane.at_point(point_on_surface);
+
+        match self.uv_alignment {
+            UvAlignment::Corner => surf_prop,
+            UvAlignment::Center => SurfaceProperties {
+                u: surf_prop.u - self.width / 2.0,
+                v: surf_prop.v - self.height / 2.0,
+                ..surf_prop
+            },
+        }
+    }
+
+    fn sample_point(&self) -> Option<SurfaceSample> {
+        // This assumes the basis vectors are orthonormal, as at_point() does
+        let u = rand_f32() * self.width;
+        let v = rand_f32() * self.height;
+        let position = self
+            .plane
+            .position
+            .add(&self.plane.u_basis.scale(u))
+            .add(&self.plane.v_basis.scale(v));
+
+        Some(SurfaceSample {
+            position,
+            normal: self.plane.normal,
+            pdf: 1.0 / (self.width * self.height),
+        })
+    }
+
+    fn area(&self) -> Option<f32> {
+        // As with sample_point(), this assumes the basis vectors are orthonormal
+        Some(self.width * self.height)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let plane = &self.plane;
+        let across = plane.u_basis.scale(self.width);
+        let up = plane.v_basis.scale(self.height);
+        Some(Aabb::from_points(&[
+            plane.position,
+            plane.position.add(&across),
+            plane.position.add(&up),
+            plane.position.add(&across).add(&up),
+        ]))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.plane.validate()?;
+        validate_positive("Quad width", self.width)?;
+        validate_positive("Quad height", self.height)
+    }
+}
+
+impl Disk {
+    /// "normal" need not be unit length, only its direction is used
+    pub fn new(center: &Vec3f, normal: &Vec3f, radius: f32) -> Disk {
+        let (u_basis, v_basis) = orthonormal_basis(&normal.normalize());
+
+        Disk {
+            plane: Plane::new(center, &u_basis, &v_basis),
+            radius,
+        }
+    }
+}
+
+impl Surface for Disk {
+    fn intersection_with_ray(&self, ray_origin: &Vec3f, ray_direction: &Vec3f) -> Option<f32> {
+        let d = self
+            .plane
+            .intersection_with_ray(ray_origin, ray_direction)?;
+        let offset = ray_origin
+            .add(&ray_direction.scale(d))
+            .sub(&self.plane.position);
+
+        if offset.length_squared() <= self.radius * self.radius {
+            Some(d)
+        } else {
+            None
+        }
+    }
+
+    fn at_point(&self, point_on_surface: &Vec3f) -> SurfaceProperties {
+        let surf_prop = self.plane.at_point(point_on_surface);
+        let (x, y) = (surf_prop.u, surf_prop.v);
+
+        SurfaceProperties {
+            normal: surf_prop.normal,
+            u: (x * x + y * y).sqrt() / self.radius,
+            v: 0.5 + y.atan2(x) * (1.0 / (2.0 * std::f32::consts::PI)),
+        }
+    }
+
+    fn sample_point(&self) -> Option<SurfaceSample> {
+        // Taking the square root makes the density uniform over the area, rather
+        // than bunched up around the center
+        let r = self.radius * rand_f32().sqrt();
+        let phi = 2.0 * std::f32::consts::PI * rand_f32();
+        let position = self
+            .plane
+            .position
+            .add(&self.plane.u_basis.scale(r * phi.cos()))
+            .add(&self.plane.v_basis.scale(r * phi.sin()));
+
+        Some(SurfaceSample {
+            position,
+            normal: self.plane.normal,
+            pdf: 1.0 / self.area()?,
+        })
+    }
+
+    fn area(&self) -> Option<f32> {
+        Some(std::f32::consts::PI * self.radius * self.radius)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let extent = disk_extent(&self.plane.normal, self.radius);
+        Some(Aabb::new(
+            &self.plane.position.sub(&extent),
+            &self.plane.position.add(&extent),
+        ))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.plane.validate()?;
+        validate_positive("Disk radius", self.radius)
+    }
+}
+
+impl Slab {
+    /// The plane's basis vectors must be orthonormal
+    pub fn new(plane: Plane, width: f32, height: f32, thickness: f32) -> Slab {
+        Slab {
+            plane,
+            width,
+            height,
+            thickness,
+        }
+    }
+
+    /// Express a vector in terms of the slab's (u, v, normal) basis
+    fn to_local(&self, vector: &Vec3f) -> [f32; 3] {
+        [
+            vector.dot(&self.plane.u_basis),
+            vector.dot(&self.plane.v_basis),
+            vector.dot(&self.plane.normal),
+        ]
+    }
+}
+
+impl Surface for Slab {
+    fn intersection_with_ray(&self, ray_origin: &Vec3f, ray_direction: &Vec3f) -> Option<f32> {
+        slab_intersection(
+            &self.to_local(&ray_origin.sub(&self.plane.position)),
+            &self.to_local(ray_direction),
+            &[0.0, 0.0, -self.thickness],
+            &[self.width, self.height, 0.0],
+        )
+    }
+
+    fn at_point(&self, point_on_surface: &Vec3f) -> SurfaceProperties {
+        let [u, v, n] = self.to_local(&point_on_surface.sub(&self.plane.position));
+        let plane = &self.plane;
+
+        // Whichever face the point is closest to is the one it is on. The top face
+        // is mapped just like the Plane, the sides are mapped with v going down
+        // into the slab.
+        let faces = [
+            (-n, plane.normal, u, v),
+            (n + self.thickness, plane.normal.scale(-1.0), u, v),
+            (u, plane.u_basis.scale(-1.0), v, -n),
+            (self.width - u, plane.u_basis, v, -n),
+            (v, plane.v_basis.scale(-1.0), u, -n),
+            (self.height - v, plane.v_basis, u, -n),
+        ];
+        let (_, normal, u, v) = faces
+            .iter()
+            .min_by(|(d1, ..), (d2, ..)| d1.abs().total_cmp(&d2.abs()))
+            .unwrap();
+
+        SurfaceProperties {
+            normal: *normal,
+            u: *u,
+            v: *v,
+        }
+    }
+
+    fn area(&self) -> Option<f32> {
+        Some(
+            2.0 * (self.width * self.height
+                + self.width * self.thickness
+                + self.height * self.thickness),
+        )
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let plane = &self.plane;
+        let across = plane.u_basis.scale(self.width);
+        let up = plane.v_basis.scale(self.height);
+        let down = plane.normal.scale(-self.thickness);
+        let top = [
+            plane.position,
+            plane.position.add(&across),
+            plane.position.add(&up),
+            plane.position.add(&across).add(&up),
+        ];
+        Some(Aabb::from_points(
+            &[top, top.map(|corner| corner.add(&down))].concat(),
+        ))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.plane.validate()?;
+        validate_positive("Slab width", self.width)?;
+        validate_positive("Slab height", self.height)?;
+        validate_positive("Slab thickness", self.thickness)
+    }
+}
+
+impl Aabb {
+    pub fn new(min: &Vec3f, max: &Vec3f) -> Aabb {
+        Aabb {
+            min: *min,
+            max: *max,
+        }
+    }
+
+    /// Smallest box containing all of the points, which must not be empty
+    pub fn from_points(points: &[Vec3f]) -> Aabb {
+        let bounds = Aabb::new(&points[0], &points[0]);
+        points[1..].iter().fold(bounds, |bounds, point| {
+            bounds.union(&Aabb::new(point, point))
+        })
+    }
+
+    /// Smallest box containing both boxes
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: Vec3f {
+                x: self.min.x.min(other.min.x),
+                y: self.min.y.min(other.min.y),
+                z: self.min.z.min(other.min.z),
+            },
+            max: Vec3f {
+                x: self.max.x.max(other.max.x),
+                y: self.max.y.max(other.max.y),
+                z: self.max.z.max(other.max.z),
+            },
+        }
+    }
+
+    /// The box grown by "amount" in every direction
+    pub fn padded(&self, amount: f32) -> Aabb {
+        let padding = Vec3f {
+            x: amount,
+            y: amount,
+            z: amount,
+        };
+        Aabb::new(&self.min.sub(&padding), &self.max.add(&padding))
+    }
+
+    pub fn min(&self) -> Vec3f {
+        self.min
+    }
+
+    pub fn max(&self) -> Vec3f {
+        self.max
+    }
+
+    pub fn center(&self) -> Vec3f {
+        self.min.add(&self.max).scale(0.5)
+    }
+
+    pub fn corners(&self) -> [Vec3f; 8] {
+        let (min, max) = (self.min, self.max);
+        [
+            min,
+            Vec3f { x: max.x, ..min },
+            Vec3f { y: max.y, ..min },
+            Vec3f { z: max.z, ..min },
+            Vec3f { x: min.x, ..max },
+            Vec3f { y: min.y, ..max },
+            Vec3f { z: min.z, ..max },
+            max,
+        ]
+    }
+
+    pub fn contains(&self, point: &Vec3f) -> bool {
+        (self.min.x..=self.max.x).contains(&point.x)
+            && (self.min.y..=self.max.y).contains(&point.y)
+            && (self.min.z..=self.max.z).contains(&point.z)
+    }
+
+    /// Range of distances along the ray for which it is inside the box, see
+    /// slab_interval()
+    pub fn ray_interval(&self, ray_origin: &Vec3f, ray_direction: &Vec3f) -> Option<(f32, f32)> {
+        slab_interval(
+            &[ray_origin.x, ray_origin.y, ray_origin.z],
+            &[ray_direction.x, ray_direction.y, ray_direction.z],
+            &[self.min.x, self.min.y, self.min.z],
+            &[self.max.x, self.max.y, self.max.z],
+        )
+    }
+}
+
+impl Surface for Aabb {
+    fn intersection_with_ray(&self, ray_origin: &Vec3f, ray_direction: &Vec3f) -> Option<f32> {
+        slab_intersection(
+            &[ray_origin.x, ray_origin.y, ray_origin.z],
+            &[ray_direction.x, ray_direction.y, ray_direction.z],
+            &[self.min.x, self.min.y, self.min.z],
+            &[self.max.x, self.max.y, self.max.z],
+        )
+    }
+
+    fn at_point(&self, point_on_surface: &Vec3f) -> SurfaceProperties {
+        let local = point_on_surface.sub(&self.min);
+        let size = self.max.sub(&self.min);
+        let axis = |x, y, z| Vec3f { x, y, z };
+
+        // Like the Slab, whichever face the point is closest to is the one it is on
+        let faces = [
+            (local.x, axis(-1.0, 0.0, 0.0), local.y, local.z),
+            (size.x - local.x, axis(1.0, 0.0, 0.0), local.y, local.z),
+            (local.y, axis(0.0, -1.0, 0.0), local.x, local.z),
+            (size.y - local.y, axis(0.0, 1.0, 0.0), local.x, local.z),
+            (local.z, axis(0.0, 0.0, -1.0), local.x, local.y),
+            (size.z - local.z, axis(0.0, 0.0, 1.0), local.x, local.y),
+        ];
+        let (_, normal, u, v) = faces
+            .iter()
+            .min_by(|(d1, ..), (d2, ..)| d1.abs().total_cmp(&d2.abs()))
+            .unwrap();
+
+        SurfaceProperties {
+            normal: *normal,
+            u: *u,
+            v: *v,
+        }
+    }
+
+    fn area(&self) -> Option<f32> {
+        let size = self.max.sub(&self.min);
+        Some(2.0 * (size.x * size.y + size.x * size.z + size.y * size.z))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(*self)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        validate_finite("Aabb min", &self.min)?;
+        validate_finite("Aabb max", &self.max)?;
+        let size = self.max.sub(&self.min);
+        validate_positive("Aabb width", size.x)?;
+        validate_positive("Aabb depth", size.y)?;
+        validate_positive("Aabb height", size.z)
+    }
+}
+
+impl Instance {
+    /// Place a copy of "surface", first scaled by "scale" and then moved by "offset"
+    pub fn new(surface: Arc<dyn Surface>, offset: &Vec3f, scale: f32) -> Instance {
+        Instance {
+            surface,
+            offset: *offset,
+            scale,
+        }
+    }
+
+    fn to_local(&self, point: &Vec3f) -> Vec3f {
+        point.sub(&self.offset).scale(1.0 / self.scale)
+    }
+
+    fn to_world(&self, point: &Vec3f) -> Vec3f {
+        point.scale(self.scale).add(&self.offset)
+    }
+}
+
+impl Surface for Instance {
+    fn intersection_with_ray(&self, ray_origin: &Vec3f, ray_direction: &Vec3f) -> Option<f32> {
+        // Scaling the direction along with the origin means the distance along the
+        // ray is the same in local space as it is in world space.
+        self.surface.intersection_with_ray(
+            &self.to_local(ray_origin),
+            &ray_direction.scale(1.0 / self.scale),
+        )
+    }
+
+    fn at_point(&self, point_on_surface: &Vec3f) -> SurfaceProperties {
+        // Since the scaling is uniform, normals are unaffected
+        self.surface.at_point(&self.to_local(point_on_surface))
+    }
+
+    fn sample_point(&self) -> Option<SurfaceSample> {
+        self.surface.sample_point().map(|sample| SurfaceSample {
+            position: self.to_world(&sample.position),
+            normal: sample.normal,
+            pdf: sample.pdf / (self.scale * self.scale),
+        })
+    }
+
+    fn area(&self) -> Option<f32> {
+        self.surface
+            .area()
+            .map(|area| area * self.scale * self.scale)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.surface
+            .bounding_box()
+            .map(|bounds| Aabb::new(&self.to_world(&bounds.min), &self.to_world(&bounds.max)))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        validate_finite("Instance offset", &self.offset)?;
+        validate_positive("Instance scale", self.scale)?;
+        self.surface.validate()
+    }
+}
+
+impl Transformed {
+    /// Place a copy of "surface" transformed by "transform", which must be
+    /// invertible (this is checked by validate())
+    pub fn new(surface: Arc<dyn Surface>, transform: Mat4) -> Transformed {
+        Transformed {
+            surface,
+            transform,
+            inverse: transform.inverse().unwrap_or(Mat4 { m: [f32::NAN; 16] }),
+        }
+    }
+
+    /// Factor by which the transform scales areas, assuming the scaling is uniform
+    fn area_scale(&self) -> f32 {
+        let transformed = |v| self.transform.mul_vector(&v);
+        let (x, y, z) = (
+            transformed(Vec3f {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            }),
+            transformed(Vec3f {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            }),
+            transformed(Vec3f {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            }),
+        );
+        let volume_scale = x.cross(&y).dot(&z).abs();
+
+        volume_scale.powf(2.0 / 3.0)
+    }
+}
+
+impl Surface for Transformed {
+    fn intersection_with_ray(&self, ray_origin: &Vec3f, ray_direction: &Vec3f) -> Option<f32> {
+        self.surface.intersection_with_ray(
+            &self.inverse.mul_point(ray_origin),
+            &self.inverse.mul_vector(ray_direction),
+        )
+    }
+
+    fn at_point(&self, point_on_surface: &Vec3f) -> SurfaceProperties {
+        let surf_prop = self
+            .surface
+            .at_point(&self.inverse.mul_point(point_on_surface));
+
+        // Normals are transformed by the inverse transpose, so that they stay
+        // perpendicular to the surface when it is scaled non-uniformly
+        SurfaceProperties {
+            normal: self
+                .inverse
+                .transpose()
+                .mul_vector(&surf_prop.normal)
+                .normalize(),
+            ..surf_prop
+        }
+    }
+
+    fn sample_point(&self) -> Option<SurfaceSample> {
+        self.surface.sample_point().map(|sample| SurfaceSample {
+            position: self.transform.mul_point(&sample.position),
+            normal: self
+                .inverse
+                .transpose()
+                .mul_vector(&sample.normal)
+                .normalize(),
+            pdf: sample.pdf / self.area_scale(),
+        })
+    }
+
+    fn area(&self) -> Option<f32> {
+        self.surface.area().map(|area| area * self.area_scale())
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.surface.bounding_box().map(|bounds| {
+            Aabb::from_points(
+                &bounds
+                    .corners()
+                    .map(|corner| self.transform.mul_point(&corner)),
+            )
+        })
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !self.transform.is_finite() {
+            return Err(String::from("Transform is not finite"));
+        }
+        if !self.inverse.is_finite() {
+            return Err(String::from("Transform is not invertible"));
+        }
+        self.surface.validate()
+    }
+}
+
+impl Triangle {
+    pub fn new(a: &Vec3f, b: &Vec3f, c: &Vec3f) -> Triangle {
+        let cross = b.sub(a).cross(&c.sub(a));
+        let length = cross.length();
+        let normal = if length > 0.0 {
+            cross.scale(1.0 / length)
+        } else {
+            cross
+        };
+
+        Triangle {
+            a: *a,
+            b: *b,
+            c: *c,
+            normal,
+        }
+    }
+
+    /// The first corner of the triangle
+    pub fn a(&self) -> Vec3f {
+        self.a
+    }
+
+    /// Barycentric weights of "b" and "c" for a point in the triangle's plane
+    pub fn barycentric(&self, point: &Vec3f) -> (f32, f32) {
+        let edge1 = self.b.sub(&self.a);
+        let edge2 = self.c.sub(&self.a);
+        let offset = point.sub(&self.a);
+
+        let d11 = edge1.dot(&edge1);
+        let d12 = edge1.dot(&edge2);
+        let d22 = edge2.dot(&edge2);
+        let d1p = edge1.dot(&offset);
+        let d2p = edge2.dot(&offset);
+        let denom = d11 * d22 - d12 * d12;
+
+        (
+            (d22 * d1p - d12 * d2p) / denom,
+            (d11 * d2p - d12 * d1p) / denom,
+        )
+    }
+}
+
+impl Surface for Triangle {
+    fn intersection_with_ray(&self, ray_origin: &Vec3f, ray_direction: &Vec3f) -> Option<f32> {
+        // Moller-Trumbore: solve for the distance and the barycentric coordinates
+        // of the hit at once, using Cramer's rule
+        let edge1 = self.b.sub(&self.a);
+        let edge2 = self.c.sub(&self.a);
+        let p = ray_direction.cross(&edge2);
+        let det = edge1.dot(&p);
+
+        // Either the ray is parallel to the triangle, or the triangle is degenerate
+        // (in which case the determinant is zero for every ray)
+        if det.abs() < 1e-12 {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+
+        let offset = ray_origin.sub(&self.a);
+        let u = offset.dot(&p) * inv_det;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+
+        let q = offset.cross(&edge1);
+        let v = ray_direction.dot(&q) * inv_det;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+
+        let dist = edge2.dot(&q) * inv_det;
+        if dist > 0.0 {
+            Some(dist)
+        } else {
+            None
+        }
+    }
+
+    fn at_point(&self, point_on_surface: &Vec3f) -> SurfaceProperties {
+        let (u, v) = self.barycentric(point_on_surface);
+
+        SurfaceProperties {
+            normal: self.normal,
+            u: u.clamp(0.0, 1.0),
+            v: v.clamp(0.0, 1.0),
+        }
+    }
+
+    fn sample_point(&self) -> Option<SurfaceSample> {
+        let area = self.area()?;
+        if area <= 0.0 {
+            return None;
+        }
+
+        // Folding the unit square in half along its diagonal gives a uniform point
+        // in the triangle
+        let (mut u, mut v) = (rand_f32(), rand_f32());
+        if u + v > 1.0 {
+            u = 1.0 - u;
+            v = 1.0 - v;
+        }
+        let position = self
+            .a
+            .add(&self.b.sub(&self.a).scale(u))
+            .add(&self.c.sub(&self.a).scale(v));
+
+        Some(SurfaceSample {
+            position,
+            normal: self.normal,
+            pdf: 1.0 / area,
+        })
+    }
+
+    fn area(&self) -> Option<f32> {
+        let cross = self.b.sub(&self.a).cross(&self.c.sub(&self.a));
+        Some(0.5 * cross.length())
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::from_points(&[self.a, self.b, self.c]))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        validate_finite("Triangle corner a", &self.a)?;
+        validate_finite("Triangle corner b", &self.b)?;
+        validate_finite("Triangle corner c", &self.c)?;
+        if self.normal.dot(&self.normal) > 0.0 {
+            Ok(())
+        } else {
+            Err(String::from("Triangle has zero area"))
+        }
+    }
+}
+
+impl Cylinder {
+    /// "axis" need not be unit length, only its direction is used
+    pub fn new(base: &Vec3f, axis: &Vec3f, radius: f32, height: f32) -> Cylinder {
+        Cylinder {
+            base: *base,
+            axis: axis.normalize(),
+            radius,
+            height,
+        }
+    }
+
+    /// Splits a vector into its component along the axis, and the remainder
+    /// perpendicular to the axis
+    fn split(&self, vector: &Vec3f) -> (f32, Vec3f) {
+        let along = vector.dot(&self.axis);
+        (along, vector.sub(&self.axis.scale(along)))
+    }
+}
+
+impl Surface for Cylinder {
+    fn intersection_with_ray(&self, ray_origin: &Vec3f, ray_direction: &Vec3f) -> Option<f32> {
+        let (origin_along, origin_radial) = self.split(&ray_origin.sub(&self.base));
+        let (direction_along, direction_radial) = self.split(ray_direction);
+        let radius_squared = self.radius * self.radius;
+        let height_at = |t: f32| origin_along + t * direction_along;
+
+        let mut candidates = [None; 4];
+
+        // The curved side is the infinite cylinder, restricted to the height range.
+        // A ray parallel to the axis can't hit it (a = 0), and only the caps matter.
+        let a = direction_radial.dot(&direction_radial);
+        if a > 0.0 {
+            let b = 2.0 * direction_radial.dot(&origin_radial);
+            let c = origin_radial.dot(&origin_radial) - radius_squared;
+            if let Some((t1, t2)) = solve_quadratic(a, b, c, false) {
+                candidates[0] = Some(t1);
+                candidates[1] = Some(t2);
+            }
+        }
+        let on_side = |t: f32| (0.0..=self.height).contains(&height_at(t));
+
+        // The caps are disks at either end, which rays perpendicular to the axis
+        // can't hit
+        if direction_along != 0.0 {
+            candidates[2] = Some(-origin_along / direction_along);
+            candidates[3] = Some((self.height - origin_along) / direction_along);
+        }
+        let on_cap = |t: f32| {
+            let radial = origin_radial.add(&direction_radial.scale(t));
+            radial.length_squared() <= radius_squared
+        };
+
+        candidates
+            .iter()
+            .enumerate()
+            .filter_map(|(i, t)| {
+                t.filter(|&t| t > 0.0 && if i < 2 { on_side(t) } else { on_cap(t) })
             })
+            .min_by(|t1, t2| t1.total_cmp(t2))
     }
 
     fn at_point(&self, point_on_surface: &Vec3f) -> SurfaceProperties {
-        self.plane.at_point(point_on_surface)
+        let (along, radial) = self.split(&point_on_surface.sub(&self.base));
+        let radial_distance = radial.length();
+        let (tangent, bitangent) = orthonormal_basis(&self.axis);
+        let u = 0.5
+            + radial.dot(&bitangent).atan2(radial.dot(&tangent))
+                * (1.0 / (2.0 * std::f32::consts::PI));
+
+        // Like the Slab, whichever face the point is closest to is the one it is on
+        let faces = [
+            (
+                radial_distance - self.radius,
+                radial.scale(1.0 / radial_distance),
+                along / self.height,
+            ),
+            (along, self.axis.scale(-1.0), 0.0),
+            (self.height - along, self.axis, 1.0),
+        ];
+        let (_, normal, v) = faces
+            .iter()
+            .min_by(|(d1, ..), (d2, ..)| d1.abs().total_cmp(&d2.abs()))
+            .unwrap();
+
+        SurfaceProperties {
+            normal: *normal,
+            u,
+            v: v.clamp(0.0, 1.0),
+        }
+    }
+
+    fn area(&self) -> Option<f32> {
+        let circumference = 2.0 * std::f32::consts::PI * self.radius;
+        Some(circumference * (self.height + self.radius))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let extent = disk_extent(&self.axis, self.radius);
+        let top = self.base.add(&self.axis.scale(self.height));
+        Some(Aabb::from_points(&[
+            self.base.sub(&extent),
+            self.base.add(&extent),
+            top.sub(&extent),
+            top.add(&extent),
+        ]))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        validate_finite("Cylinder base", &self.base)?;
+        validate_finite("Cylinder axis", &self.axis)?;
+        validate_positive("Cylinder radius", self.radius)?;
+        validate_positive("Cylinder height", self.height)
+    }
+}
+
+impl Torus {
+    /// "axis" need not be unit length, only its direction is used
+    pub fn new(center: &Vec3f, axis: &Vec3f, major_radius: f32, minor_radius: f32) -> Torus {
+        Torus {
+            center: *center,
+            axis: axis.normalize(),
+            major_radius,
+            minor_radius,
+        }
+    }
+
+    /// Express a vector in a basis with the axis as z
+    fn local_coords(&self, vector: &Vec3f) -> [f64; 3] {
+        let (tangent, bitangent) = orthonormal_basis(&self.axis);
+        [
+            vector.dot(&tangent) as f64,
+            vector.dot(&bitangent) as f64,
+            vector.dot(&self.axis) as f64,
+        ]
+    }
+}
+
+impl Surface for Torus {
+    fn intersection_with_ray(&self, ray_origin: &Vec3f, ray_direction: &Vec3f) -> Option<f32> {
+        let origin_minus_center = ray_origin.sub(&self.center);
+
+        // Most rays miss, so first check against a bounding sphere, which is much
+        // cheaper than solving the quartic
+        let bound = self.major_radius + self.minor_radius;
+        let a = ray_direction.dot(ray_direction);
+        let b = 2.0 * ray_direction.dot(&origin_minus_center);
+        let c = origin_minus_center.dot(&origin_minus_center) - bound * bound;
+        match solve_quadratic(a, b, c, true) {
+            Some((t1, t2)) if t1.max(t2) > 0.0 => (),
+            _ => return None,
+        }
+
+        // Points on the torus satisfy (|p|^2 + R^2 - r^2)^2 = 4R^2(x^2 + y^2) in
+        // local coordinates. Substituting p = o + td, with d normalized so that the
+        // quartic is monic, gives the coefficients below. f64 is used throughout,
+        // since the coefficients involve fourth powers of the distances.
+        let length = (a as f64).sqrt();
+        let [ox, oy, oz] = self.local_coords(&origin_minus_center);
+        let [dx, dy, dz] = self.local_coords(ray_direction).map(|x| x / length);
+        let major_squared = (self.major_radius as f64).powi(2);
+        let minor_squared = (self.minor_radius as f64).powi(2);
+
+        let o_dot_d = ox * dx + oy * dy + oz * dz;
+        let e = (ox * ox + oy * oy + oz * oz) - major_squared - minor_squared;
+        let roots = solve_quartic(
+            4.0 * o_dot_d,
+            2.0 * e + 4.0 * o_dot_d * o_dot_d + 4.0 * major_squared * dz * dz,
+            4.0 * o_dot_d * e + 8.0 * major_squared * oz * dz,
+            e * e - 4.0 * major_squared * (minor_squared - oz * oz),
+        );
+
+        roots
+            .into_iter()
+            .filter(|&t| t > 0.0)
+            .min_by(|t1, t2| t1.total_cmp(t2))
+            .map(|t| (t / length) as f32)
+    }
+
+    fn at_point(&self, point_on_surface: &Vec3f) -> SurfaceProperties {
+        let offset = point_on_surface.sub(&self.center);
+        let height = offset.dot(&self.axis);
+        let radial = offset.sub(&self.axis.scale(height));
+        let radial_distance = radial.length();
+
+        // The normal points away from the nearest point on the circle through the
+        // middle of the tube
+        let tube_center = radial.scale(self.major_radius / radial_distance);
+        let normal = offset.sub(&tube_center).normalize();
+
+        let (tangent, bitangent) = orthonormal_basis(&self.axis);
+        let u = 0.5
+            + radial.dot(&bitangent).atan2(radial.dot(&tangent))
+                * (1.0 / (2.0 * std::f32::consts::PI));
+        let v = 0.5
+            + height.atan2(radial_distance - self.major_radius)
+                * (1.0 / (2.0 * std::f32::consts::PI));
+
+        SurfaceProperties { normal, u, v }
+    }
+
+    fn area(&self) -> Option<f32> {
+        Some(4.0 * std::f32::consts::PI.powi(2) * self.major_radius * self.minor_radius)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        // The circle through the middle of the tube, widened by the tube's radius
+        let circle = disk_extent(&self.axis, self.major_radius);
+        let extent = circle.add(&Vec3f {
+            x: self.minor_radius,
+            y: self.minor_radius,
+            z: self.minor_radius,
+        });
+        Some(Aabb::new(
+            &self.center.sub(&extent),
+            &self.center.add(&extent),
+        ))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        validate_finite("Torus center", &self.center)?;
+        validate_finite("Torus axis", &self.axis)?;
+        validate_positive("Torus major radius", self.major_radius)?;
+        validate_positive("Torus minor radius", self.minor_radius)
+    }
+}
+
+/// Intersects a ray with an axis aligned box between the corners "min" and "max"
+/// using the slab method, with vectors given as arrays of (x, y, z) components.
+/// Returns the range of distances along the ray (which may be negative, ie
+/// behind the origin) for which it is inside the box.
+pub fn slab_interval(
+    origin: &[f32; 3],
+    direction: &[f32; 3],
+    min: &[f32; 3],
+    max: &[f32; 3],
+) -> Option<(f32, f32)> {
+    let mut t_near = f32::NEG_INFINITY;
+    let mut t_far = f32::INFINITY;
+
+    for axis in 0..3 {
+        if direction[axis] == 0.0 {
+            // Parallel to this pair of faces, so either always between them or never
+            if origin[axis] < min[axis] || origin[axis] > max[axis] {
+                return None;
+            }
+        } else {
+            let t1 = (min[axis] - origin[axis]) / direction[axis];
+            let t2 = (max[axis] - origin[axis]) / direction[axis];
+
+            t_near = t_near.max(t1.min(t2));
+            t_far = t_far.min(t1.max(t2));
+        }
+    }
+
+    if t_near > t_far {
+        None
+    } else {
+        Some((t_near, t_far))
+    }
+}
+
+/// Like slab_interval(), but returns the distance to the nearest intersection in
+/// front of the ray origin, which will be on the far side of the box if the
+/// origin is inside it.
+fn slab_intersection(
+    origin: &[f32; 3],
+    direction: &[f32; 3],
+    min: &[f32; 3],
+    max: &[f32; 3],
+) -> Option<f32> {
+    match slab_interval(origin, direction, min, max) {
+        Some((_, t_far)) if t_far <= 0.0 => None,
+        Some((t_near, _)) if t_near > 0.0 => Some(t_near),
+        Some((_, t_far)) => Some(t_far),
+        None => None,
+    }
+}
+
+/// Half the size of the bounding box of a disk (or circle) with the given unit
+/// normal and radius, along each axis
+fn disk_extent(normal: &Vec3f, radius: f32) -> Vec3f {
+    let extent = |n: f32| radius * (1.0 - n * n).max(0.0).sqrt();
+    Vec3f {
+        x: extent(normal.x),
+        y: extent(normal.y),
+        z: extent(normal.z),
+    }
+}
+
+/// Returns an error unless "value" is finite and greater than zero
+fn validate_positive(name: &str, value: f32) -> Result<(), String> {
+    if value.is_finite() && value > 0.0 {
+        Ok(())
+    } else {
+        Err(format!("{} must be positive, but is {}", name, value))
+    }
+}
+
+fn validate_finite(name: &str, vector: &Vec3f) -> Result<(), String> {
+    if vector.is_finite() {
+        Ok(())
+    } else {
+        Err(format!("{} is not finite: {:?}", name, vector))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vec3f(x: f32, y: f32, z: f32) -> Vec3f {
+        Vec3f { x, y, z }
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-4,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn sphere_distances_are_in_world_units() {
+        let unit_sphere = Sphere::new(&vec3f(0.0, 0.0, 0.0), 1.0);
+        let forwards = vec3f(0.0, 0.0, 1.0);
+        let t = unit_sphere.intersection_with_ray(&vec3f(0.0, 0.0, -5.0), &forwards);
+        assert_close(t.unwrap(), 4.0);
+        // From the inside, the far side is hit
+        let t = unit_sphere.intersection_with_ray(&vec3f(0.0, 0.0, 0.0), &forwards);
+        assert_close(t.unwrap(), 1.0);
+        // At an angle, for a direction which isn't along an axis
+        let diagonal = vec3f(1.0, 1.0, 0.0).normalize();
+        let t = unit_sphere.intersection_with_ray(&vec3f(-3.0, -3.0, 0.0), &diagonal);
+        assert_close(t.unwrap(), 18.0f32.sqrt() - 1.0);
+    }
+
+    #[test]
+    fn scaled_sphere_distances_are_in_world_units() {
+        let unit_sphere: Arc<dyn Surface> = Arc::new(Sphere::new(&vec3f(0.0, 0.0, 0.0), 1.0));
+        let forwards = vec3f(0.0, 0.0, 1.0);
+
+        let instance = Instance::new(Arc::clone(&unit_sphere), &vec3f(10.0, 0.0, 0.0), 3.0);
+        let t = instance.intersection_with_ray(&vec3f(10.0, 0.0, -10.0), &forwards);
+        assert_close(t.unwrap(), 7.0);
+
+        let transform =
+            Mat4::translation(&vec3f(0.0, 5.0, 0.0)).mul_mat(&Mat4::scaling(&vec3f(2.0, 2.0, 2.0)));
+        let transformed = Transformed::new(unit_sphere, transform);
+        let t = transformed.intersection_with_ray(&vec3f(0.0, 5.0, -10.0), &forwards);
+        assert_close(t.unwrap(), 8.0);
+    }
+
+    #[test]
+    fn instances_of_one_sphere_are_hit_where_they_are_placed() {
+        let sphere: Arc<dyn Surface> = Arc::new(Sphere::new(&vec3f(0.0, 0.0, 0.0), 1.0));
+        let left = Instance::new(Arc::clone(&sphere), &vec3f(-5.0, 0.0, 0.0), 1.0);
+        let right = Instance::new(sphere, &vec3f(5.0, 0.0, 0.0), 2.0);
+        let down = vec3f(0.0, 0.0, -1.0);
+
+        for (instance, x, top) in [(&left, -5.0, 1.0), (&right, 5.0, 2.0)] {
+            let t = instance
+                .intersection_with_ray(&vec3f(x, 0.0, 10.0), &down)
+                .unwrap();
+            assert_close(t, 10.0 - top);
+            let normal = instance.at_point(&vec3f(x, 0.0, top)).normal;
+            assert_close(normal.z, 1.0);
+        }
+        // Nothing is left at the shared sphere's own position
+        assert_eq!(
+            left.intersection_with_ray(&vec3f(0.0, 0.0, 10.0), &down),
+            None
+        );
+        assert_eq!(
+            right.intersection_with_ray(&vec3f(0.0, 0.0, 10.0), &down),
+            None
+        );
+    }
+
+    #[test]
+    fn center_aligned_quad_has_uv_origin_in_the_middle() {
+        let plane = Plane::new(
+            &vec3f(1.0, 2.0, 3.0),
+            &vec3f(1.0, 0.0, 0.0),
+            &vec3f(0.0, 1.0, 0.0),
+        );
+        let quad = Quad::new(plane, 4.0, 2.0).with_uv_alignment(UvAlignment::Center);
+
+        let middle = quad.at_point(&quad.center());
+        assert_close(middle.u, 0.0);
+        assert_close(middle.v, 0.0);
+        let corner = quad.at_point(&vec3f(1.0, 2.0, 3.0));
+        assert_close(corner.u, -2.0);
+        assert_close(corner.v, -1.0);
+    }
+
+    #[test]
+    fn plane_uv_origin_is_projected_into_the_plane() {
+        let plane = Plane::new(
+            &vec3f(0.0, 0.0, 0.0),
+            &vec3f(1.0, 0.0, 0.0),
+            &vec3f(0.0, 1.0, 0.0),
+        )
+        .with_uv_origin(&vec3f(3.0, -1.0, 7.0));
+
+        let origin = plane.at_point(&vec3f(3.0, -1.0, 0.0));
+        assert_close(origin.u, 0.0);
+        assert_close(origin.v, 0.0);
+        assert_eq!(
+            plane.intersection_with_ray(&vec3f(0.0, 0.0, 5.0), &vec3f(0.0, 0.0, -1.0)),
+            Some(5.0)
+        );
+    }
+
+    /// Torus around the origin in the x-y plane, with the tube 2.0 from the axis
+    fn flat_torus() -> Torus {
+        Torus::new(&vec3f(0.0, 0.0, 0.0), &vec3f(0.0, 0.0, 1.0), 2.0, 0.5)
+    }
+
+    #[test]
+    fn torus_ray_through_hole_misses() {
+        let down = vec3f(0.0, 0.0, -1.0);
+        assert_eq!(
+            flat_torus().intersection_with_ray(&vec3f(0.0, 0.0, 5.0), &down),
+            None
+        );
+    }
+
+    #[test]
+    fn torus_ray_at_tube_hits() {
+        let torus = flat_torus();
+        let t = torus
+            .intersection_with_ray(&vec3f(-5.0, 0.0, 0.0), &vec3f(1.0, 0.0, 0.0))
+            .unwrap();
+        assert_close(t, 2.5);
+
+        // Straight down onto the top of the tube
+        let t = torus
+            .intersection_with_ray(&vec3f(0.0, 2.0, 5.0), &vec3f(0.0, 0.0, -1.0))
+            .unwrap();
+        assert_close(t, 4.5);
+    }
+
+    #[test]
+    fn torus_ray_from_inside_tube_ignores_roots_behind() {
+        // From the middle of the tube, two of the four roots are behind the origin
+        // and the nearest one in front is where the ray leaves the tube
+        let torus = flat_torus();
+        let t = torus
+            .intersection_with_ray(&vec3f(2.0, 0.0, 0.0), &vec3f(1.0, 0.0, 0.0))
+            .unwrap();
+        assert_close(t, 0.5);
+        let t = torus
+            .intersection_with_ray(&vec3f(2.0, 0.0, 0.0), &vec3f(-1.0, 0.0, 0.0))
+            .unwrap();
+        assert_close(t, 0.5);
+    }
+
+    #[test]
+    fn transformed_quad_rotated_about_x() {
+        // A 2x2 Quad in the x-y plane facing +z, rotated a quarter turn about x so
+        // that it stands in the x-z plane facing -y
+        let quad = Quad::new(
+            Plane::new(
+                &vec3f(0.0, 0.0, 0.0),
+                &vec3f(1.0, 0.0, 0.0),
+                &vec3f(0.0, 1.0, 0.0),
+            ),
+            2.0,
+            2.0,
+        );
+        let rotated = Transformed::new(
+            Arc::new(quad),
+            Mat4::rotation(&vec3f(1.0, 0.0, 0.0), std::f32::consts::FRAC_PI_2),
+        );
+
+        let origin = vec3f(1.0, -5.0, 1.0);
+        let direction = vec3f(0.0, 1.0, 0.0);
+        let t = rotated.intersection_with_ray(&origin, &direction).unwrap();
+        assert_close(t, 5.0);
+
+        let normal = rotated.at_point(&(origin + direction * t)).normal;
+        assert_close(normal.x, 0.0);
+        assert_close(normal.y, -1.0);
+        assert_close(normal.z, 0.0);
+
+        // It no longer covers where it was before the rotation
+        let down = vec3f(0.0, 0.0, -1.0);
+        assert_eq!(
+            rotated.intersection_with_ray(&vec3f(1.0, 1.0, 5.0), &down),
+            None
+        );
+    }
+
+    #[test]
+    fn triangle_hit_by_ray_along_x() {
+        // In the y-z plane at x = 3.0
+        let triangle = Triangle::new(
+            &vec3f(3.0, 0.0, 0.0),
+            &vec3f(3.0, 1.0, 0.0),
+            &vec3f(3.0, 0.0, 1.0),
+        );
+        let along_x = vec3f(1.0, 0.0, 0.0);
+
+        let t = triangle
+            .intersection_with_ray(&vec3f(0.0, 0.25, 0.25), &along_x)
+            .unwrap();
+        assert_close(t, 3.0);
+
+        // Beyond the hypotenuse, and behind the ray
+        assert_eq!(
+            triangle.intersection_with_ray(&vec3f(0.0, 0.75, 0.75), &along_x),
+            None
+        );
+        assert_eq!(
+            triangle.intersection_with_ray(&vec3f(5.0, 0.25, 0.25), &along_x),
+            None
+        );
+    }
+
+    #[test]
+    fn triangle_normal_follows_winding_order() {
+        let (a, b, c) = (
+            vec3f(3.0, 0.0, 0.0),
+            vec3f(3.0, 1.0, 0.0),
+            vec3f(3.0, 0.0, 1.0),
+        );
+        let point = vec3f(3.0, 0.25, 0.25);
+
+        let normal = Triangle::new(&a, &b, &c).at_point(&point).normal;
+        assert_close(normal.x, 1.0);
+        let reversed = Triangle::new(&a, &c, &b).at_point(&point).normal;
+        assert_close(reversed.x, -1.0);
+    }
+
+    #[test]
+    fn degenerate_triangle_is_never_hit() {
+        // All three corners on a line
+        let triangle = Triangle::new(
+            &vec3f(3.0, 0.0, 0.0),
+            &vec3f(3.0, 1.0, 1.0),
+            &vec3f(3.0, 2.0, 2.0),
+        );
+        assert_eq!(
+            triangle.intersection_with_ray(&vec3f(0.0, 1.0, 1.0), &vec3f(1.0, 0.0, 0.0)),
+            None
+        );
+        assert!(triangle.validate().is_err());
     }
 }
@@ -1,17 +1,40 @@
+use std::io;
+
 use num_complex::Complex;
 
-use crate::math::{linear_interpolation, mandelbrot_escape_time, Rgb};
+use crate::math::{linear_interpolation, mandelbrot_escape_time, Rgb, Vec3f};
+use crate::ppm;
 use crate::scene::{Camera, Scene};
+use crate::stats::{self, RayKind};
+use crate::util::{counter_rand_u64, Array2D, EdgeMode};
 
-/// A Texture maps a (u, v) coordinate on a Surface into a color
+/// A Texture maps a point on a Surface into a color
 pub trait Texture: Sync {
-    fn color(&self, scene: &Scene, max_depth: i32, u: f32, v: f32) -> Rgb;
+    fn color(&self, scene: &Scene, max_depth: i32, point: &TexturePoint) -> Rgb;
+}
+
+/// The point on a Surface that a Texture is being looked up at
+#[derive(Debug, Copy, Clone)]
+pub struct TexturePoint {
+    /// Surface coordinates, which wrapping Textures may transform before passing
+    /// them on
+    pub u: f32,
+    pub v: f32,
+    /// Position of the point in the world
+    pub position: Vec3f,
+    /// Approximate width of the area around the point covered by the pixel being
+    /// rendered, in (u, v) units, or 0.0 if unknown. Surfaces pass this on in world
+    /// units, so it is only accurate for those whose (u, v) coordinates are
+    /// distances, like Plane and Quad.
+    pub footprint: f32,
 }
 
 /// Infinite checkerboard pattern, alternating between two "sub Textures"
 pub struct Checkerboard {
     texture1: Box<dyn Texture>,
     texture2: Box<dyn Texture>,
+    u_squares_per_unit: f32,
+    v_squares_per_unit: f32,
 }
 
 /// Offsets and scales the (u, v) coordinates of another Texture
@@ -23,56 +46,238 @@ pub struct CoordinateTransform {
     pub v_scale: f32,
 }
 
+/// Another Texture with its colors turned to grays of the same luminance, eg to
+/// use a colored image as a mask
+pub struct Grayscale {
+    pub texture: Box<dyn Texture>,
+}
+
+/// Moves another Texture across the (u, v) plane over time, at a constant velocity
+pub struct Scroll {
+    pub texture: Box<dyn Texture>,
+    pub u_velocity: f32,
+    pub v_velocity: f32,
+}
+
 /// Texture representing the Mandelbrot set
 pub struct MandelbrotSet {
     pub colormap: Vec<Rgb>,
+    /// Evaluate a subsamples by subsamples grid of points for each lookup, and
+    /// average the results. 1 disables this internal antialiasing.
+    pub subsamples: usize,
+    /// Width and height of the subsample grid, in (u, v) units
+    pub subsample_spacing: f32,
+    /// Draw dark lines where the escape time crosses whole numbers of iterations,
+    /// giving a contour map of the set
+    pub contour_lines: bool,
+    /// Width of the contour lines, in iterations of escape time
+    pub contour_thickness: f32,
 }
 
 /// Texture used to recursively cast a ray into the same scene
 pub struct Portal {
     pub camera: Camera,
+    /// Cast a subsamples by subsamples grid of rays for each lookup, and average
+    /// the results. 1 disables this internal antialiasing.
+    pub subsamples: usize,
+    /// Width and height of the subsample grid, in (u, v) units
+    pub subsample_spacing: f32,
+}
+
+/// Ways of deriving (u, v) coordinates from a position in the world, used by
+/// Projection. Each is relative to the Projection's center, with z as the axis.
+#[derive(Debug, Copy, Clone)]
+pub enum ProjectionMode {
+    /// Straight down the z axis, u and v are the x and y offsets from the center
+    Planar,
+    /// Wrapped around the z axis, u is the fraction of a turn around the axis and v
+    /// is the height above the center
+    Cylindrical,
+    /// Wrapped around the center, u is the fraction of a turn around the z axis
+    /// and v runs from 0.0 at the top to 1.0 at the bottom, as with a Sphere
+    Spherical,
+}
+
+/// Looks up another Texture using coordinates derived from the world position of
+/// the point, ignoring the Surface's own (u, v) coordinates
+pub struct Projection {
+    pub texture: Box<dyn Texture>,
+    pub mode: ProjectionMode,
+    pub center: Vec3f,
+}
+
+/// A picture, stretched over the unit square of (u, v) coordinates. u runs across
+/// the picture from left to right, and v runs down it from top to bottom.
+pub struct ImageTexture {
+    pub image: Array2D<Rgb>,
+    pub filter: ImageFilter,
+    /// Whether the picture repeats beyond the unit square, or its edges extend
+    pub edges: EdgeMode,
+}
+
+/// How an ImageTexture finds the color between the centers of its pixels
+#[derive(Debug, Copy, Clone)]
+pub enum ImageFilter {
+    /// The color of the pixel the point lies in, which looks blocky when the
+    /// picture is magnified
+    Nearest,
+    /// Blends the four nearest pixels, which gives smooth gradients between them
+    Bilinear,
+}
+
+/// Smoothly varying random pattern (Perlin gradient noise), blending between two
+/// "sub Textures". Useful for clouds, marble, terrain and other organic looking
+/// patterns.
+pub struct Noise {
+    pub texture1: Box<dyn Texture>,
+    pub texture2: Box<dyn Texture>,
+    /// Number of features per unit of (u, v), for the first octave
+    pub frequency: f32,
+    /// Number of layers of noise which are summed, each at twice the frequency
+    /// and half the amplitude of the last, adding progressively finer detail
+    /// (fractal Brownian motion). 1 gives plain noise.
+    pub octaves: u32,
+    /// Shuffled 0..=255, which selects the pseudo-random gradient at each lattice
+    /// point. It is repeated, so it can be indexed by the sum of two entries.
+    permutation: Vec<u8>,
+}
+
+/// Blends from one "sub Texture" to another over the unit square of (u, v)
+/// coordinates, keeping the end colors beyond it. Handy for skies and backdrops.
+pub struct Gradient {
+    /// Texture at the start of the gradient (eg u = 0.0)
+    pub texture1: Box<dyn Texture>,
+    /// Texture at the end of the gradient (eg u = 1.0)
+    pub texture2: Box<dyn Texture>,
+    pub direction: GradientDirection,
+}
+
+/// Which way a Gradient runs across the (u, v) plane
+#[derive(Debug, Copy, Clone)]
+pub enum GradientDirection {
+    /// From u = 0.0 to u = 1.0
+    U,
+    /// From v = 0.0 to v = 1.0
+    V,
+    /// From (0.0, 0.0) to (1.0, 1.0)
+    Diagonal,
 }
 
 /// A color can be used as a Texture
 impl Texture for Rgb {
-    fn color(&self, _scene: &Scene, _current_depth: i32, _u: f32, _v: f32) -> Rgb {
+    fn color(&self, _scene: &Scene, _current_depth: i32, _point: &TexturePoint) -> Rgb {
         *self
     }
 }
 
 impl Checkerboard {
+    /// Checkerboard with squares that are 1.0 units across in (u, v) space
     pub fn new(texture1: Box<dyn Texture>, texture2: Box<dyn Texture>) -> Checkerboard {
-        Checkerboard { texture1, texture2 }
+        Checkerboard {
+            texture1,
+            texture2,
+            u_squares_per_unit: 1.0,
+            v_squares_per_unit: 1.0,
+        }
+    }
+
+    /// Checkerboard with exactly squares_u by squares_v squares across a surface
+    /// whose (u, v) coordinates range over [0, u_extent) by [0, v_extent), such
+    /// as a Quad of the same width and height.
+    pub fn with_square_count(
+        texture1: Box<dyn Texture>,
+        texture2: Box<dyn Texture>,
+        squares_u: u32,
+        squares_v: u32,
+        u_extent: f32,
+        v_extent: f32,
+    ) -> Checkerboard {
+        Checkerboard {
+            texture1,
+            texture2,
+            u_squares_per_unit: (squares_u as f32) / u_extent,
+            v_squares_per_unit: (squares_v as f32) / v_extent,
+        }
+    }
+
+    /// Whether the point (u, v), measured in squares, is in a square showing the
+    /// first texture. That's where the sum of the square coordinates is even, ie
+    /// where their parities match. Taking the parities separately with
+    /// rem_euclid() stays correct for negative and huge coordinates, which don't
+    /// fit in an integer (and where adding the floats could round).
+    fn is_first_square(u: f32, v: f32) -> bool {
+        u.floor().rem_euclid(2.0) == v.floor().rem_euclid(2.0)
     }
 }
 
 impl Texture for Checkerboard {
-    fn color(&self, scene: &Scene, max_depth: i32, u: f32, v: f32) -> Rgb {
-        let square_number = (u.floor() + v.floor()) as i32;
+    fn color(&self, scene: &Scene, max_depth: i32, point: &TexturePoint) -> Rgb {
+        let u = point.u * self.u_squares_per_unit;
+        let v = point.v * self.v_squares_per_unit;
         let square_u = u - u.floor();
         let square_v = v - v.floor();
+        let square_point = TexturePoint {
+            u: square_u,
+            v: square_v,
+            footprint: point.footprint * self.u_squares_per_unit.max(self.v_squares_per_unit),
+            ..*point
+        };
 
-        match (square_number + 1_000_000) % 2 {
-            0 => self.texture1.color(scene, max_depth, square_u, square_v),
-            1 => self.texture2.color(scene, max_depth, square_u, square_v),
-            _ => unreachable!(),
+        if Self::is_first_square(u, v) {
+            self.texture1.color(scene, max_depth, &square_point)
+        } else {
+            self.texture2.color(scene, max_depth, &square_point)
         }
     }
 }
 
 impl Texture for CoordinateTransform {
-    fn color(&self, scene: &Scene, max_depth: i32, u: f32, v: f32) -> Rgb {
-        let u2 = self.u_scale * (self.u_offset + u);
-        let v2 = self.v_scale * (self.v_offset + v);
+    fn color(&self, scene: &Scene, max_depth: i32, point: &TexturePoint) -> Rgb {
+        let transformed = TexturePoint {
+            u: self.u_scale * (self.u_offset + point.u),
+            v: self.v_scale * (self.v_offset + point.v),
+            footprint: point.footprint * self.u_scale.abs().max(self.v_scale.abs()),
+            ..*point
+        };
 
-        self.texture.color(scene, max_depth, u2, v2)
+        self.texture.color(scene, max_depth, &transformed)
     }
 }
 
-impl Texture for MandelbrotSet {
-    fn color(&self, _scene: &Scene, _max_depth: i32, u: f32, v: f32) -> Rgb {
+/// Averages the colors returned by "f" over a grid_size by grid_size grid of
+/// (u, v) coordinates centered on the point and spanning "spacing" units in each
+/// direction.
+fn supersample(
+    grid_size: usize,
+    spacing: f32,
+    point: &TexturePoint,
+    f: impl Fn(f32, f32) -> Rgb,
+) -> Rgb {
+    let (u, v) = (point.u, point.v);
+    if grid_size <= 1 {
+        return f(u, v);
+    }
+
+    let step = spacing / (grid_size as f32);
+    let start = (step - spacing) / 2.0;
+
+    (0..grid_size)
+        .flat_map(|i| (0..grid_size).map(move |j| (i, j)))
+        .map(|(i, j)| f(u + start + step * (i as f32), v + start + step * (j as f32)))
+        .fold(Rgb::BLACK, |acc, color| acc.add(&color))
+        .scale(1.0 / ((grid_size * grid_size) as f32))
+}
+
+impl MandelbrotSet {
+    /// Returns true if the escape time is within half a line width of a whole number
+    fn on_contour_line(&self, escape_time: f32) -> bool {
+        (escape_time - escape_time.round()).abs() < self.contour_thickness / 2.0
+    }
+
+    fn color_at_point(&self, u: f32, v: f32) -> Rgb {
         let escape_time = mandelbrot_escape_time(Complex::new(u, v));
         match escape_time {
+            Some(t) if self.contour_lines && self.on_contour_line(t) => Rgb::BLACK,
             Some(t) => {
                 let index = t * 0.25;
                 linear_interpolation(&self.colormap, index).srgb_to_linear()
@@ -82,12 +287,222 @@ impl Texture for MandelbrotSet {
     }
 }
 
+impl ProjectionMode {
+    /// Returns the (u, v) coordinates of a position relative to the projection center
+    pub fn project(&self, offset: &Vec3f) -> (f32, f32) {
+        let turns_around_axis = || 0.5 + offset.y.atan2(offset.x) / (2.0 * std::f32::consts::PI);
+
+        match self {
+            ProjectionMode::Planar => (offset.x, offset.y),
+            ProjectionMode::Cylindrical => (turns_around_axis(), offset.z),
+            ProjectionMode::Spherical => {
+                let elevation = offset.normalize().z.asin();
+                (turns_around_axis(), 0.5 - elevation / std::f32::consts::PI)
+            }
+        }
+    }
+}
+
+impl Texture for Projection {
+    fn color(&self, scene: &Scene, max_depth: i32, point: &TexturePoint) -> Rgb {
+        let (u, v) = self.mode.project(&point.position.sub(&self.center));
+        let projected = TexturePoint { u, v, ..*point };
+
+        self.texture.color(scene, max_depth, &projected)
+    }
+}
+
+impl Texture for Grayscale {
+    fn color(&self, scene: &Scene, max_depth: i32, point: &TexturePoint) -> Rgb {
+        self.texture.color(scene, max_depth, point).to_gray()
+    }
+}
+
+impl Texture for Scroll {
+    fn color(&self, scene: &Scene, max_depth: i32, point: &TexturePoint) -> Rgb {
+        let scrolled = TexturePoint {
+            u: point.u - self.u_velocity * scene.time,
+            v: point.v - self.v_velocity * scene.time,
+            ..*point
+        };
+
+        self.texture.color(scene, max_depth, &scrolled)
+    }
+}
+
+impl Texture for MandelbrotSet {
+    fn color(&self, _scene: &Scene, _max_depth: i32, point: &TexturePoint) -> Rgb {
+        supersample(self.subsamples, self.subsample_spacing, point, |u, v| {
+            self.color_at_point(u, v)
+        })
+    }
+}
+
 impl Texture for Portal {
-    fn color(&self, scene: &Scene, max_depth: i32, u: f32, v: f32) -> Rgb {
-        scene.cast(
-            self.camera.ray_origin(),
-            &self.camera.ray_direction(u, v),
-            max_depth - 1,
-        )
+    fn color(&self, scene: &Scene, max_depth: i32, point: &TexturePoint) -> Rgb {
+        supersample(self.subsamples, self.subsample_spacing, point, |u, v| {
+            stats::record_ray(RayKind::Secondary);
+            scene.cast(
+                &self.camera.ray_origin(u, v),
+                &self.camera.ray_direction(u, v),
+                max_depth - 1,
+            )
+        })
+    }
+}
+
+impl ImageTexture {
+    /// Loads the picture from a binary PPM file, to be repeated and filtered
+    /// bilinearly
+    pub fn load(filename: &str) -> io::Result<ImageTexture> {
+        Ok(ImageTexture {
+            image: ppm::read_ppm(filename)?,
+            filter: ImageFilter::Bilinear,
+            edges: EdgeMode::Wrap,
+        })
+    }
+}
+
+impl Texture for ImageTexture {
+    fn color(&self, _scene: &Scene, _max_depth: i32, point: &TexturePoint) -> Rgb {
+        match self.filter {
+            ImageFilter::Nearest => self.image.sample_nearest(point.u, point.v, self.edges),
+            ImageFilter::Bilinear => self.image.sample_bilinear(point.u, point.v, self.edges),
+        }
+    }
+}
+
+impl Noise {
+    /// Noise with a pattern determined by "seed", so that the same seed always
+    /// gives the same pattern
+    pub fn new(
+        texture1: Box<dyn Texture>,
+        texture2: Box<dyn Texture>,
+        frequency: f32,
+        octaves: u32,
+        seed: u32,
+    ) -> Noise {
+        // Fisher-Yates shuffle
+        let mut permutation: Vec<u8> = (0..=255).collect();
+        for i in (1..permutation.len()).rev() {
+            let j = (counter_rand_u64(i as u64, 0, seed) % ((i + 1) as u64)) as usize;
+            permutation.swap(i, j);
+        }
+        permutation.extend_from_within(..);
+
+        Noise {
+            texture1,
+            texture2,
+            frequency,
+            octaves,
+            permutation,
+        }
+    }
+
+    /// Returns the sum of the octaves of noise at (u, v), in the range [-1, 1]
+    pub fn value(&self, u: f32, v: f32) -> f32 {
+        let mut total = 0.0;
+        let mut amplitude = 1.0;
+        let mut total_amplitude = 0.0;
+        let mut frequency = self.frequency;
+        for _ in 0..self.octaves.max(1) {
+            total += amplitude * self.gradient_noise(u * frequency, v * frequency);
+            total_amplitude += amplitude;
+            amplitude *= 0.5;
+            frequency *= 2.0;
+        }
+
+        (total / total_amplitude).clamp(-1.0, 1.0)
+    }
+
+    /// Single octave of 2-D Perlin noise, which is 0.0 at every integer lattice
+    /// point, and varies smoothly in between
+    fn gradient_noise(&self, x: f32, y: f32) -> f32 {
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (xi, yi) = (
+            (x0 as i64).rem_euclid(256) as usize,
+            (y0 as i64).rem_euclid(256) as usize,
+        );
+
+        // Dot product of the offset from a corner with that corner's gradient, one
+        // of eight evenly spaced directions
+        let corner = |dx: usize, dy: usize| {
+            let hash = self.permutation[self.permutation[xi + dx] as usize + yi + dy];
+            let angle = (hash % 8) as f32 * std::f32::consts::FRAC_PI_4;
+            let (offset_x, offset_y) = (fx - dx as f32, fy - dy as f32);
+            offset_x * angle.cos() + offset_y * angle.sin()
+        };
+        // Quintic fade curve, which has continuous first and second derivatives
+        let fade = |t: f32| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+
+        let (sx, sy) = (fade(fx), fade(fy));
+        let value = lerp(
+            lerp(corner(0, 0), corner(1, 0), sx),
+            lerp(corner(0, 1), corner(1, 1), sx),
+            sy,
+        );
+
+        // Scale the theoretical range of +/- sqrt(0.5) up to roughly +/- 1
+        value * std::f32::consts::SQRT_2
+    }
+}
+
+impl Texture for Noise {
+    fn color(&self, scene: &Scene, max_depth: i32, point: &TexturePoint) -> Rgb {
+        let t = (self.value(point.u, point.v) + 1.0) / 2.0;
+
+        self.texture1
+            .color(scene, max_depth, point)
+            .lerp(&self.texture2.color(scene, max_depth, point), t)
+    }
+}
+
+impl GradientDirection {
+    /// Returns how far along the gradient (u, v) is, before clamping
+    fn position(&self, u: f32, v: f32) -> f32 {
+        match self {
+            GradientDirection::U => u,
+            GradientDirection::V => v,
+            GradientDirection::Diagonal => (u + v) / 2.0,
+        }
+    }
+}
+
+impl Texture for Gradient {
+    fn color(&self, scene: &Scene, max_depth: i32, point: &TexturePoint) -> Rgb {
+        let t = self.direction.position(point.u, point.v).clamp(0.0, 1.0);
+
+        self.texture1
+            .color(scene, max_depth, point)
+            .lerp(&self.texture2.color(scene, max_depth, point), t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkerboard_parity_near_origin() {
+        assert!(Checkerboard::is_first_square(0.5, 0.5));
+        assert!(!Checkerboard::is_first_square(1.5, 0.5));
+        assert!(!Checkerboard::is_first_square(-0.5, 0.5));
+        assert!(Checkerboard::is_first_square(-0.5, -0.5));
+    }
+
+    #[test]
+    fn checkerboard_parity_beyond_a_million_squares() {
+        // f32 has a resolution of 0.25 at this size, so these are exact
+        assert!(Checkerboard::is_first_square(3_000_000.5, 0.5));
+        assert!(!Checkerboard::is_first_square(3_000_001.5, 0.5));
+        assert!(!Checkerboard::is_first_square(-3_000_000.5, 0.5));
+        assert!(Checkerboard::is_first_square(-3_000_001.5, 0.5));
+        assert!(Checkerboard::is_first_square(-3_000_001.5, 3_000_000.5));
+
+        // Whole numbers beyond the range of i32
+        assert!(Checkerboard::is_first_square(1e10, 0.5));
+        assert!(!Checkerboard::is_first_square(-1e10 - 1024.0, 1.5));
     }
 }
@@ -1,7 +1,9 @@
 use std::cell::Cell;
 use std::sync::Mutex;
 use std::thread;
-use strided::{MutStride, Stride};
+use strided::MutStride;
+
+use crate::math::{orthonormal_basis, Rgb, Vec3f};
 
 /// Fixed size two dimensional array
 pub struct Array2D<T> {
@@ -10,7 +12,6 @@ pub struct Array2D<T> {
     data: Vec<T>,
 }
 
-#[allow(dead_code)]
 impl<T: Clone> Array2D<T> {
     /// Construct a "rows" by "columns" two dimensional array, filled with "fill_element"
     pub fn new(rows: usize, columns: usize, fill_element: &T) -> Array2D<T> {
@@ -22,6 +23,22 @@ impl<T: Clone> Array2D<T> {
         }
     }
 
+    /// Construct a "rows" by "columns" two dimensional array from its elements in
+    /// row order
+    pub fn from_vec(rows: usize, columns: usize, data: Vec<T>) -> Array2D<T> {
+        assert_eq!(data.len(), rows * columns);
+        Array2D {
+            rows,
+            columns,
+            data,
+        }
+    }
+
+    /// Gives back the elements in row order, see from_vec()
+    pub fn into_vec(self) -> Vec<T> {
+        self.data
+    }
+
     /// Get a reference to the element at the given (zero-indexed) row and column
     pub fn get(&self, row: usize, column: usize) -> &T {
         debug_assert!(row < self.rows);
@@ -46,14 +63,63 @@ impl<T: Clone> Array2D<T> {
         self.data.chunks_exact_mut(self.columns)
     }
 
-    // TODO: I would prefer not to expose Stride/MutStride here, it would be nice
-    // if the iter_rows() and iter_columns() functions had the same type signature
-    pub fn iter_columns(&self) -> impl Iterator<Item = Stride<T>> {
-        Stride::new(&self.data).substrides(self.columns)
+    // TODO: I would prefer not to expose MutStride here, it would be nice if the
+    // iter_rows_mut() and iter_columns_mut() functions had the same type signature
+    pub fn iter_columns_mut(&mut self) -> impl Iterator<Item = MutStride<'_, T>> {
+        MutStride::new(&mut self.data).substrides_mut(self.columns)
     }
+}
 
-    pub fn iter_columns_mut(&mut self) -> impl Iterator<Item = MutStride<T>> {
-        MutStride::new(&mut self.data).substrides_mut(self.columns)
+/// How lookups into an image treat coordinates beyond its edges
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EdgeMode {
+    /// The image repeats, so that it tiles the plane
+    Wrap,
+    /// The pixels along the edges are extended outwards
+    Clamp,
+}
+
+impl EdgeMode {
+    /// Maps a row or column index, which may be outside the image, to one inside
+    fn index(&self, index: i64, size: usize) -> usize {
+        match self {
+            EdgeMode::Wrap => index.rem_euclid(size as i64) as usize,
+            EdgeMode::Clamp => index.clamp(0, (size as i64) - 1) as usize,
+        }
+    }
+}
+
+/// Lookups into images with continuous coordinates, where (u, v) = (0.0, 0.0) is
+/// the top left corner of the image and (1.0, 1.0) is the bottom right corner
+impl Array2D<Rgb> {
+    /// Returns the pixel which contains the point (u, v)
+    pub fn sample_nearest(&self, u: f32, v: f32, edges: EdgeMode) -> Rgb {
+        let row = edges.index((v * (self.rows as f32)).floor() as i64, self.rows);
+        let column = edges.index((u * (self.columns as f32)).floor() as i64, self.columns);
+
+        *self.get(row, column)
+    }
+
+    /// Interpolates between the four pixels whose centers surround the point
+    /// (u, v), weighting each by how close the point is to it
+    pub fn sample_bilinear(&self, u: f32, v: f32, edges: EdgeMode) -> Rgb {
+        // Pixel centers lie at half-integer coordinates, so shift them onto the
+        // integers to find the surrounding pixels and the fractions between them
+        let y = v * (self.rows as f32) - 0.5;
+        let x = u * (self.columns as f32) - 0.5;
+        let (y0, x0) = (y.floor(), x.floor());
+        let (fy, fx) = (y - y0, x - x0);
+
+        let pixel = |dy: i64, dx: i64| {
+            *self.get(
+                edges.index((y0 as i64) + dy, self.rows),
+                edges.index((x0 as i64) + dx, self.columns),
+            )
+        };
+        let top = pixel(0, 0).scale(1.0 - fx).add(&pixel(0, 1).scale(fx));
+        let bottom = pixel(1, 0).scale(1.0 - fx).add(&pixel(1, 1).scale(fx));
+
+        top.scale(1.0 - fy).add(&bottom.scale(fy))
     }
 }
 
@@ -95,6 +161,24 @@ where
     });
 }
 
+/// Runs two closures in parallel, "a" on a new thread and "b" on the calling
+/// thread, and returns both results. This suits divide and conquer work such as
+/// building the two halves of a tree, where the results have to be combined.
+/// Since each call spawns a thread, recursive callers should only split the
+/// first few levels this way, until there is enough work for every CPU.
+pub fn join<A, B, RA, RB>(a: A, b: B) -> (RA, RB)
+where
+    A: FnOnce() -> RA + Send,
+    B: FnOnce() -> RB,
+    RA: Send,
+{
+    thread::scope(|s| {
+        let handle = s.spawn(a);
+        let result_b = b();
+        (handle.join().unwrap(), result_b)
+    })
+}
+
 thread_local! {
     static PRNG_STATE: Cell<u64> = const { Cell::new(1) };
 }
@@ -131,3 +215,81 @@ pub fn rand_f32() -> f32 {
     // converting to float.
     (((rand_u64() >> 1) as i64) as f32) * scale_factor
 }
+
+/// Number of steps per unit that image values are rounded to before hashing
+const IMAGE_HASH_QUANTIZATION: f32 = 1024.0;
+
+/// Hashes an image, for checking that a scene still renders the same as before.
+/// Each channel is rounded to a multiple of 1/IMAGE_HASH_QUANTIZATION first, so
+/// that last-bit differences in floating point results (eg from a different
+/// platform or compiler) don't change the hash, and the pixels are hashed in row
+/// order with FNV-1a, which unlike std's hashers is stable between Rust versions.
+pub fn image_hash(image: &Array2D<Rgb>) -> u64 {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+
+    let mut hash = FNV_OFFSET_BASIS;
+    let mut hash_bytes = |bytes: &[u8]| {
+        for byte in bytes {
+            hash = (hash ^ (*byte as u64)).wrapping_mul(FNV_PRIME);
+        }
+    };
+
+    hash_bytes(&(image.rows as u64).to_le_bytes());
+    hash_bytes(&(image.columns as u64).to_le_bytes());
+    for pixel in image.iter_rows().flatten() {
+        for channel in [pixel.red, pixel.green, pixel.blue] {
+            let quantized = (channel * IMAGE_HASH_QUANTIZATION).round() as i32;
+            hash_bytes(&quantized.to_le_bytes());
+        }
+    }
+
+    hash
+}
+
+/// Counter-based random number: returns a pseudorandom u64 determined entirely by
+/// its arguments, with no state carried between calls. This allows eg the random
+/// numbers for any sample of any pixel to be generated directly, in any order and
+/// on any thread, and to come out the same from run to run.
+pub fn counter_rand_u64(pixel_index: u64, sample_index: u32, seed: u32) -> u64 {
+    // Combine the counters into a single key, and then scramble it with the
+    // SplitMix64 finalizer, which is a bijection, so distinct keys always give
+    // distinct outputs.
+    let key = pixel_index
+        .wrapping_mul(0x9E3779B97F4A7C15)
+        .wrapping_add(((seed as u64) << 32) | (sample_index as u64));
+
+    let mut z = key.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+/// Counter-based version of rand_f32(), see counter_rand_u64(). The result is in
+/// [0, 1.0), and unlike rand_f32() never equals 1.0.
+pub fn counter_rand_f32(pixel_index: u64, sample_index: u32, seed: u32) -> f32 {
+    // Keep only as many bits as fit in an f32's mantissa, so the conversion is exact
+    let bits = counter_rand_u64(pixel_index, sample_index, seed) >> 40;
+    (bits as f32) * (1.0 / ((1u64 << 24) as f32))
+}
+
+/// Returns a random point (x, y) uniformly distributed over the unit disk
+pub fn rand_in_unit_disk() -> (f32, f32) {
+    let radius = rand_f32().sqrt();
+    let angle = 2.0 * std::f32::consts::PI * rand_f32();
+
+    (radius * angle.cos(), radius * angle.sin())
+}
+
+/// Returns a random unit vector uniformly distributed over the solid angle of a
+/// cone around the unit vector "axis", with the given half angle (in radians).
+pub fn rand_in_cone(axis: &Vec3f, half_angle: f32) -> Vec3f {
+    let cos_theta = 1.0 - rand_f32() * (1.0 - half_angle.cos());
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * std::f32::consts::PI * rand_f32();
+    let (tangent, bitangent) = orthonormal_basis(axis);
+
+    axis.scale(cos_theta)
+        .add(&tangent.scale(sin_theta * phi.cos()))
+        .add(&bitangent.scale(sin_theta * phi.sin()))
+}